@@ -0,0 +1,64 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Base64 after RFC 4648, for the features that need it.
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(super) fn base64_encode(bytes: &[u8]) -> String {
+	let mut res = String::new();
+	for chunk in bytes.chunks(3) {
+		let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+		for i in 0..4 {
+			if i <= chunk.len() {
+				res.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+			} else {
+				res.push('=');
+			}
+		}
+	}
+	res
+}
+
+// Decodes base64, skipping whitespace, or None if `s` is not base64.
+#[cfg(feature = "mail")]
+pub(super) fn base64_decode(s: &str) -> Option<Vec<u8>> {
+	let mut res = Vec::new();
+	let (mut n, mut bits) = (0u32, 0);
+	for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+		if c == b'=' {
+			break;
+		}
+		n = n << 6 | BASE64.iter().position(|&b| b == c)? as u32;
+		bits += 6;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((n >> bits) as u8);
+		}
+	}
+	Some(res)
+}
+
+#[cfg(feature = "mail")]
+#[test]
+fn test_base64() {
+	for s in ["", "f", "fo", "foo", "foob", "fooba", "foobar", "λx"] {
+		assert_eq!(Some(s.as_bytes().to_vec()), base64_decode(&base64_encode(s.as_bytes())));
+	}
+	assert_eq!("Zm9vYmE=", base64_encode(b"fooba"));
+	assert_eq!(None, base64_decode("Zm9v!"));
+}
@@ -0,0 +1,174 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// A cache on disk for the results of expensive computations, so that runs
+// after the first skip them. An entry is named by the SHA-256 of its key
+// as printed and holds the result as MessagePack, so keys and results must
+// be data that `msgpack-encode` can write. The cache lives in $CRUST_CACHE_DIR,
+// or in crust/ under $XDG_CACHE_HOME or ~/.cache. Without any of them there
+// is no cache, rather than one in a directory others could write to.
+
+use std::cell::Cell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use super::hash::{hex, Sha256};
+use super::serial::{msgpack_encode, msgpack_value};
+use super::{apply, builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+thread_local! {
+	// Whether the evaluation running on this thread may use the cache.
+	static ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+// Puts back the setting `with_cache` replaced, also when the evaluation
+// panics.
+struct Restore(bool);
+
+impl Drop for Restore {
+	fn drop(&mut self) {
+		ENABLED.with(|e| e.set(self.0));
+	}
+}
+
+// Runs `f` with the cache turned on or off.
+pub(super) fn with_cache<T, F: FnOnce() -> T>(on: bool, f: F) -> T {
+	let _restore = Restore(ENABLED.with(|e| e.replace(on)));
+	f()
+}
+
+fn directory() -> Option<PathBuf> {
+	if let Some(dir) = env::var_os("CRUST_CACHE_DIR") {
+		return Some(PathBuf::from(dir));
+	}
+	let base = match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+		(Some(dir), _) => PathBuf::from(dir),
+		(None, Some(home)) => PathBuf::from(home).join(".cache"),
+		(None, None) => return None
+	};
+	Some(base.join("crust"))
+}
+
+// The value in the entry at `path`, if there is one younger than `ttl`.
+fn lookup(path: &Path, ttl: Option<Duration>) -> Option<Value> {
+	let modified = fs::metadata(path).ok()?.modified().ok()?;
+	if let Some(ttl) = ttl {
+		// Entries from the future, after the clock was set back, are fresh.
+		if SystemTime::now().duration_since(modified).unwrap_or_default() >= ttl {
+			return None;
+		}
+	}
+	msgpack_value(&fs::read(path).ok()?)
+}
+
+// Numbers the temporary files of this process, which threads may write at
+// the same time.
+static NEXT_TMP: AtomicUsize = AtomicUsize::new(0);
+
+// Writes the entry through a temporary file, so that a run reading it at
+// the same time sees all of it or none.
+fn store(path: &Path, v: &Value) -> Result<(), CrustError> {
+	let mut data = Vec::new();
+	msgpack_encode(v, &mut data)?;
+	let dir = path.parent().expect("entries are in the cache directory");
+	let n = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+	let tmp = path.with_extension(format!("tmp{}-{}", std::process::id(), n));
+	let io_error = |e: std::io::Error| -> CrustError { ErrorKind::Io(format!("{}: {}", dir.display(), e)).into() };
+	fs::create_dir_all(dir).map_err(io_error)?;
+	fs::write(&tmp, data).map_err(io_error)?;
+	fs::rename(&tmp, path).map_err(|e| {
+		let _ = fs::remove_file(&tmp);
+		io_error(e)
+	})
+}
+
+// (cached key thunk [ttl-seconds]) returns the result of calling thunk
+// the last time `cached` was called with an equal key, or calls it and
+// keeps the result if there is none or it is older than `ttl-seconds`.
+fn builtin_cached(args: &[Value]) -> Result<Value, CrustError> {
+	cached(directory().as_deref(), args)
+}
+
+// `cached` with the cache in `dir`, or without a cache if there is none.
+fn cached(dir: Option<&Path>, args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("cached", args, 2, Some(3))?;
+	// Procedures print alike, so they would share an entry.
+	msgpack_encode(&args[0], &mut Vec::new())?;
+	let ttl = match args.get(2) {
+		Some(&Value::Integer(n)) if n >= 0 => Some(Duration::from_secs(n as u64)),
+		Some(v) => return Err(wrong_type("a non-negative integer", v)),
+		None => None
+	};
+	let dir = match dir {
+		Some(dir) if ENABLED.with(Cell::get) => dir,
+		_ => return apply(args[1].clone(), Vec::new(), None)
+	};
+	let mut sha = Sha256::new();
+	sha.update(args[0].to_string().as_bytes());
+	let path = dir.join(hex(&sha.finish()) + ".msgpack");
+	if let Some(v) = lookup(&path, ttl) {
+		return Ok(v);
+	}
+	let v = apply(args[1].clone(), Vec::new(), None)?;
+	store(&path, &v)?;
+	Ok(v)
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("cached", builtin_cached),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_cached() {
+	let dir = super::TempDir::new();
+	let mut interp = super::Interpreter::new();
+	let cache_dir = dir.to_path_buf();
+	interp.register("cached", move |args| cached(Some(&cache_dir), args));
+	let calls = Rc::new(Cell::new(0));
+	let counter = calls.clone();
+	interp.register("compute", move |_| {
+		counter.set(counter.get() + 1);
+		Ok(Value::list(vec![Value::Integer(counter.get()), Value::Str(Rc::from("x"))]))
+	});
+	let run = |interp: &mut super::Interpreter, src| interp.eval_str(src).map(|v| v.to_string()).map_err(|e| e.to_string());
+	assert_eq!(Ok("(1 \"x\")".to_string()), run(&mut interp, "(cached '(data 1) compute)"));
+	assert_eq!(Ok("(1 \"x\")".to_string()), run(&mut interp, "(cached '(data 1) compute)"));
+	assert_eq!(Ok("(2 \"x\")".to_string()), run(&mut interp, "(cached \"(data 1)\" compute)"));
+	assert_eq!(Ok("(2 \"x\")".to_string()), run(&mut interp, "(cached \"(data 1)\" compute 3600)"));
+	assert_eq!(Ok("(3 \"x\")".to_string()), run(&mut interp, "(cached \"(data 1)\" compute 0)"));
+	interp.use_cache(false);
+	assert_eq!(Ok("(4 \"x\")".to_string()), run(&mut interp, "(cached '(data 1) compute)"));
+	interp.use_cache(true);
+	assert_eq!(Ok("(1 \"x\")".to_string()), run(&mut interp, "(cached '(data 1) compute)"));
+	assert_eq!(4, calls.get());
+	assert_eq!(Err("1:1: expected a list, number, boolean, string or symbol, got #<procedure>".to_string()),
+	           run(&mut interp, "(cached 'f (lambda () (lambda () 1)))"));
+	assert_eq!(Err("1:1: expected a list, number, boolean, string or symbol, got #<builtin car>".to_string()),
+	           run(&mut interp, "(cached (list car) compute)"));
+	assert_eq!(4, calls.get());
+	interp.register("cached", |args| cached(None, args));
+	assert_eq!(Ok("(5 \"x\")".to_string()), run(&mut interp, "(cached '(data 1) compute)"));
+}
@@ -0,0 +1,722 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// gzip and zip files, behind the `compress` feature. crust has no byte
+// vectors, so the builtins work on files. DEFLATE is implemented here:
+// decompression handles everything the format allows, while compression
+// sticks to LZ77 with the fixed Huffman codes, which is far simpler than
+// building per-block codes and gets most of the way there.
+
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::path::{Component, Path};
+use std::rc::Rc;
+
+use super::hash::crc32;
+use super::{builtin, check_arity, list_items, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99,
+                                115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025,
+                              1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12,
+                              13, 13];
+
+// The most a file may decompress to, so that a small corrupt or malicious
+// file cannot fill the memory or the disk.
+const MAX_OUTPUT: usize = 1 << 30;
+
+// Why data could not be decompressed.
+#[derive(Debug, PartialEq)]
+enum Failure {
+	Corrupt,
+	TooLarge
+}
+
+// A canonical Huffman code: the number of codes of each length and the
+// symbols ordered by code.
+struct Huffman {
+	counts: [u16; 16],
+	symbols: Vec<u16>
+}
+
+impl Huffman {
+	// The code with the given code lengths, or None if there are more
+	// codes of some length than fit, or fewer than needed to use up every
+	// bit pattern. A single code of one bit, or none, is allowed, as the
+	// format asks of distance codes for data with one distance or none.
+	fn new(lengths: &[u8]) -> Option<Huffman> {
+		let mut counts = [0; 16];
+		for &len in lengths {
+			counts[len as usize] += 1;
+		}
+		counts[0] = 0;
+		// The bit patterns of each length not taken by shorter codes.
+		let mut left = 1i32;
+		for &count in &counts[1..] {
+			left = (left << 1) - count as i32;
+			if left < 0 {
+				return None;
+			}
+		}
+		let codes: u16 = counts.iter().sum();
+		if left > 0 && !(codes == 0 || codes == 1 && counts[1] == 1) {
+			return None;
+		}
+		let mut symbols: Vec<u16> = (0..lengths.len() as u16).filter(|&s| lengths[s as usize] > 0).collect();
+		symbols.sort_by_key(|&s| lengths[s as usize]);
+		Some(Huffman { counts, symbols })
+	}
+}
+
+// The output of `inflate`, which may not grow past `limit` bytes.
+struct Output {
+	bytes: Vec<u8>,
+	limit: usize,
+	full: bool
+}
+
+impl Output {
+	fn room(&mut self, n: usize) -> Option<()> {
+		if self.limit - self.bytes.len() < n {
+			self.full = true;
+			return None;
+		}
+		Some(())
+	}
+}
+
+struct BitReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+	bit: u32
+}
+
+impl<'a> BitReader<'a> {
+	fn bits(&mut self, n: u32) -> Option<u32> {
+		let mut v = 0;
+		for i in 0..n {
+			let byte = *self.data.get(self.pos)?;
+			v |= ((byte >> self.bit) as u32 & 1) << i;
+			self.bit += 1;
+			if self.bit == 8 {
+				self.bit = 0;
+				self.pos += 1;
+			}
+		}
+		Some(v)
+	}
+
+	fn decode(&mut self, h: &Huffman) -> Option<u16> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for len in 1..16 {
+			code |= self.bits(1)? as i32;
+			let count = h.counts[len] as i32;
+			if code - count < first {
+				return h.symbols.get((index + code - first) as usize).copied();
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		None
+	}
+}
+
+// The distance code has 32 codes so that it is complete, though the last
+// two are not valid distances.
+fn fixed_codes() -> (Huffman, Huffman) {
+	let mut lengths = [8u8; 288];
+	lengths[144..256].fill(9);
+	lengths[256..280].fill(7);
+	(Huffman::new(&lengths).unwrap(), Huffman::new(&[5; 32]).unwrap())
+}
+
+fn dynamic_codes(r: &mut BitReader) -> Option<(Huffman, Huffman)> {
+	const ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+	let nlen = r.bits(5)? as usize + 257;
+	let ndist = r.bits(5)? as usize + 1;
+	let ncode = r.bits(4)? as usize + 4;
+	let mut code_lengths = [0u8; 19];
+	for &i in &ORDER[..ncode] {
+		code_lengths[i] = r.bits(3)? as u8;
+	}
+	let code = Huffman::new(&code_lengths)?;
+	let mut lengths = Vec::new();
+	while lengths.len() < nlen + ndist {
+		let (len, repeat) = match r.decode(&code)? {
+			sym @ 0..=15 => (sym as u8, 1),
+			16 => (*lengths.last()?, 3 + r.bits(2)?),
+			17 => (0, 3 + r.bits(3)?),
+			_ => (0, 11 + r.bits(7)?)
+		};
+		lengths.extend(std::iter::repeat_n(len, repeat as usize));
+	}
+	// Without an end of block code no block could end.
+	if lengths.len() != nlen + ndist || lengths[256] == 0 {
+		return None;
+	}
+	Some((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+// Decompresses raw DEFLATE data of at most `limit` bytes, returning it and
+// the number of bytes it took up.
+fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize), Failure> {
+	let mut out = Output { bytes: Vec::new(), limit, full: false };
+	match inflate_into(data, &mut out) {
+		Some(used) => Ok((out.bytes, used)),
+		None if out.full => Err(Failure::TooLarge),
+		None => Err(Failure::Corrupt)
+	}
+}
+
+fn inflate_into(data: &[u8], out: &mut Output) -> Option<usize> {
+	let mut r = BitReader { data, pos: 0, bit: 0 };
+	loop {
+		let last = r.bits(1)? == 1;
+		match r.bits(2)? {
+			0 => {
+				if r.bit > 0 {
+					r.bit = 0;
+					r.pos += 1;
+				}
+				let len = u16::from_le_bytes([*data.get(r.pos)?, *data.get(r.pos + 1)?]);
+				let nlen = u16::from_le_bytes([*data.get(r.pos + 2)?, *data.get(r.pos + 3)?]);
+				if nlen != !len {
+					return None;
+				}
+				r.pos += 4;
+				let stored = data.get(r.pos..r.pos + len as usize)?;
+				out.room(stored.len())?;
+				out.bytes.extend_from_slice(stored);
+				r.pos += stored.len();
+			}
+			btype @ (1 | 2) => {
+				let (lit, dist) = if btype == 1 { fixed_codes() } else { dynamic_codes(&mut r)? };
+				loop {
+					let sym = r.decode(&lit)? as usize;
+					if sym < 256 {
+						out.room(1)?;
+						out.bytes.push(sym as u8);
+						continue;
+					} else if sym == 256 {
+						break;
+					}
+					let i = sym - 257;
+					let len = *LENGTH_BASE.get(i)? as usize + r.bits(*LENGTH_EXTRA.get(i)? as u32)? as usize;
+					let d = r.decode(&dist)? as usize;
+					let distance = *DIST_BASE.get(d)? as usize + r.bits(*DIST_EXTRA.get(d)? as u32)? as usize;
+					let start = out.bytes.len().checked_sub(distance)?;
+					out.room(len)?;
+					for k in 0..len {
+						out.bytes.push(out.bytes[start + k]);
+					}
+				}
+			}
+			_ => return None
+		}
+		if last {
+			return Some(r.pos + if r.bit > 0 { 1 } else { 0 });
+		}
+	}
+}
+
+struct BitWriter {
+	out: Vec<u8>,
+	bits: u32,
+	n: u32
+}
+
+impl BitWriter {
+	fn bits(&mut self, v: u32, n: u32) {
+		self.bits |= v << self.n;
+		self.n += n;
+		while self.n >= 8 {
+			self.out.push(self.bits as u8);
+			self.bits >>= 8;
+			self.n -= 8;
+		}
+	}
+
+	// Huffman codes go out most significant bit first.
+	fn code(&mut self, code: u32, len: u32) {
+		for i in (0..len).rev() {
+			self.bits(code >> i & 1, 1);
+		}
+	}
+
+	fn literal(&mut self, sym: u32) {
+		match sym {
+			0..=143 => self.code(0x30 + sym, 8),
+			144..=255 => self.code(0x190 + sym - 144, 9),
+			256..=279 => self.code(sym - 256, 7),
+			_ => self.code(0xc0 + sym - 280, 8)
+		}
+	}
+
+	fn finish(mut self) -> Vec<u8> {
+		if self.n > 0 {
+			self.out.push(self.bits as u8);
+		}
+		self.out
+	}
+}
+
+// The index of the last entry in `base` that is at most `v`.
+fn bucket(base: &[u16], v: usize) -> usize {
+	base.iter().rposition(|&b| b as usize <= v).unwrap()
+}
+
+// Finds earlier occurrences of the bytes at a position through a hash of
+// the three bytes starting there, chaining positions with the same bytes.
+struct Matcher {
+	heads: HashMap<[u8; 3], usize>,
+	prev: Vec<Option<usize>>
+}
+
+impl Matcher {
+	const WINDOW: usize = 32768;
+	const MAX_CHAIN: usize = 64;
+
+	fn insert(&mut self, data: &[u8], i: usize) {
+		if let Some(key) = data.get(i..i + 3) {
+			self.prev[i] = self.heads.insert([key[0], key[1], key[2]], i);
+		}
+	}
+
+	// The length and distance of the longest match for the bytes at `i`.
+	fn longest(&self, data: &[u8], i: usize) -> (usize, usize) {
+		let (mut best_len, mut best_dist) = (0, 0);
+		let mut candidate = data.get(i..i + 3).and_then(|key| self.heads.get(key).copied());
+		let mut chain = 0;
+		while let Some(c) = candidate {
+			if i - c > Self::WINDOW || chain == Self::MAX_CHAIN {
+				break;
+			}
+			let len = data[c..].iter().zip(&data[i..]).take(258).take_while(|(a, b)| a == b).count();
+			if len > best_len {
+				best_len = len;
+				best_dist = i - c;
+			}
+			candidate = self.prev[c];
+			chain += 1;
+		}
+		(best_len, best_dist)
+	}
+}
+
+// Compresses `data` as a single DEFLATE block with the fixed codes.
+fn deflate(data: &[u8]) -> Vec<u8> {
+	let mut w = BitWriter { out: Vec::new(), bits: 0, n: 0 };
+	// The last block, with fixed codes.
+	w.bits(1, 1);
+	w.bits(1, 2);
+	let mut matcher = Matcher { heads: HashMap::new(), prev: vec![None; data.len()] };
+	let mut i = 0;
+	while i < data.len() {
+		let (len, dist) = matcher.longest(data, i);
+		if len >= 3 {
+			let l = bucket(&LENGTH_BASE, len);
+			w.literal(257 + l as u32);
+			w.bits((len - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA[l] as u32);
+			let d = bucket(&DIST_BASE, dist);
+			w.code(d as u32, 5);
+			w.bits((dist - DIST_BASE[d] as usize) as u32, DIST_EXTRA[d] as u32);
+			for k in i..i + len {
+				matcher.insert(data, k);
+			}
+			i += len;
+		} else {
+			w.literal(data[i] as u32);
+			matcher.insert(data, i);
+			i += 1;
+		}
+	}
+	w.literal(256);
+	w.finish()
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+	// No file name or time, and 255 for an unknown operating system.
+	let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+	out.extend(deflate(data));
+	out.extend(crc32(data).to_le_bytes());
+	out.extend((data.len() as u32).to_le_bytes());
+	out
+}
+
+// The length of the header of the gzip member at the start of `data`.
+fn gzip_header(data: &[u8]) -> Option<usize> {
+	if data.get(..3)? != [0x1f, 0x8b, 8] {
+		return None;
+	}
+	let flags = *data.get(3)?;
+	let mut pos = 10;
+	if flags & 4 != 0 {
+		pos += 2 + u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+	}
+	// The file name and the comment end with a zero byte.
+	for flag in [8, 16] {
+		if flags & flag != 0 {
+			pos += data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+		}
+	}
+	if flags & 2 != 0 {
+		pos += 2;
+	}
+	Some(pos)
+}
+
+// Decompresses the concatenated gzip members in `data`, to at most `limit`
+// bytes.
+fn gunzip(mut data: &[u8], limit: usize) -> Result<Vec<u8>, Failure> {
+	let mut out = Vec::new();
+	loop {
+		let pos = gzip_header(data).ok_or(Failure::Corrupt)?;
+		let (member, used) = inflate(data.get(pos..).ok_or(Failure::Corrupt)?, limit - out.len())?;
+		let trailer = data.get(pos + used..pos + used + 8).ok_or(Failure::Corrupt)?;
+		if trailer[..4] != crc32(&member).to_le_bytes() || trailer[4..] != (member.len() as u32).to_le_bytes() {
+			return Err(Failure::Corrupt);
+		}
+		out.extend(member);
+		data = &data[pos + used + 8..];
+		if data.is_empty() {
+			return Ok(out);
+		}
+	}
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<usize> {
+	Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?) as usize)
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+	Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+struct Entry {
+	name: String,
+	data: Vec<u8>
+}
+
+// A zip archive of `entries`, each compressed unless that makes it bigger.
+// Archives over 4 GiB would need the zip64 extensions and are refused.
+fn zip(entries: &[Entry]) -> Option<Vec<u8>> {
+	let mut out = Vec::new();
+	let mut central = Vec::new();
+	for entry in entries {
+		let compressed = deflate(&entry.data);
+		let (method, stored): (u16, &[u8]) = if compressed.len() < entry.data.len() { (8, &compressed) } else { (0, &entry.data) };
+		let offset = u32::try_from(out.len()).ok()?;
+		let size = u32::try_from(entry.data.len()).ok()?;
+		let name = entry.name.as_bytes();
+		// Version 2.0, UTF-8 names, and 1980-01-01 for the time.
+		let mut fields = Vec::new();
+		fields.extend(20u16.to_le_bytes());
+		fields.extend(0x800u16.to_le_bytes());
+		fields.extend(method.to_le_bytes());
+		fields.extend(0u16.to_le_bytes());
+		fields.extend(33u16.to_le_bytes());
+		fields.extend(crc32(&entry.data).to_le_bytes());
+		fields.extend((stored.len() as u32).to_le_bytes());
+		fields.extend(size.to_le_bytes());
+		fields.extend((name.len() as u16).to_le_bytes());
+		fields.extend(0u16.to_le_bytes());
+		out.extend(0x04034b50u32.to_le_bytes());
+		out.extend(&fields);
+		out.extend(name);
+		out.extend(stored);
+		central.extend(0x02014b50u32.to_le_bytes());
+		central.extend(20u16.to_le_bytes());
+		central.extend(&fields);
+		// No comment, disk 0, no attributes.
+		central.extend([0; 10]);
+		central.extend(offset.to_le_bytes());
+		central.extend(name);
+	}
+	let count = u16::try_from(entries.len()).ok()?;
+	let central_offset = u32::try_from(out.len()).ok()?;
+	out.extend(&central);
+	out.extend(0x06054b50u32.to_le_bytes());
+	out.extend([0; 4]);
+	out.extend(count.to_le_bytes());
+	out.extend(count.to_le_bytes());
+	out.extend((central.len() as u32).to_le_bytes());
+	out.extend(central_offset.to_le_bytes());
+	out.extend(0u16.to_le_bytes());
+	Some(out)
+}
+
+// An entry of a zip archive as its central directory describes it.
+struct Record<'a> {
+	name: String,
+	method: usize,
+	crc: u32,
+	size: usize,
+	stored: &'a [u8]
+}
+
+// The entries in the central directory of a zip archive, or None if it is
+// corrupt.
+fn directory(data: &[u8]) -> Option<Vec<Record<'_>>> {
+	// The end of central directory record is followed by a comment of at
+	// most 64 KiB.
+	let end = (0..=data.len().checked_sub(22)?).rev().take(65536 + 22)
+		.find(|&i| u32_at(data, i) == Some(0x06054b50))?;
+	let count = u16_at(data, end + 10)?;
+	let mut pos = u32_at(data, end + 16)? as usize;
+	let mut records = Vec::new();
+	for _ in 0..count {
+		if u32_at(data, pos)? != 0x02014b50 {
+			return None;
+		}
+		let method = u16_at(data, pos + 10)?;
+		let crc = u32_at(data, pos + 16)?;
+		let stored_size = u32_at(data, pos + 20)? as usize;
+		let size = u32_at(data, pos + 24)? as usize;
+		let name_len = u16_at(data, pos + 28)?;
+		let extra_len = u16_at(data, pos + 30)?;
+		let comment_len = u16_at(data, pos + 32)?;
+		let offset = u32_at(data, pos + 42)? as usize;
+		let name = String::from_utf8_lossy(data.get(pos + 46..pos + 46 + name_len)?).into_owned();
+		pos += 46 + name_len + extra_len + comment_len;
+		if u32_at(data, offset)? != 0x04034b50 {
+			return None;
+		}
+		let start = offset + 30 + u16_at(data, offset + 26)? + u16_at(data, offset + 28)?;
+		let stored = data.get(start..start + stored_size)?;
+		records.push(Record { name, method, crc, size, stored });
+	}
+	Some(records)
+}
+
+// The entries of a zip archive, which may hold at most `limit` bytes in
+// all. Only storing and DEFLATE are supported.
+fn unzip(data: &[u8], limit: usize) -> Result<Vec<Entry>, Failure> {
+	let mut left = limit;
+	let mut entries = Vec::new();
+	for record in directory(data).ok_or(Failure::Corrupt)? {
+		left = left.checked_sub(record.size).ok_or(Failure::TooLarge)?;
+		let contents = match record.method {
+			0 => record.stored.to_vec(),
+			// More than the size given is as corrupt as less.
+			8 => inflate(record.stored, record.size).map_err(|_| Failure::Corrupt)?.0,
+			_ => return Err(Failure::Corrupt)
+		};
+		if contents.len() != record.size || crc32(&contents) != record.crc {
+			return Err(Failure::Corrupt);
+		}
+		entries.push(Entry { name: record.name, data: contents });
+	}
+	Ok(entries)
+}
+
+fn file_error(path: &str, e: std::io::Error) -> CrustError {
+	ErrorKind::Io(format!("{}: {}", path, e)).into()
+}
+
+fn path_arg(v: &Value) -> Result<&str, CrustError> {
+	match *v {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type("a file name", v))
+	}
+}
+
+// The error for the file `path` that could not be decompressed, being
+// `what` if it is corrupt.
+fn failure_error(path: &str, failure: Failure, what: &str) -> CrustError {
+	match failure {
+		Failure::Corrupt => ErrorKind::Io(format!("{}: {}", path, what)).into(),
+		Failure::TooLarge => ErrorKind::Io(format!("{}: decompresses to more than {} bytes", path, MAX_OUTPUT)).into()
+	}
+}
+
+// Reads the file `args[0]`, transforms it with `f` and writes the result to
+// the file `args[1]`.
+fn convert(name: &str, args: &[Value], f: fn(&[u8]) -> Result<Vec<u8>, Failure>, what: &str) -> Result<Value, CrustError> {
+	check_arity(name, args, 2, Some(2))?;
+	let (from, to) = (path_arg(&args[0])?, path_arg(&args[1])?);
+	let data = fs::read(from).map_err(|e| file_error(from, e))?;
+	let converted = f(&data).map_err(|failure| failure_error(from, failure, what))?;
+	fs::write(to, converted).map_err(|e| file_error(to, e))?;
+	Ok(Value::Unspecified)
+}
+
+// (gzip-compress from to) and (gzip-decompress from to) convert between
+// files.
+fn builtin_gzip_compress(args: &[Value]) -> Result<Value, CrustError> {
+	convert("gzip-compress", args, |data| Ok(gzip(data)), "")
+}
+
+fn builtin_gzip_decompress(args: &[Value]) -> Result<Value, CrustError> {
+	convert("gzip-decompress", args, |data| gunzip(data, MAX_OUTPUT), "not a valid gzip file")
+}
+
+// (zip-write archive files) stores the files with the given names, which
+// are kept as they are as the names of the entries.
+fn builtin_zip_write(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("zip-write", args, 2, Some(2))?;
+	let archive = path_arg(&args[0])?;
+	let files = list_items(&args[1]).ok_or_else(|| wrong_type("a list of file names", &args[1]))?;
+	let mut entries = Vec::new();
+	for file in &files {
+		let name = path_arg(file)?;
+		let data = fs::read(name).map_err(|e| file_error(name, e))?;
+		entries.push(Entry { name: name.to_string(), data });
+	}
+	let data = zip(&entries).ok_or_else(|| ErrorKind::Io(format!("{}: too large for a zip archive", archive)))?;
+	fs::write(archive, data).map_err(|e| file_error(archive, e))?;
+	Ok(Value::Unspecified)
+}
+
+// (zip-read archive [directory]) returns the names of the entries of an
+// archive, extracting them into `directory` if there is one. Entries that
+// would end up outside it are refused.
+fn builtin_zip_read(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("zip-read", args, 1, Some(2))?;
+	let archive = path_arg(&args[0])?;
+	let data = fs::read(archive).map_err(|e| file_error(archive, e))?;
+	let entries = unzip(&data, MAX_OUTPUT).map_err(|failure| failure_error(archive, failure, "not a valid zip archive"))?;
+	if let Some(dir) = args.get(1) {
+		let dir = Path::new(path_arg(dir)?);
+		for entry in &entries {
+			let name = Path::new(&entry.name);
+			if !name.components().all(|c| matches!(c, Component::Normal(_))) {
+				return Err(ErrorKind::Io(format!("{}: unsafe entry name {}", archive, entry.name)).into());
+			}
+			let path = dir.join(name);
+			let display = path.display().to_string();
+			if entry.name.ends_with('/') {
+				fs::create_dir_all(&path).map_err(|e| file_error(&display, e))?;
+				continue;
+			}
+			if let Some(parent) = path.parent() {
+				fs::create_dir_all(parent).map_err(|e| file_error(&display, e))?;
+			}
+			fs::write(&path, &entry.data).map_err(|e| file_error(&display, e))?;
+		}
+	}
+	Ok(Value::list(entries.iter().map(|e| Value::Str(Rc::from(e.name.as_str()))).collect()))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("gzip-compress", builtin_gzip_compress),
+		("gzip-decompress", builtin_gzip_decompress),
+		("zip-write", builtin_zip_write),
+		("zip-read", builtin_zip_read),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_inflate_dynamic() {
+	// Generated by zlib, which picks dynamic codes for this input.
+	let compressed = [
+		0x25, 0x8a, 0x81, 0x0d, 0x00, 0x30, 0x08, 0xc2, 0x6e, 0x2d, 0xf0, 0xff, 0x0d, 0x03,
+		0xa7, 0x06, 0xb4, 0x82, 0x04, 0x11, 0x35, 0x11, 0x53, 0xed, 0xc8, 0xb3, 0xbb, 0xbc,
+		0xe7, 0xed, 0x63, 0xc6, 0xa3, 0x4a, 0x8a, 0xc2, 0x05, 0xd3, 0xfe, 0xd5, 0xf4, 0x03
+	];
+	let expected = b"abbaadbabbabadcaabaababcbaabcaabacdbababcaacbaacaccaabbddabcdaabcbadadaaaaaaabac";
+	assert_eq!(inflate(&compressed, MAX_OUTPUT), Ok((expected.to_vec(), compressed.len())));
+	assert_eq!(inflate(&compressed[..20], MAX_OUTPUT), Err(Failure::Corrupt));
+	assert_eq!(inflate(&compressed, expected.len() - 1), Err(Failure::TooLarge));
+}
+
+#[test]
+fn test_inflate_corrupt() {
+	// A stored block of "abc", whose length is checked against its
+	// complement.
+	let stored = [1, 3, 0, !3, !0, b'a', b'b', b'c'];
+	assert_eq!(inflate(&stored, MAX_OUTPUT), Ok((b"abc".to_vec(), stored.len())));
+	let mut bad = stored;
+	bad[3] ^= 1;
+	assert_eq!(inflate(&bad, MAX_OUTPUT), Err(Failure::Corrupt));
+	assert_eq!(inflate(&stored, 2), Err(Failure::TooLarge));
+	// Three codes of one bit, and two codes of two bits that leave half of
+	// the patterns unused.
+	assert!(Huffman::new(&[1, 1, 1]).is_none());
+	assert!(Huffman::new(&[2, 2]).is_none());
+	assert!(Huffman::new(&[1, 2, 2]).is_some());
+	assert!(Huffman::new(&[0, 1]).is_some());
+	assert!(Huffman::new(&[0, 0]).is_some());
+	// A dynamic block whose code length code is oversubscribed: all 19 code
+	// length codes have one bit.
+	let mut w = BitWriter { out: Vec::new(), bits: 0, n: 0 };
+	w.bits(1, 1);
+	w.bits(2, 2);
+	w.bits(0, 5);
+	w.bits(0, 5);
+	w.bits(15, 4);
+	for _ in 0..19 {
+		w.bits(1, 3);
+	}
+	assert_eq!(inflate(&w.finish(), MAX_OUTPUT), Err(Failure::Corrupt));
+	// A long run of copies stops at the limit.
+	let text = vec![b'x'; 100_000];
+	let compressed = gzip(&text);
+	assert_eq!(gunzip(&compressed, MAX_OUTPUT), Ok(text));
+	assert_eq!(gunzip(&compressed, 99_999), Err(Failure::TooLarge));
+}
+
+#[test]
+fn test_round_trip() {
+	let text: Vec<u8> = (0..5000u32).map(|i| b"crust "[(i * i % 7 % 6) as usize]).collect();
+	for data in [&b""[..], b"a", &text] {
+		let compressed = gzip(data);
+		assert_eq!(gunzip(&compressed, MAX_OUTPUT).as_deref(), Ok(data));
+		let mut twice = compressed.clone();
+		twice.extend(&compressed);
+		assert_eq!(gunzip(&twice, MAX_OUTPUT), Ok([data, data].concat()));
+	}
+	assert!(gzip(&text).len() < text.len() / 4);
+	let mut corrupt = gzip(&text);
+	let n = corrupt.len();
+	corrupt[n - 5] ^= 1;
+	assert_eq!(gunzip(&corrupt, MAX_OUTPUT), Err(Failure::Corrupt));
+	// Headers cut short, before and after the flags.
+	for n in [3, 4, 9] {
+		assert_eq!(gunzip(&gzip(b"a")[..n], MAX_OUTPUT), Err(Failure::Corrupt));
+	}
+
+	let entries = vec![
+		Entry { name: "a/text".to_string(), data: text.clone() },
+		Entry { name: "empty".to_string(), data: Vec::new() }
+	];
+	let archive = zip(&entries).unwrap();
+	assert_eq!(unzip(&archive, text.len() - 1).err(), Some(Failure::TooLarge));
+	let unzipped = unzip(&archive, MAX_OUTPUT).unwrap();
+	assert_eq!(unzipped.len(), 2);
+	assert_eq!((unzipped[0].name.as_str(), &unzipped[0].data), ("a/text", &text));
+	assert_eq!((unzipped[1].name.as_str(), &unzipped[1].data), ("empty", &Vec::new()));
+}
+
+#[test]
+fn test_zip_slip() {
+	let dir = super::TempDir::new();
+	let archive = dir.join("evil.zip");
+	fs::write(&archive, zip(&[Entry { name: "../evil".to_string(), data: b"x".to_vec() }]).unwrap()).unwrap();
+	let str_value = |p: &Path| Value::Str(Rc::from(p.to_str().unwrap()));
+	let res = builtin_zip_read(&[str_value(&archive), str_value(&dir.join("out"))]);
+	assert!(matches!(res, Err(CrustError { kind: ErrorKind::Io(_), .. })));
+	assert!(!dir.join("evil").exists());
+	assert!(builtin_zip_read(&[str_value(&archive)]).is_ok());
+}
@@ -0,0 +1,169 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Schemas for configuration files evaluated by `Interpreter::eval_config`.
+// A configuration is an association list of `(key value)` lists, as
+// elsewhere in crust, and a schema gives the keys it may have and the type
+// of each value. Nested association lists are checked against schemas of
+// their own.
+
+use std::fmt::Write as _;
+
+use super::{list_items, CrustError, ErrorKind, Value};
+
+/// The type a value in a configuration must have.
+pub enum Type {
+	/// Any value.
+	Any,
+	Integer,
+	/// An integer or a float.
+	Number,
+	Boolean,
+	String,
+	Symbol,
+	/// A list whose items all have the type.
+	List(Box<Type>),
+	/// An association list checked against the schema.
+	Record(Schema)
+}
+
+/// The keys a configuration may have, with the type of each.
+#[derive(Default)]
+pub struct Schema {
+	fields: Vec<(String, Type, bool)>
+}
+
+impl Schema {
+	pub fn new() -> Schema {
+		Schema::default()
+	}
+
+	/// Adds a key that the configuration must have.
+	pub fn required(mut self, key: &str, ty: Type) -> Schema {
+		self.fields.push((key.to_string(), ty, true));
+		self
+	}
+
+	/// Adds a key that the configuration may leave out.
+	pub fn optional(mut self, key: &str, ty: Type) -> Schema {
+		self.fields.push((key.to_string(), ty, false));
+		self
+	}
+
+	pub(super) fn validate(&self, v: &Value) -> Result<(), CrustError> {
+		check_record(self, v, "").map_err(|msg| ErrorKind::Config(msg).into())
+	}
+}
+
+// The name of `key` inside the record at `path`, as in `server.port`.
+fn key_path(path: &str, key: &str) -> String {
+	if path.is_empty() { key.to_string() } else { format!("{}.{}", path, key) }
+}
+
+fn mismatch(path: &str, expected: &str, got: &Value) -> String {
+	let mut msg = String::new();
+	if !path.is_empty() {
+		let _ = write!(msg, "{}: ", path);
+	}
+	let _ = write!(msg, "expected {}, got {}", expected, got);
+	msg
+}
+
+fn check_record(schema: &Schema, v: &Value, path: &str) -> Result<(), String> {
+	let items = list_items(v).ok_or_else(|| mismatch(path, "an association list", v))?;
+	let mut entries = Vec::new();
+	for item in &items {
+		let entry = list_items(item).ok_or_else(|| mismatch(path, "a (key value) list", item))?;
+		match entry[..] {
+			[Value::Symbol(ref key), ref value] => entries.push((key.clone(), value.clone())),
+			_ => return Err(mismatch(path, "a (key value) list", item))
+		}
+	}
+	let mut seen: Vec<&str> = Vec::new();
+	for (key, value) in &entries {
+		let (_, ty, _) = schema.fields.iter().find(|f| f.0 == **key)
+			.ok_or_else(|| format!("unknown key '{}'", key_path(path, key)))?;
+		if seen.contains(&&**key) {
+			return Err(format!("'{}' is given twice", key_path(path, key)));
+		}
+		seen.push(key);
+		check(ty, value, &key_path(path, key))?;
+	}
+	for (key, _, required) in &schema.fields {
+		if *required && !seen.contains(&key.as_str()) {
+			return Err(format!("missing key '{}'", key_path(path, key)));
+		}
+	}
+	Ok(())
+}
+
+fn check(ty: &Type, v: &Value, path: &str) -> Result<(), String> {
+	match *ty {
+		Type::List(ref item) => {
+			let items = list_items(v).ok_or_else(|| mismatch(path, "a list", v))?;
+			for (i, x) in items.iter().enumerate() {
+				check(item, x, &format!("{}[{}]", path, i))?;
+			}
+			return Ok(());
+		}
+		Type::Record(ref schema) => return check_record(schema, v, path),
+		_ => ()
+	}
+	let expected = match (ty, v) {
+		(&Type::Any, _) |
+		(&Type::Integer, &Value::Integer(_)) |
+		(&Type::Number, &Value::Integer(_)) |
+		(&Type::Number, &Value::Float(_)) |
+		(&Type::Boolean, &Value::Boolean(_)) |
+		(&Type::String, &Value::Str(_)) |
+		(&Type::Symbol, &Value::Symbol(_)) => return Ok(()),
+		(&Type::Integer, _) => "an integer",
+		(&Type::Number, _) => "a number",
+		(&Type::Boolean, _) => "a boolean",
+		(&Type::String, _) => "a string",
+		_ => "a symbol"
+	};
+	Err(mismatch(path, expected, v))
+}
+
+#[test]
+fn test_eval_config() {
+	let schema = Schema::new()
+		.required("name", Type::String)
+		.optional("port", Type::Integer)
+		.optional("ratio", Type::Number)
+		.optional("tags", Type::List(Box::new(Type::Symbol)))
+		.optional("server", Type::Record(Schema::new().required("host", Type::String).optional("tls", Type::Boolean)));
+	let mut interp = super::Interpreter::new();
+	let mut config = |src: &str| interp.eval_config(src, &schema).map(|v| v.to_string()).map_err(|e| e.to_string());
+	assert_eq!(Ok("((name \"app\") (port 8080) (tags (a b)) (server ((host \"h\") (tls #t))))".to_string()),
+	           config("(define (kb n) (* n 1024)) \
+	                   (list (list 'name \"app\") (list 'port (- (kb 8) 112)) '(tags (a b)) '(server ((host \"h\") (tls #t))))"));
+	assert_eq!(Ok("((ratio 1) (name \"x\"))".to_string()), config("'((ratio 1) (name \"x\"))"));
+	assert_eq!(Err("invalid configuration: missing key 'name'".to_string()), config("'((port 1))"));
+	assert_eq!(Err("invalid configuration: unknown key 'server.hots'".to_string()),
+	           config("'((name \"x\") (server ((hots \"h\"))))"));
+	assert_eq!(Err("invalid configuration: port: expected an integer, got \"80\"".to_string()),
+	           config("'((name \"x\") (port \"80\"))"));
+	assert_eq!(Err("invalid configuration: tags[1]: expected a symbol, got 2".to_string()),
+	           config("'((name \"x\") (tags (a 2)))"));
+	assert_eq!(Err("invalid configuration: 'name' is given twice".to_string()), config("'((name \"x\") (name \"y\"))"));
+	assert_eq!(Err("invalid configuration: expected a (key value) list, got (name)".to_string()), config("'((name))"));
+	// Builtins with effects are not there.
+	assert_eq!(Err("1:2: unbound symbol 'tar-create'".to_string()), config("(tar-create \"x.tar\" '(\"/\"))"));
+	assert_eq!(Err("1:2: unbound symbol 'cached'".to_string()), config("(cached 'k (lambda () 1))"));
+}
@@ -0,0 +1,305 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Graphviz output behind `crust viz` and `value->dot`. Syntax trees are drawn
+// as trees, and runtime values as box-and-pointer diagrams in which a pair,
+// procedure or environment reachable in several ways is drawn only once.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::rc::Rc;
+
+use super::{lex, parse, quoted, Closure, CrustError, Env, Node, NodeKind, Pair, Pos, Value};
+
+// Escapes `s` for a double-quoted DOT string.
+fn escape(s: &str) -> String {
+	let mut res = String::new();
+	for c in s.chars() {
+		match c {
+			'"' | '\\' => {
+				res.push('\\');
+				res.push(c);
+			}
+			'\n' => res.push_str("\\n"),
+			c => res.push(c)
+		}
+	}
+	res
+}
+
+// Escapes `s` for a field of a record label, where braces, bars and angle
+// brackets have a meaning of their own.
+fn escape_field(s: &str) -> String {
+	let mut res = String::new();
+	for c in escape(s).chars() {
+		if "{}|<>".contains(c) {
+			res.push('\\');
+		}
+		res.push(c);
+	}
+	res
+}
+
+struct Tree {
+	out: String,
+	next: usize
+}
+
+impl Tree {
+	fn add(&mut self, label: &str, pos: Option<Pos>) -> usize {
+		let id = self.next;
+		self.next += 1;
+		let label = match pos {
+			Some(pos) => format!("{}\n{}", label, pos),
+			None => label.to_string()
+		};
+		let _ = writeln!(self.out, "\tn{} [label=\"{}\"];", id, escape(&label));
+		id
+	}
+
+	fn edge(&mut self, from: usize, to: usize, label: Option<&str>) {
+		let _ = match label {
+			Some(label) => writeln!(self.out, "\tn{} -> n{} [label=\"{}\"];", from, to, escape(label)),
+			None => writeln!(self.out, "\tn{} -> n{};", from, to)
+		};
+	}
+
+	fn child(&mut self, from: usize, node: &Node, label: Option<&str>) {
+		let to = self.node(node);
+		self.edge(from, to, label);
+	}
+
+	fn children(&mut self, from: usize, nodes: &[Node]) {
+		for node in nodes {
+			self.child(from, node, None);
+		}
+	}
+
+	// Adds `node` and everything below it, returning the id of `node`.
+	fn node(&mut self, node: &Node) -> usize {
+		let pos = Some(node.span.start);
+		match node.kind {
+			NodeKind::Symbol(ref name) => self.add(name, pos),
+			NodeKind::Quote(ref datum) => self.add(&format!("'{}", quoted(datum)), pos),
+			NodeKind::If(ref test, ref consequent, ref alternative) => {
+				let id = self.add("if", pos);
+				self.child(id, test, Some("test"));
+				self.child(id, consequent, Some("then"));
+				if let Some(ref alternative) = *alternative {
+					self.child(id, alternative, Some("else"));
+				}
+				id
+			}
+			NodeKind::Cond(ref clauses) => {
+				let id = self.add("cond", pos);
+				for clause in clauses {
+					let clause_id = self.add(if clause.test.is_some() { "clause" } else { "else" }, None);
+					self.edge(id, clause_id, None);
+					if let Some(ref test) = clause.test {
+						self.child(clause_id, test, Some("test"));
+					}
+					self.children(clause_id, &clause.body);
+				}
+				id
+			}
+			NodeKind::And(ref operands) => {
+				let id = self.add("and", pos);
+				self.children(id, operands);
+				id
+			}
+			NodeKind::Or(ref operands) => {
+				let id = self.add("or", pos);
+				self.children(id, operands);
+				id
+			}
+			NodeKind::Define(ref name, ref value) => {
+				let id = self.add(&format!("define {}", name.name), pos);
+				self.child(id, value, None);
+				id
+			}
+			NodeKind::Lambda(ref fun) => {
+				let params: Vec<&str> = fun.params.iter().map(|p| &*p.name).collect();
+				let id = self.add(&format!("lambda ({})", params.join(" ")), pos);
+				self.children(id, &fun.body);
+				id
+			}
+			NodeKind::Application(ref f, ref args) => {
+				let id = self.add("call", pos);
+				self.child(id, f, Some("operator"));
+				self.children(id, args);
+				id
+			}
+			NodeKind::Integer(_) | NodeKind::Float(_) | NodeKind::Boolean(_) | NodeKind::Str(_) | NodeKind::List(_) => {
+				self.add(&quoted(node).to_string(), pos)
+			}
+		}
+	}
+}
+
+/// The syntax tree of the program `source` as a DOT graph, with the
+/// position of each expression.
+pub fn ast(source: &str) -> Result<String, CrustError> {
+	let tokens = lex(source)?;
+	let roots = parse(&tokens)?;
+	let mut tree = Tree { out: String::from("digraph ast {\n\tnode [shape=box];\n"), next: 0 };
+	let program = tree.add("program", None);
+	for root in &roots {
+		tree.child(program, root, None);
+	}
+	tree.out.push_str("}\n");
+	Ok(tree.out)
+}
+
+enum Item {
+	Pair(Rc<Pair>),
+	Procedure(Rc<Closure>),
+	Env(Rc<Env>)
+}
+
+impl Item {
+	fn ptr(&self) -> *const () {
+		match *self {
+			Item::Pair(ref p) => Rc::as_ptr(p) as *const (),
+			Item::Procedure(ref c) => Rc::as_ptr(c) as *const (),
+			Item::Env(ref e) => Rc::as_ptr(e) as *const ()
+		}
+	}
+}
+
+struct Graph {
+	nodes: String,
+	edges: String,
+	ids: HashMap<*const (), usize>,
+	// Items that have an id but have not been drawn yet. Drawing from a
+	// worklist rather than recursively keeps long lists off the stack.
+	todo: Vec<(usize, Item)>
+}
+
+impl Graph {
+	fn id(&mut self, item: Item) -> usize {
+		let ptr = item.ptr();
+		if let Some(&id) = self.ids.get(&ptr) {
+			return id;
+		}
+		let id = self.ids.len();
+		self.ids.insert(ptr, id);
+		self.todo.push((id, item));
+		id
+	}
+
+	fn edge(&mut self, from: usize, port: &str, to: Item) {
+		let to = self.id(to);
+		let _ = writeln!(self.edges, "\tn{}:{} -> n{};", from, port, to);
+	}
+
+	// The text of the field `port` of node `from` holding `v`. Atoms are
+	// written in the field, anything else is pointed to by an edge.
+	fn field(&mut self, from: usize, port: &str, v: &Value) -> String {
+		match *v {
+			Value::Pair(ref p) => self.edge(from, port, Item::Pair(p.clone())),
+			Value::Procedure(ref c) => self.edge(from, port, Item::Procedure(c.clone())),
+			ref v => return escape_field(&v.to_string())
+		}
+		String::new()
+	}
+
+	fn draw(&mut self, id: usize, item: Item) {
+		let label = match item {
+			Item::Pair(pair) => {
+				let car = self.field(id, "car", &pair.0);
+				let cdr = self.field(id, "cdr", &pair.1);
+				format!("<car> {}|<cdr> {}", car, cdr)
+			}
+			Item::Procedure(c) => {
+				let name = escape_field(&Value::Procedure(c.clone()).to_string());
+				// The global environment holds every builtin and is left out.
+				if c.env.parent.is_some() {
+					self.edge(id, "env", Item::Env(c.env.clone()));
+					format!("{}|<env> env", name)
+				} else {
+					name
+				}
+			}
+			Item::Env(env) => {
+				let mut vars: Vec<(Rc<str>, Value)> = env.vars.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+				vars.sort_by(|a, b| a.0.cmp(&b.0));
+				let mut fields = Vec::new();
+				for (i, (name, v)) in vars.iter().enumerate() {
+					let port = format!("v{}", i);
+					let value = self.field(id, &port, v);
+					fields.push(format!("<{}> {} = {}", port, escape_field(name), value));
+				}
+				if let Some(ref parent) = env.parent {
+					if parent.parent.is_some() {
+						self.edge(id, "parent", Item::Env(parent.clone()));
+						fields.push("<parent> parent".to_string());
+					}
+				}
+				format!("{{{}}}", fields.join("|"))
+			}
+		};
+		let _ = writeln!(self.nodes, "\tn{} [label=\"{}\"];", id, label);
+	}
+}
+
+/// `v` as a DOT graph of records: pairs with their car and cdr, procedures
+/// with the environment they were created in, and environments with their
+/// variables.
+pub fn value(v: &Value) -> String {
+	let mut graph = Graph { nodes: String::new(), edges: String::new(), ids: HashMap::new(), todo: Vec::new() };
+	let root = match *v {
+		Value::Pair(ref p) => Item::Pair(p.clone()),
+		Value::Procedure(ref c) => Item::Procedure(c.clone()),
+		ref v => return format!("digraph value {{\n\tn0 [shape=plaintext, label=\"{}\"];\n}}\n", escape(&v.to_string()))
+	};
+	graph.id(root);
+	while let Some((id, item)) = graph.todo.pop() {
+		graph.draw(id, item);
+	}
+	format!("digraph value {{\n\tnode [shape=record];\n{}{}}}\n", graph.nodes, graph.edges)
+}
+
+#[test]
+fn test_ast() {
+	assert_eq!("digraph ast {\n\tnode [shape=box];\n\tn0 [label=\"program\"];\n\
+	            \tn1 [label=\"define x\\n1:1\"];\n\tn2 [label=\"if\\n1:11\"];\n\
+	            \tn3 [label=\"#t\\n1:15\"];\n\tn2 -> n3 [label=\"test\"];\n\
+	            \tn4 [label=\"\\\"a\\\"\\n1:18\"];\n\tn2 -> n4 [label=\"then\"];\n\
+	            \tn1 -> n2;\n\tn0 -> n1;\n}\n",
+	           ast("(define x (if #t \"a\"))").unwrap());
+	assert_eq!("1:1: unbalanced parens", ast("(f").unwrap_err().to_string());
+}
+
+#[test]
+fn test_value() {
+	let value = |src| value(&super::Interpreter::new().eval_str(src).unwrap());
+	assert_eq!("digraph value {\n\tn0 [shape=plaintext, label=\"\\\"a\\\"\"];\n}\n", value("\"a\""));
+	// Both fields of the outer pair point to the same list.
+	assert_eq!("digraph value {\n\tnode [shape=record];\n\
+	            \tn0 [label=\"<car> |<cdr> \"];\n\
+	            \tn1 [label=\"<car> 1|<cdr> \\|\"];\n\
+	            \tn0:car -> n1;\n\tn0:cdr -> n1;\n}\n",
+	           value("(define xs (cons 1 '|)) (cons xs xs)"));
+	// A procedure whose environment refers back to it.
+	assert_eq!("digraph value {\n\tnode [shape=record];\n\
+	            \tn0 [label=\"#\\<procedure g\\>|<env> env\"];\n\
+	            \tn1 [label=\"{<v0> g = |<v1> n = 1}\"];\n\
+	            \tn0:env -> n1;\n\tn1:v0 -> n0;\n}\n",
+	           value("(define (f n) (define (g) n) g) (f 1)"));
+	// Long lists are drawn without recursion.
+	assert_eq!(100_001, value("(define (iota n acc) (if (= n 0) acc (iota (- n 1) (cons n acc)))) (iota 100000 '())").lines().count() / 2);
+}
@@ -0,0 +1,195 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Forking an interpreter: copying its global definitions into a fresh
+// global environment. Only what a child could change, or see changed, is
+// copied. Environments are, and with them the procedures that close over
+// them and the lists that hold such procedures. The syntax of procedures,
+// strings and data without procedures in it are immutable and shared with
+// the parent.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::{Closure, Env, Pair, Value};
+
+struct Copier<'e> {
+	from: &'e Rc<Env>,
+	to: &'e Rc<Env>,
+	envs: HashMap<*const Env, Rc<Env>>,
+	// The environments whose variables are still to be copied, as the
+	// original and its copy.
+	pending: Vec<(Rc<Env>, Rc<Env>)>,
+	// The copies of pairs, `None` for those that are shared.
+	pairs: HashMap<*const Pair, Option<Value>>,
+	closures: HashMap<*const Closure, Value>
+}
+
+impl<'e> Copier<'e> {
+	// The copy of `env`, or `None` if it belongs to another interpreter.
+	fn env(&mut self, env: &Rc<Env>) -> Option<Rc<Env>> {
+		if Rc::ptr_eq(env, self.from) {
+			return Some(self.to.clone());
+		}
+		if let Some(copy) = self.envs.get(&Rc::as_ptr(env)) {
+			return Some(copy.clone());
+		}
+		let parent = self.env(env.parent.as_ref()?)?;
+		let copy = Env::new(Some(parent));
+		self.envs.insert(Rc::as_ptr(env), copy.clone());
+		self.pending.push((env.clone(), copy.clone()));
+		Some(copy)
+	}
+
+	// The copy of `v`, or `None` if `v` can be shared as it is.
+	fn value(&mut self, v: &Value) -> Option<Value> {
+		match *v {
+			Value::Pair(ref first) => {
+				if let Some(copy) = self.pairs.get(&Rc::as_ptr(first)) {
+					return copy.clone();
+				}
+				// The pairs of the cdr chain, so that long lists do not
+				// nest, up to one that has been copied before.
+				let mut pairs = Vec::new();
+				let mut rest = v;
+				while let Value::Pair(ref pair) = *rest {
+					if !pairs.is_empty() && self.pairs.contains_key(&Rc::as_ptr(pair)) {
+						break;
+					}
+					pairs.push(pair.clone());
+					rest = &pair.1;
+				}
+				let tail = self.value(rest);
+				let cars: Vec<Option<Value>> = pairs.iter().map(|pair| self.value(&pair.0)).collect();
+				if tail.is_none() && cars.iter().all(Option::is_none) {
+					for pair in &pairs {
+						self.pairs.insert(Rc::as_ptr(pair), None);
+					}
+					return None;
+				}
+				let mut copy = tail.unwrap_or_else(|| rest.clone());
+				for (pair, car) in pairs.iter().zip(cars).rev() {
+					copy = Value::cons(car.unwrap_or_else(|| pair.0.clone()), copy);
+					self.pairs.insert(Rc::as_ptr(pair), Some(copy.clone()));
+				}
+				Some(copy)
+			}
+			Value::Procedure(ref c) => {
+				if let Some(copy) = self.closures.get(&Rc::as_ptr(c)) {
+					return Some(copy.clone());
+				}
+				let copy = Value::procedure(c.fun.clone(), self.env(&c.env)?);
+				self.closures.insert(Rc::as_ptr(c), copy.clone());
+				Some(copy)
+			}
+			// The parent's own builtins are replaced by the child's, which
+			// keep their state, like pending timers, apart.
+			Value::Builtin(ref b) => match (self.from.lookup(&b.name), self.to.lookup(&b.name)) {
+				(Some(Value::Builtin(ref global)), Some(copy @ Value::Builtin(_))) if Rc::ptr_eq(global, b) => Some(copy),
+				_ => None
+			},
+			_ => None
+		}
+	}
+}
+
+// Defines the globals of `from` in `to`, which holds the builtins of a new
+// interpreter. Builtins bound under their own names that `to` does not
+// have, like those registered by the host, are shared.
+pub(super) fn fork(from: &Rc<Env>, to: &Rc<Env>) {
+	let globals: Vec<(Rc<str>, Value)> = from.vars.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+	for (name, v) in &globals {
+		if matches!(*v, Value::Builtin(ref b) if b.name == *name) && to.lookup(name).is_none() {
+			to.define(name.clone(), v.clone());
+		}
+	}
+	let mut copier = Copier {
+		from,
+		to,
+		envs: HashMap::new(),
+		pending: Vec::new(),
+		pairs: HashMap::new(),
+		closures: HashMap::new()
+	};
+	for (name, v) in &globals {
+		if !matches!(*v, Value::Builtin(ref b) if b.name == *name) {
+			let copy = copier.value(v).unwrap_or_else(|| v.clone());
+			to.define(name.clone(), copy);
+		}
+	}
+	while let Some((env, copy)) = copier.pending.pop() {
+		let vars: Vec<(Rc<str>, Value)> = env.vars.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+		for (name, v) in vars {
+			let value = copier.value(&v).unwrap_or(v);
+			copy.define(name, value);
+		}
+	}
+}
+
+#[test]
+fn test_fork() {
+	use super::Interpreter;
+	let mut parent = Interpreter::new();
+	parent.register("double", |args| match args[0] {
+		Value::Integer(n) => Ok(Value::Integer(n * 2)),
+		_ => Ok(Value::Nil)
+	});
+	parent.eval_str("(define (f) (g)) (define (g) 1) (define data (list 1 \"a\" '(b))) \
+	                 (define (counter n) (lambda () n)) (define fs (list (counter 1) car))").unwrap();
+	let mut child = parent.fork();
+	child.eval_str("(define (g) 2) (define x 3)").unwrap();
+	// Procedures defined in the parent see the child's definitions.
+	assert_eq!("2", child.eval_str("(f)").unwrap().to_string());
+	assert_eq!("1", parent.eval_str("(f)").unwrap().to_string());
+	assert_eq!("1:1: unbound symbol 'x'", parent.eval_str("x").unwrap_err().to_string());
+	assert_eq!("(1 a)", child.eval_str("(list ((car fs)) ((car (cdr fs)) '(a)))").unwrap().to_string());
+	assert_eq!("8", child.eval_str("(double 4)").unwrap().to_string());
+	let global = |interp: &Interpreter, name: &str| interp.env.lookup(name).unwrap();
+	match (global(&parent, "data"), global(&child, "data")) {
+		(Value::Pair(ref a), Value::Pair(ref b)) => assert!(Rc::ptr_eq(a, b)),
+		_ => unreachable!()
+	}
+	match (global(&parent, "fs"), global(&child, "fs")) {
+		(Value::Pair(ref a), Value::Pair(ref b)) => assert!(!Rc::ptr_eq(a, b)),
+		_ => unreachable!()
+	}
+	match (global(&parent, "car"), global(&child, "car")) {
+		(Value::Builtin(ref a), Value::Builtin(ref b)) => assert!(!Rc::ptr_eq(a, b)),
+		_ => unreachable!()
+	}
+	// The builtins in `fs` are the child's own.
+	assert_eq!("#t", child.eval_str("(eq? car (car (cdr fs)))").unwrap().to_string());
+}
+
+#[test]
+fn test_fork_timers() {
+	use std::cell::Cell;
+	let mut parent = super::Interpreter::new();
+	let ticks = Rc::new(Cell::new(0));
+	let counter = ticks.clone();
+	parent.register("tick", move |_| {
+		counter.set(counter.get() + 1);
+		Ok(Value::Unspecified)
+	});
+	let mut child = parent.fork();
+	child.eval_str("(after 1 tick)").unwrap();
+	// The child's timers are not the parent's.
+	parent.eval_str("(run-scheduler)").unwrap();
+	assert_eq!(0, ticks.get());
+	child.eval_str("(run-scheduler)").unwrap();
+	assert_eq!(1, ticks.get());
+}
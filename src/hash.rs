@@ -0,0 +1,285 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Checksums: CRC-32 as used by gzip and zip, and SHA-256. Both take their
+// input in pieces, so that files can be hashed a buffer at a time.
+
+use std::fs::File;
+use std::io::Read;
+use std::rc::Rc;
+
+use super::{builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+const fn crc_table() -> [u32; 256] {
+	let mut table = [0; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut c = i as u32;
+		let mut k = 0;
+		while k < 8 {
+			c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+			k += 1;
+		}
+		table[i] = c;
+		i += 1;
+	}
+	table
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+pub(super) struct Crc32(u32);
+
+impl Crc32 {
+	pub(super) fn new() -> Crc32 {
+		Crc32(!0)
+	}
+
+	pub(super) fn update(&mut self, data: &[u8]) {
+		self.0 = data.iter().fold(self.0, |crc, &b| CRC_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8));
+	}
+
+	pub(super) fn finish(&self) -> u32 {
+		!self.0
+	}
+}
+
+pub(super) fn crc32(data: &[u8]) -> u32 {
+	let mut crc = Crc32::new();
+	crc.update(data);
+	crc.finish()
+}
+
+const SHA256_K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+// SHA-256 after FIPS 180-4.
+pub(super) struct Sha256 {
+	state: [u32; 8],
+	block: [u8; 64],
+	used: usize,
+	len: u64
+}
+
+impl Sha256 {
+	pub(super) fn new() -> Sha256 {
+		Sha256 {
+			state: [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+			block: [0; 64],
+			used: 0,
+			len: 0
+		}
+	}
+
+	pub(super) fn update(&mut self, mut data: &[u8]) {
+		self.len += data.len() as u64;
+		while !data.is_empty() {
+			let n = (64 - self.used).min(data.len());
+			self.block[self.used..self.used + n].copy_from_slice(&data[..n]);
+			self.used += n;
+			data = &data[n..];
+			if self.used == 64 {
+				self.compress();
+				self.used = 0;
+			}
+		}
+	}
+
+	fn compress(&mut self) {
+		let mut w = [0u32; 64];
+		for (i, word) in self.block.chunks(4).enumerate() {
+			w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+		}
+		for i in 16..64 {
+			let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+			let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+			w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+		}
+		let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+		for i in 0..64 {
+			let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+			let ch = (e & f) ^ (!e & g);
+			let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+			let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+			let maj = (a & b) ^ (a & c) ^ (b & c);
+			let t2 = s0.wrapping_add(maj);
+			h = g;
+			g = f;
+			f = e;
+			e = d.wrapping_add(t1);
+			d = c;
+			c = b;
+			b = a;
+			a = t1.wrapping_add(t2);
+		}
+		for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+			*s = s.wrapping_add(v);
+		}
+	}
+
+	pub(super) fn finish(mut self) -> [u8; 32] {
+		let bits = self.len.wrapping_mul(8);
+		self.update(&[0x80]);
+		while self.used != 56 {
+			self.update(&[0]);
+		}
+		self.update(&bits.to_be_bytes());
+		let mut digest = [0; 32];
+		for (out, s) in digest.chunks_mut(4).zip(self.state) {
+			out.copy_from_slice(&s.to_be_bytes());
+		}
+		digest
+	}
+}
+
+// SHA-1, which is broken for security but still part of protocols such as
+// the WebSocket handshake.
+#[cfg(feature = "websocket")]
+pub(super) fn sha1(data: &[u8]) -> [u8; 20] {
+	let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+	let mut message = data.to_vec();
+	message.push(0x80);
+	while message.len() % 64 != 56 {
+		message.push(0);
+	}
+	message.extend((data.len() as u64).wrapping_mul(8).to_be_bytes());
+	for block in message.chunks(64) {
+		let mut w = [0u32; 80];
+		for (i, word) in block.chunks(4).enumerate() {
+			w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+		}
+		for i in 16..80 {
+			w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+		}
+		let [mut a, mut b, mut c, mut d, mut e] = h;
+		for (i, &wi) in w.iter().enumerate() {
+			let (f, k) = match i {
+				0..=19 => ((b & c) | (!b & d), 0x5a827999),
+				20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+				40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+				_ => (b ^ c ^ d, 0xca62c1d6)
+			};
+			let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(wi);
+			e = d;
+			d = c;
+			c = b.rotate_left(30);
+			b = a;
+			a = t;
+		}
+		for (s, v) in h.iter_mut().zip([a, b, c, d, e]) {
+			*s = s.wrapping_add(v);
+		}
+	}
+	let mut digest = [0; 20];
+	for (out, s) in digest.chunks_mut(4).zip(h) {
+		out.copy_from_slice(&s.to_be_bytes());
+	}
+	digest
+}
+
+pub(super) fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn string_arg<'v>(name: &str, args: &'v [Value]) -> Result<&'v str, CrustError> {
+	check_arity(name, args, 1, Some(1))?;
+	match args[0] {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type("a string", v))
+	}
+}
+
+// (crc32 string) is the CRC-32 of the UTF-8 encoding of a string.
+fn builtin_crc32(args: &[Value]) -> Result<Value, CrustError> {
+	let s = string_arg("crc32", args)?;
+	Ok(Value::Integer(crc32(s.as_bytes()) as i64))
+}
+
+// (file-sha256 path) is the SHA-256 of a file as a hexadecimal string. The
+// file is read a buffer at a time, so it may be larger than memory.
+fn builtin_file_sha256(args: &[Value]) -> Result<Value, CrustError> {
+	let path = string_arg("file-sha256", args)?;
+	let in_file = |e: std::io::Error| -> CrustError { ErrorKind::Io(format!("{}: {}", path, e)).into() };
+	let mut file = File::open(path).map_err(in_file)?;
+	let mut sha = Sha256::new();
+	let mut buf = vec![0; 1 << 16];
+	loop {
+		match file.read(&mut buf) {
+			Ok(0) => break,
+			Ok(n) => sha.update(&buf[..n]),
+			Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+			Err(e) => return Err(in_file(e))
+		}
+	}
+	Ok(Value::Str(Rc::from(hex(&sha.finish()))))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("crc32", builtin_crc32),
+		("file-sha256", builtin_file_sha256),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_crc32() {
+	assert_eq!(crc32(b""), 0);
+	assert_eq!(crc32(b"123456789"), 0xcbf43926);
+	let mut crc = Crc32::new();
+	crc.update(b"1234");
+	crc.update(b"56789");
+	assert_eq!(crc.finish(), 0xcbf43926);
+}
+
+#[test]
+fn test_sha256() {
+	let sha = |data: &[u8]| {
+		let mut sha = Sha256::new();
+		sha.update(data);
+		hex(&sha.finish())
+	};
+	assert_eq!(sha(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+	assert_eq!(sha(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+	assert_eq!(sha(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+		"248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+	// Fed in pieces that straddle the blocks.
+	let data = vec![b'a'; 1000];
+	let mut pieces = Sha256::new();
+	for chunk in data.chunks(7) {
+		pieces.update(chunk);
+	}
+	assert_eq!(hex(&pieces.finish()), sha(&data));
+}
+
+#[cfg(feature = "websocket")]
+#[test]
+fn test_sha1() {
+	assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+	assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+		"84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+}
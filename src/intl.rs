@@ -0,0 +1,255 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Locale-aware formatting of numbers and dates, behind the `intl` feature.
+// There is no locale database to lean on without dependencies, so crust
+// carries the separators and names of a handful of locales itself.
+
+use std::fmt::Write as _;
+use std::rc::Rc;
+
+use super::{builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, Value};
+
+struct Locale {
+	name: &'static str,
+	decimal: char,
+	group: char,
+	months: [&'static str; 12],
+	// Starting with Monday.
+	weekdays: [&'static str; 7]
+}
+
+const LOCALES: &[Locale] = &[
+	Locale {
+		name: "en",
+		decimal: '.',
+		group: ',',
+		months: ["January", "February", "March", "April", "May", "June", "July", "August", "September", "October",
+		         "November", "December"],
+		weekdays: ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"]
+	},
+	Locale {
+		name: "de",
+		decimal: ',',
+		group: '.',
+		months: ["Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August", "September", "Oktober",
+		         "November", "Dezember"],
+		weekdays: ["Montag", "Dienstag", "Mittwoch", "Donnerstag", "Freitag", "Samstag", "Sonntag"]
+	},
+	Locale {
+		name: "fr",
+		decimal: ',',
+		group: '\u{a0}',
+		months: ["janvier", "février", "mars", "avril", "mai", "juin", "juillet", "août", "septembre", "octobre",
+		         "novembre", "décembre"],
+		weekdays: ["lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi", "dimanche"]
+	},
+	Locale {
+		name: "es",
+		decimal: ',',
+		group: '.',
+		months: ["enero", "febrero", "marzo", "abril", "mayo", "junio", "julio", "agosto", "septiembre", "octubre",
+		         "noviembre", "diciembre"],
+		weekdays: ["lunes", "martes", "miércoles", "jueves", "viernes", "sábado", "domingo"]
+	},
+	Locale {
+		name: "sv",
+		decimal: ',',
+		group: '\u{a0}',
+		months: ["januari", "februari", "mars", "april", "maj", "juni", "juli", "augusti", "september", "oktober",
+		         "november", "december"],
+		weekdays: ["måndag", "tisdag", "onsdag", "torsdag", "fredag", "lördag", "söndag"]
+	}
+];
+
+// The locale named by the optional argument at `i`, English by default.
+fn locale(args: &[Value], i: usize) -> Result<&'static Locale, CrustError> {
+	let name = match args.get(i) {
+		None => return Ok(&LOCALES[0]),
+		Some(Value::Str(s)) | Some(Value::Symbol(s)) => s,
+		Some(v) => return Err(wrong_type("a locale", v))
+	};
+	LOCALES.iter().find(|l| l.name == &**name).ok_or_else(|| wrong_type("a locale (en, de, fr, es or sv)", &args[i]))
+}
+
+// Writes `digits` with `group` between every three digits from the right.
+fn group_digits(out: &mut String, digits: &str, group: char) {
+	for (i, c) in digits.chars().enumerate() {
+		if i > 0 && (digits.len() - i).is_multiple_of(3) {
+			out.push(group);
+		}
+		out.push(c);
+	}
+}
+
+fn format_number(x: &Value, decimals: usize, locale: &Locale) -> Result<String, CrustError> {
+	let s = match *x {
+		Value::Integer(n) if decimals == 0 => n.to_string(),
+		Value::Integer(n) => format!("{:.*}", decimals, n as f64),
+		Value::Float(f) if f.is_finite() => format!("{:.*}", decimals, f),
+		ref v => return Err(wrong_type("a finite number", v))
+	};
+	let (sign, s) = match s.strip_prefix('-') {
+		Some(s) => ("-", s),
+		None => ("", &*s)
+	};
+	let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+	let mut out = sign.to_string();
+	group_digits(&mut out, int, locale.group);
+	if !frac.is_empty() {
+		out.push(locale.decimal);
+		out.push_str(frac);
+	}
+	Ok(out)
+}
+
+// (format-number x [decimals [locale]]) writes `x` rounded to `decimals`
+// places, which default to 0 for integers and 2 for floats, with the
+// separators of `locale`.
+fn builtin_format_number(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("format-number", args, 1, Some(3))?;
+	let decimals = match args.get(1) {
+		None if matches!(args[0], Value::Float(_)) => 2,
+		None => 0,
+		Some(&Value::Integer(n @ 0..=20)) => n as usize,
+		Some(v) => return Err(wrong_type("a number of decimals from 0 to 20", v))
+	};
+	Ok(Value::Str(Rc::from(format_number(&args[0], decimals, locale(args, 2)?)?)))
+}
+
+// The year, month and day, counting from 1, of the day `days` after
+// 1970-01-01 in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, usize, usize) {
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = (doy - (153 * mp + 2) / 5 + 1) as usize;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 } as usize;
+	let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+	(year, month, day)
+}
+
+// Formats the UTC time `secs` seconds after the Unix epoch with `pattern`,
+// in which yyyy, yy, MMMM, MMM, MM, M, dd, d, EEEE, EEE, HH, H, mm and ss
+// stand for parts of the date, anything in single quotes is literal and ''
+// is a quote.
+fn format_date(secs: i64, pattern: &str, locale: &Locale) -> String {
+	let days = secs.div_euclid(86400);
+	let time = secs.rem_euclid(86400);
+	let (year, month, day) = civil_from_days(days);
+	// 1970-01-01 was a Thursday.
+	let weekday = locale.weekdays[(days + 3).rem_euclid(7) as usize];
+	let month_name = locale.months[month - 1];
+	let mut out = String::new();
+	let chars: Vec<char> = pattern.chars().collect();
+	let mut i = 0;
+	while i < chars.len() {
+		let c = chars[i];
+		let n = chars[i..].iter().take_while(|&&d| d == c).count();
+		let _ = match (c, n) {
+			('\'', _) => {
+				// Quoted text, in which, as outside it, '' is a quote.
+				i += 1;
+				if chars.get(i) == Some(&'\'') {
+					out.push('\'');
+					i += 1;
+					continue;
+				}
+				while i < chars.len() {
+					i += 1;
+					match chars[i - 1] {
+						'\'' if chars.get(i) == Some(&'\'') => {
+							out.push('\'');
+							i += 1;
+						}
+						'\'' => break,
+						c => out.push(c)
+					}
+				}
+				continue;
+			}
+			('y', 2) => write!(out, "{:02}", year.rem_euclid(100)),
+			('y', _) => write!(out, "{:04}", year),
+			('M', 1) => write!(out, "{}", month),
+			('M', 2) => write!(out, "{:02}", month),
+			('M', 3) => write!(out, "{}", month_name.chars().take(3).collect::<String>()),
+			('M', _) => write!(out, "{}", month_name),
+			('d', 1) => write!(out, "{}", day),
+			('d', _) => write!(out, "{:02}", day),
+			('E', 1..=3) => write!(out, "{}", weekday.chars().take(3).collect::<String>()),
+			('E', _) => write!(out, "{}", weekday),
+			('H', 1) => write!(out, "{}", time / 3600),
+			('H', _) => write!(out, "{:02}", time / 3600),
+			('m', _) => write!(out, "{:02}", time / 60 % 60),
+			('s', _) => write!(out, "{:02}", time % 60),
+			_ => write!(out, "{}", chars[i..i + n].iter().collect::<String>())
+		};
+		i += n;
+	}
+	out
+}
+
+// (format-date seconds pattern [locale]) formats a Unix time, in UTC.
+fn builtin_format_date(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("format-date", args, 2, Some(3))?;
+	let secs = match args[0] {
+		Value::Integer(n) => n,
+		ref v => return Err(wrong_type("an integer number of seconds", v))
+	};
+	match args[1] {
+		Value::Str(ref pattern) => Ok(Value::Str(Rc::from(format_date(secs, pattern, locale(args, 2)?)))),
+		ref v => Err(wrong_type("a pattern string", v))
+	}
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("format-number", builtin_format_number),
+		("format-date", builtin_format_date),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_format_number() {
+	let mut interp = super::Interpreter::new();
+	let mut format = |src| interp.eval_str(src).unwrap().to_string();
+	assert_eq!("\"1,234,567\"", format("(format-number 1234567)"));
+	assert_eq!("\"-1,234.57\"", format("(format-number -1234.567)"));
+	assert_eq!("\"999.0\"", format("(format-number 999 1)"));
+	assert_eq!("\"1.000.000,5\"", format("(format-number 1000000.5 1 'de)"));
+	assert_eq!("\"12\u{a0}345,00\"", format("(format-number 12345 2 \"sv\")"));
+	assert_eq!("\"-9,223,372,036,854,775,808\"", format("(format-number (- -9223372036854775807 1))"));
+	let error = super::Interpreter::new().eval_str("(format-number 1 0 'xx)").unwrap_err().to_string();
+	assert_eq!("1:1: expected a locale (en, de, fr, es or sv), got xx", error);
+}
+
+#[test]
+fn test_format_date() {
+	let en = &LOCALES[0];
+	assert_eq!("1970-01-01 00:00:00 Thursday", format_date(0, "yyyy-MM-dd HH:mm:ss EEEE", en));
+	// 2000-02-29 is a leap day.
+	assert_eq!("Tue 29 Feb '00, 23:59", format_date(951868740, "EEE d MMM ''yy, HH:mm", en));
+	assert_eq!("Dienstag, 29. Februar 2000", format_date(951868740, "EEEE, d. MMMM yyyy", &LOCALES[1]));
+	assert_eq!("1969-12-31 23:59:59", format_date(-1, "yyyy-MM-dd HH:mm:ss", en));
+	assert_eq!("at 1 o'clock", format_date(3600, "'at' H 'o''clock'", en));
+}
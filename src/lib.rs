@@ -0,0 +1,2678 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+//! An interpreter for crust, a small Scheme, for embedding in other
+//! programs:
+//!
+//! ```
+//! use crust::{ErrorKind, Interpreter, Value};
+//!
+//! let mut interp = Interpreter::new();
+//! interp.register("string-length", |args| match args {
+//!     [Value::Str(s)] => Ok(Value::Integer(s.chars().count() as i64)),
+//!     _ => Err(ErrorKind::Host("string-length expects a string".to_string()).into())
+//! });
+//! interp.eval_str("(define (twice n) (* 2 n))").unwrap();
+//! let v = interp.eval_str("(twice (string-length \"crust\"))").unwrap();
+//! assert_eq!("10", v.to_string());
+//! ```
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::mem;
+use std::fmt;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::panic;
+use std::slice;
+use std::time::Duration;
+
+#[cfg(any(feature = "mail", feature = "websocket"))]
+mod base64;
+mod cache;
+#[cfg(feature = "compress")]
+mod compress;
+pub mod config;
+pub mod dot;
+mod fork;
+mod hash;
+#[cfg(feature = "intl")]
+mod intl;
+mod limit;
+#[cfg(feature = "mail")]
+mod mail;
+#[cfg(feature = "net")]
+mod net;
+mod pool;
+mod process;
+pub mod reduce;
+pub mod refactor;
+mod serial;
+mod signal;
+mod snapshot;
+mod svg;
+mod tar;
+pub mod task;
+mod term;
+mod timer;
+#[cfg(feature = "tui")]
+mod tui;
+mod url;
+mod watch;
+#[cfg(feature = "websocket")]
+mod websocket;
+
+pub use limit::{Usage, MAX_DEPTH, STACK_SIZE};
+pub use pool::{Eval, Pool};
+
+// A position in the source: 1-based line and column (in characters) and
+// 0-based byte offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pos {
+	pub line: usize,
+	pub col: usize,
+	pub offset: usize
+}
+
+impl fmt::Display for Pos {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}:{}", self.line, self.col)
+	}
+}
+
+// The source range of a token or node, `end` is the position just past
+// its last character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Span {
+	start: Pos,
+	end: Pos
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+	UnbalancedParens,
+	UnterminatedString,
+	// Source that is not UTF-8, with the byte offset of the first byte
+	// that is not part of a valid sequence.
+	InvalidUtf8(usize),
+	InvalidToken(String),
+	UnexpectedToken(String),
+	// A special form used with the wrong shape, e.g. `(if)`.
+	BadSyntax(String),
+	UnboundSymbol(String),
+	ArityMismatch { name: String, min: usize, max: Option<usize>, got: usize },
+	DivisionByZero,
+	Overflow,
+	WrongType { expected: &'static str, got: String },
+	NotAProcedure(String),
+	// A `crust refactor` command cannot be applied to the program.
+	Refactor(String),
+	// The check given to `crust reduce` does not hold for the program to
+	// reduce.
+	CheckFails,
+	// An error returned by a procedure registered with
+	// `Interpreter::register`.
+	Host(String),
+	// A builtin failed to read or write a file.
+	Io(String),
+	// A snapshot could not be taken or restored.
+	Snapshot(String),
+	// The evaluation ran for longer than the interpreter's time limit.
+	TimeLimit,
+	// The evaluation made more procedure calls than its fuel limit allows.
+	FuelExhausted,
+	// Calls nested deeper than `MAX_DEPTH` in an evaluation with a limit,
+	// or with the depth limit on.
+	DepthLimit,
+	// The tasks given to `crust task` cannot be run.
+	Task(String),
+	// A configuration does not match its schema.
+	Config(String),
+	// `(exit code)` was called. This unwinds like an error, so that the
+	// host decides what stopping the program means.
+	Exit(i32)
+}
+
+impl fmt::Display for ErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			ErrorKind::UnbalancedParens => write!(f, "unbalanced parens"),
+			ErrorKind::UnterminatedString => write!(f, "unterminated string"),
+			ErrorKind::InvalidUtf8(offset) => write!(f, "invalid UTF-8 at byte offset {}", offset),
+			ErrorKind::InvalidToken(ref t) => write!(f, "invalid token '{}'", t),
+			ErrorKind::UnexpectedToken(ref t) => write!(f, "unexpected token '{}'", t),
+			ErrorKind::BadSyntax(ref msg) => write!(f, "bad syntax: {}", msg),
+			ErrorKind::UnboundSymbol(ref s) => write!(f, "unbound symbol '{}'", s),
+			ErrorKind::ArityMismatch { ref name, min, max, got } => {
+				write!(f, "wrong number of arguments to {}: expected ", name)?;
+				match max {
+					Some(max) if max == min => write!(f, "{}", min)?,
+					Some(max) => write!(f, "{} to {}", min, max)?,
+					None => write!(f, "at least {}", min)?
+				}
+				write!(f, ", got {}", got)
+			}
+			ErrorKind::DivisionByZero => write!(f, "division by zero"),
+			ErrorKind::Overflow => write!(f, "arithmetic overflow"),
+			ErrorKind::WrongType { expected, ref got } => write!(f, "expected {}, got {}", expected, got),
+			ErrorKind::NotAProcedure(ref v) => write!(f, "not a procedure: {}", v),
+			ErrorKind::Refactor(ref msg) => write!(f, "{}", msg),
+			ErrorKind::CheckFails => write!(f, "the check does not hold for the original program"),
+			ErrorKind::Host(ref msg) => write!(f, "{}", msg),
+			ErrorKind::Io(ref msg) => write!(f, "{}", msg),
+			ErrorKind::Snapshot(ref msg) => write!(f, "{}", msg),
+			ErrorKind::TimeLimit => write!(f, "time limit exceeded"),
+			ErrorKind::FuelExhausted => write!(f, "out of fuel"),
+			ErrorKind::DepthLimit => write!(f, "too many nested calls"),
+			ErrorKind::Task(ref msg) => write!(f, "{}", msg),
+			ErrorKind::Config(ref msg) => write!(f, "invalid configuration: {}", msg),
+			ErrorKind::Exit(code) => write!(f, "exit with status {}", code)
+		}
+	}
+}
+
+// Errors raised inside builtins do not know where they happened; `pos` is
+// filled in by the application that called the builtin.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CrustError {
+	pub kind: ErrorKind,
+	pub pos: Option<Pos>
+}
+
+impl CrustError {
+	fn new(kind: ErrorKind, pos: Pos) -> CrustError {
+		CrustError { kind, pos: Some(pos) }
+	}
+
+	fn or_at(self, pos: Pos) -> CrustError {
+		CrustError { pos: self.pos.or(Some(pos)), ..self }
+	}
+}
+
+impl From<ErrorKind> for CrustError {
+	fn from(kind: ErrorKind) -> CrustError {
+		CrustError { kind, pos: None }
+	}
+}
+
+impl std::error::Error for CrustError {}
+
+impl fmt::Display for CrustError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.pos {
+			Some(pos) => write!(f, "{}: {}", pos, self.kind),
+			None => write!(f, "{}", self.kind)
+		}
+	}
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+	LeftParen,
+	RightParen,
+	Quote,
+	Integer(i64),
+	Float(f64),
+	Boolean(bool),
+	Str(String),
+	Symbol(&'a str)
+}
+
+// Floats print like Rust's `{:?}` so that they always have a decimal point
+// or exponent, except for the infinities and NaN that have no literal.
+fn write_float(f: &mut fmt::Formatter, x: f64) -> fmt::Result {
+	if x.is_nan() {
+		write!(f, "+nan.0")
+	} else if x.is_infinite() {
+		write!(f, "{}inf.0", if x > 0.0 { "+" } else { "-" })
+	} else {
+		write!(f, "{:?}", x)
+	}
+}
+
+// Writes `s` as a string literal that lexes back to `s`.
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+	write!(f, "\"")?;
+	for c in s.chars() {
+		match c {
+			'"' => write!(f, "\\\"")?,
+			'\\' => write!(f, "\\\\")?,
+			'\n' => write!(f, "\\n")?,
+			'\t' => write!(f, "\\t")?,
+			'\r' => write!(f, "\\r")?,
+			c => write!(f, "{}", c)?
+		}
+	}
+	write!(f, "\"")
+}
+
+impl<'a> fmt::Display for Token<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Token::LeftParen => write!(f, "("),
+			Token::RightParen => write!(f, ")"),
+			Token::Quote => write!(f, "'"),
+			Token::Integer(n) => write!(f, "{}", n),
+			Token::Float(x) => write_float(f, x),
+			Token::Boolean(b) => write!(f, "{}", if b { "#t" } else { "#f" }),
+			Token::Str(ref s) => write_string(f, s),
+			Token::Symbol(s) => write!(f, "{}", s)
+		}
+	}
+}
+
+// A name being bound by `define` or a lambda parameter list.
+#[derive(Debug)]
+struct Ident {
+	name: Rc<str>,
+	span: Span
+}
+
+#[derive(Debug)]
+struct Fun {
+	name: Option<Rc<str>>,
+	params: Vec<Ident>,
+	body: Vec<Node>
+}
+
+// A `cond` clause, `test` is `None` for the `else` clause.
+#[derive(Debug)]
+struct Clause {
+	test: Option<Node>,
+	body: Vec<Node>
+}
+
+#[derive(Debug)]
+struct Node {
+	kind: NodeKind,
+	span: Span
+}
+
+#[derive(Debug)]
+enum NodeKind {
+	Symbol(Rc<str>),
+	Integer(i64),
+	Float(f64),
+	Boolean(bool),
+	Str(Rc<str>),
+	Quote(Box<Node>),
+	// A list inside a quoted datum, it is never evaluated as a form.
+	List(Vec<Node>),
+	If(Box<Node>, Box<Node>, Option<Box<Node>>),
+	Cond(Vec<Clause>),
+	And(Vec<Node>),
+	Or(Vec<Node>),
+	Define(Ident, Box<Node>),
+	// Shared with the closures created from it.
+	Lambda(Rc<Fun>),
+	Application(Box<Node>, Vec<Node>)
+}
+
+/// A crust value.
+#[derive(Clone)]
+pub enum Value {
+	/// The value of expressions that have no useful value, like `define`.
+	Unspecified,
+	/// The empty list.
+	Nil,
+	Boolean(bool),
+	Integer(i64),
+	Float(f64),
+	Str(Rc<str>),
+	Symbol(Rc<str>),
+	Pair(Rc<Pair>),
+	Builtin(Rc<Builtin>),
+	Procedure(Rc<Closure>)
+}
+
+/// The car and cdr of a pair.
+pub struct Pair(pub Value, pub Value);
+
+// Dropping a list would otherwise recurse once per element, overflowing the
+// stack on long lists, so the pairs of the cdr chain that nobody else holds
+// are unlinked one at a time.
+impl Drop for Pair {
+	fn drop(&mut self) {
+		limit::freed(mem::size_of::<Pair>());
+		let mut rest = mem::replace(&mut self.1, Value::Nil);
+		while let Value::Pair(pair) = rest {
+			match Rc::try_unwrap(pair) {
+				Ok(mut pair) => rest = mem::replace(&mut pair.1, Value::Nil),
+				Err(_) => break
+			}
+		}
+	}
+}
+
+type NativeFn = dyn Fn(&[Value]) -> Result<Value, CrustError>;
+
+/// A procedure implemented in Rust, either one of crust's own or one
+/// registered with [`Interpreter::register`].
+pub struct Builtin {
+	name: Rc<str>,
+	fun: Box<NativeFn>,
+	// Whether this is `apply`, whose call of its first argument is made by
+	// the evaluator so that it can be a tail call.
+	is_apply: bool
+}
+
+/// A procedure defined in crust, together with the environment it was
+/// created in.
+pub struct Closure {
+	fun: Rc<Fun>,
+	env: Rc<Env>
+}
+
+impl Drop for Closure {
+	fn drop(&mut self) {
+		limit::freed(mem::size_of::<Closure>());
+	}
+}
+
+impl Value {
+	pub fn cons(car: Value, cdr: Value) -> Value {
+		limit::allocated(mem::size_of::<Pair>());
+		Value::Pair(Rc::new(Pair(car, cdr)))
+	}
+
+	fn procedure(fun: Rc<Fun>, env: Rc<Env>) -> Value {
+		limit::allocated(mem::size_of::<Closure>());
+		Value::Procedure(Rc::new(Closure { fun, env }))
+	}
+
+	pub fn list(items: Vec<Value>) -> Value {
+		items.into_iter().rev().fold(Value::Nil, |list, item| Value::cons(item, list))
+	}
+
+	/// Everything but #f counts as true in a test.
+	pub fn is_true(&self) -> bool {
+		!matches!(*self, Value::Boolean(false))
+	}
+}
+
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Value::Unspecified => write!(f, "#<unspecified>"),
+			Value::Nil => write!(f, "()"),
+			Value::Boolean(b) => write!(f, "{}", if b { "#t" } else { "#f" }),
+			Value::Integer(n) => write!(f, "{}", n),
+			Value::Float(x) => write_float(f, x),
+			Value::Str(ref s) => write_string(f, s),
+			Value::Symbol(ref s) => write!(f, "{}", s),
+			Value::Pair(ref pair) => {
+				write!(f, "({}", pair.0)?;
+				let mut rest = &pair.1;
+				loop {
+					match *rest {
+						Value::Pair(ref pair) => {
+							write!(f, " {}", pair.0)?;
+							rest = &pair.1;
+						}
+						Value::Nil => break,
+						ref v => {
+							write!(f, " . {}", v)?;
+							break;
+						}
+					}
+				}
+				write!(f, ")")
+			}
+			Value::Builtin(ref b) => write!(f, "#<builtin {}>", b.name),
+			Value::Procedure(ref c) => match c.fun.name {
+				Some(ref name) => write!(f, "#<procedure {}>", name),
+				None => write!(f, "#<procedure>")
+			}
+		}
+	}
+}
+
+// Closures point back into the environment they were created in, which
+// usually contains the closure itself, so Debug must not follow `env`.
+impl fmt::Debug for Value {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Display::fmt(self, f)
+	}
+}
+
+struct Env {
+	vars: RefCell<HashMap<Rc<str>, Value>>,
+	parent: Option<Rc<Env>>
+}
+
+// What a variable counts as on the heap, see `limit`.
+const VAR_SIZE: usize = mem::size_of::<(Rc<str>, Value)>();
+
+impl Env {
+	fn new(parent: Option<Rc<Env>>) -> Rc<Env> {
+		limit::allocated(mem::size_of::<Env>());
+		Rc::new(Env { vars: RefCell::new(HashMap::new()), parent })
+	}
+
+	fn lookup(&self, name: &str) -> Option<Value> {
+		match self.vars.borrow().get(name) {
+			Some(v) => Some(v.clone()),
+			None => self.parent.as_ref().and_then(|p| p.lookup(name))
+		}
+	}
+
+	fn define(&self, name: Rc<str>, value: Value) {
+		if self.vars.borrow_mut().insert(name, value).is_none() {
+			limit::allocated(VAR_SIZE);
+		}
+	}
+}
+
+impl Drop for Env {
+	fn drop(&mut self) {
+		limit::freed(mem::size_of::<Env>() + self.vars.borrow().len() * VAR_SIZE);
+	}
+}
+
+// Empties `root` and the environments of the procedures reachable from it
+// that were made under it. A procedure refers to the environment it was
+// defined in, so an environment holding one of its own procedures is never
+// freed otherwise. Procedures that are still held elsewhere no longer find
+// their variables afterwards.
+fn release(root: &Rc<Env>) {
+	let under_root = |env: &Env| {
+		let mut env = Some(env);
+		while let Some(e) = env {
+			if std::ptr::eq(e, &**root) {
+				return true;
+			}
+			env = e.parent.as_deref();
+		}
+		false
+	};
+	let (mut seen_envs, mut seen_pairs) = (HashSet::new(), HashSet::new());
+	let (mut envs, mut values, mut emptied) = (vec![root.clone()], Vec::new(), Vec::new());
+	while let Some(env) = envs.pop() {
+		if !under_root(&env) || !seen_envs.insert(Rc::as_ptr(&env)) {
+			continue;
+		}
+		let vars = mem::take(&mut *env.vars.borrow_mut());
+		limit::freed(vars.len() * VAR_SIZE);
+		values.extend(vars.values().cloned());
+		// Dropped at the end, once nothing is reached through them.
+		emptied.push(vars);
+		while let Some(v) = values.pop() {
+			match v {
+				Value::Pair(ref pair) if seen_pairs.insert(Rc::as_ptr(pair)) => {
+					values.push(pair.0.clone());
+					values.push(pair.1.clone());
+				}
+				Value::Procedure(ref c) => envs.push(c.env.clone()),
+				_ => ()
+			}
+		}
+	}
+}
+
+// Walks the source a character at a time, keeping track of the position
+// of the next character.
+struct Scanner<'a> {
+	chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+	pos: Pos
+}
+
+impl<'a> Scanner<'a> {
+	fn peek(&mut self) -> Option<char> {
+		self.chars.peek().map(|&(_, c)| c)
+	}
+
+	fn bump(&mut self) -> Option<char> {
+		let (i, c) = self.chars.next()?;
+		self.pos.offset = i + c.len_utf8();
+		if c == '\n' {
+			self.pos.line += 1;
+			self.pos.col = 1;
+		} else {
+			self.pos.col += 1;
+		}
+		Some(c)
+	}
+}
+
+fn is_delimiter(c: char) -> bool {
+	c.is_whitespace() || "()'\";".contains(c)
+}
+
+fn invalid(w: &str, pos: Pos) -> CrustError {
+	CrustError::new(ErrorKind::InvalidToken(w.to_string()), pos)
+}
+
+// Lexes a string literal, starting at its opening quote.
+fn lex_string(sc: &mut Scanner) -> Result<Token<'static>, CrustError> {
+	let start = sc.pos;
+	sc.bump();
+	let mut s = String::new();
+	loop {
+		let pos = sc.pos;
+		match sc.bump() {
+			Some('"') => return Ok(Token::Str(s)),
+			Some('\\') => match sc.bump() {
+				Some('"') => s.push('"'),
+				Some('\\') => s.push('\\'),
+				Some('n') => s.push('\n'),
+				Some('t') => s.push('\t'),
+				Some('r') => s.push('\r'),
+				Some(c) => return Err(invalid(&format!("\\{}", c), pos)),
+				None => return Err(CrustError::new(ErrorKind::UnterminatedString, start))
+			},
+			Some(c) => s.push(c),
+			None => return Err(CrustError::new(ErrorKind::UnterminatedString, start))
+		}
+	}
+}
+
+// Words starting with a digit, or a sign or `.` followed by a digit, must
+// be numbers. Everything else, including `+`, `-` and `...`, is a symbol.
+fn is_numeric(w: &str) -> bool {
+	let w = w.strip_prefix(['+', '-']).unwrap_or(w);
+	let w = w.strip_prefix('.').unwrap_or(w);
+	w.starts_with(|c: char| c.is_ascii_digit())
+}
+
+// The number written as `w`, or None if `w` is not a number. Integers are
+// in `radix` unless `w` starts with one of the prefixes `#b`, `#o`, `#d` or
+// `#x`; floats are always decimal. This is the only place that decides
+// what a number looks like, for both the lexer and `string->number`.
+fn parse_number(w: &str, radix: u32) -> Option<Token<'static>> {
+	match w {
+		"+inf.0" => return Some(Token::Float(f64::INFINITY)),
+		"-inf.0" => return Some(Token::Float(f64::NEG_INFINITY)),
+		"+nan.0" | "-nan.0" => return Some(Token::Float(f64::NAN)),
+		_ => ()
+	}
+	let (radix, w) = match w.get(..2) {
+		Some("#b" | "#B") => (2, &w[2..]),
+		Some("#o" | "#O") => (8, &w[2..]),
+		Some("#d" | "#D") => (10, &w[2..]),
+		Some("#x" | "#X") => (16, &w[2..]),
+		_ => (radix, w)
+	};
+	let digits = w.strip_prefix(['+', '-']).unwrap_or(w);
+	if !digits.is_empty() && digits.chars().all(|c| c.is_digit(radix)) {
+		// An integer that does not fit is not a number rather than a
+		// silently rounded float.
+		i64::from_str_radix(w, radix).ok().map(Token::Integer)
+	} else if radix == 10 && is_numeric(w) {
+		w.parse().ok().map(Token::Float)
+	} else {
+		None
+	}
+}
+
+#[test]
+fn test_parse_number() {
+	let cases = [
+		("0", Some(Token::Integer(0))),
+		("-17", Some(Token::Integer(-17))),
+		("+17", Some(Token::Integer(17))),
+		("007", Some(Token::Integer(7))),
+		("9223372036854775807", Some(Token::Integer(i64::MAX))),
+		("-9223372036854775808", Some(Token::Integer(i64::MIN))),
+		("9223372036854775808", None),
+		("#xff", Some(Token::Integer(255))),
+		("#x-FF", Some(Token::Integer(-255))),
+		("#b101", Some(Token::Integer(5))),
+		("#o17", Some(Token::Integer(15))),
+		("#d19", Some(Token::Integer(19))),
+		("#d1.5", Some(Token::Float(1.5))),
+		("#b102", None),
+		("#x1.5", None),
+		("#x", None),
+		("#x+", None),
+		("#X1", Some(Token::Integer(1))),
+		("1.5", Some(Token::Float(1.5))),
+		("-.5", Some(Token::Float(-0.5))),
+		("1.", Some(Token::Float(1.0))),
+		("1e3", Some(Token::Float(1000.0))),
+		("1E-3", Some(Token::Float(0.001))),
+		("+inf.0", Some(Token::Float(f64::INFINITY))),
+		("-inf.0", Some(Token::Float(f64::NEG_INFINITY))),
+		("inf", None),
+		("nan", None),
+		("infinity", None),
+		("1e", None),
+		("1.2.3", None),
+		("1_000", None),
+		("١", None),
+		("", None),
+		("+", None),
+		("-", None),
+		(".", None),
+		("...", None),
+		("1+", None),
+	];
+	for (w, expected) in cases {
+		assert_eq!(expected, parse_number(w, 10), "{}", w);
+	}
+	assert!(matches!(parse_number("+nan.0", 10), Some(Token::Float(x)) if x.is_nan()));
+	assert_eq!(Some(Token::Integer(255)), parse_number("ff", 16));
+	assert_eq!(Some(Token::Integer(5)), parse_number("#b101", 16));
+	assert_eq!(None, parse_number("1.5", 16));
+}
+
+// Lexes a word delimited by whitespace, parens, quotes or a comment.
+fn atom(w: &str, pos: Pos) -> Result<Token<'_>, CrustError> {
+	match w {
+		"#t" | "#true" => Ok(Token::Boolean(true)),
+		"#f" | "#false" => Ok(Token::Boolean(false)),
+		_ => match parse_number(w, 10) {
+			Some(n) => Ok(n),
+			// Words that look like numbers are errors rather than symbols
+			// when they are not, and `#` starts no symbol.
+			None if w.starts_with('#') || is_numeric(w) => Err(invalid(w, pos)),
+			None => Ok(Token::Symbol(w))
+		}
+	}
+}
+
+fn lex(s: &str) -> Result<Vec<(Token<'_>, Span)>, CrustError> {
+	let mut sc = Scanner { chars: s.char_indices().peekable(), pos: Pos { line: 1, col: 1, offset: 0 } };
+	let mut tokens = Vec::new();
+	while let Some(c) = sc.peek() {
+		let start = sc.pos;
+		let token = match c {
+			_ if c.is_whitespace() => {
+				sc.bump();
+				continue;
+			}
+			';' => {
+				while sc.bump().is_some_and(|c| c != '\n') {}
+				continue;
+			}
+			'(' => { sc.bump(); Token::LeftParen }
+			')' => { sc.bump(); Token::RightParen }
+			'\'' => { sc.bump(); Token::Quote }
+			'"' => lex_string(&mut sc)?,
+			_ => {
+				while sc.peek().is_some_and(|c| !is_delimiter(c)) {
+					sc.bump();
+				}
+				atom(&s[start.offset..sc.pos.offset], start)?
+			}
+		};
+		tokens.push((token, Span { start, end: sc.pos }));
+	}
+	Ok(tokens)
+}
+
+#[test]
+fn test_lex() {
+	let tokens: Vec<Token> = lex("'(a . \"b\\\"c\") ; comment\n#t #false -1 +2.5 1e3 .5 - ...").unwrap()
+		.into_iter().map(|(t, _)| t).collect();
+	assert_eq!(vec![Token::Quote, Token::LeftParen, Token::Symbol("a"), Token::Symbol("."),
+	                Token::Str("b\"c".to_string()), Token::RightParen, Token::Boolean(true),
+	                Token::Boolean(false), Token::Integer(-1), Token::Float(2.5), Token::Float(1000.0),
+	                Token::Float(0.5), Token::Symbol("-"), Token::Symbol("...")],
+	           tokens);
+	let error = |src| lex(src).unwrap_err().to_string();
+	assert_eq!("1:4: unterminated string", error("(f \"ab)"));
+	assert_eq!("1:3: invalid token '\\q'", error("\"a\\q\""));
+	assert_eq!("1:1: invalid token '99999999999999999999'", error("99999999999999999999"));
+	assert_eq!("1:2: invalid token '1+'", error("(1+ 2)"));
+	assert_eq!("1:1: invalid token '#x'", error("#x"));
+}
+
+#[test]
+fn test_lex_positions() {
+	let tokens = lex("(+ 1\n  (λ x))").unwrap();
+	let positions: Vec<(usize, usize, usize)> = tokens.iter()
+		.map(|&(_, s)| (s.start.line, s.start.col, s.start.offset))
+		.collect();
+	assert_eq!(vec![(1, 1, 0), (1, 2, 1), (1, 4, 3), (2, 3, 7), (2, 4, 8), (2, 6, 11), (2, 7, 12), (2, 8, 13)],
+	           positions);
+	let (_, lambda) = tokens[4];
+	assert_eq!((2, 5, 10), (lambda.end.line, lambda.end.col, lambda.end.offset));
+}
+
+#[test]
+fn test_lex_large_input() {
+	// A few megabytes of generated code, to catch the lexer going quadratic
+	// or positions drifting far into a file.
+	let mut src = String::new();
+	let lines = 50_000;
+	for i in 0..lines {
+		let _ = writeln!(src, "(define (f{} x) (if (< x {}) \"λ\\n{}\" (+ x 1.5 #t 'sym))) ; comment", i, i, i);
+	}
+	assert!(src.len() > 3_000_000);
+	let tokens = lex(&src).unwrap();
+	assert_eq!(24 * lines, tokens.len());
+	let (ref last, span) = tokens[tokens.len() - 1];
+	assert_eq!(Token::RightParen, *last);
+	assert_eq!((lines, 65), (span.start.line, span.start.col));
+	assert_eq!(src.len() - " ; comment\n".len(), span.end.offset);
+}
+
+// Lower-cases the names of the symbols in `tokens`, keeping their spans in
+// the original source. The folded names are stored in `names`.
+fn fold_case<'a>(tokens: Vec<(Token<'a>, Span)>, names: &'a mut Vec<String>) -> Vec<(Token<'a>, Span)> {
+	names.extend(tokens.iter().filter_map(|t| match t.0 {
+		Token::Symbol(s) => Some(s.to_lowercase()),
+		_ => None
+	}));
+	let mut names = names.iter();
+	tokens.into_iter().map(|(token, span)| match token {
+		Token::Symbol(_) => (Token::Symbol(names.next().unwrap()), span),
+		token => (token, span)
+	}).collect()
+}
+
+/// Decodes a program read as bytes, failing at the first byte that is not
+/// valid UTF-8 or, if `lossy` is set, replacing invalid sequences with
+/// U+FFFD.
+pub fn decode(bytes: &[u8], lossy: bool) -> Result<Cow<'_, str>, CrustError> {
+	match std::str::from_utf8(bytes) {
+		Ok(s) => Ok(Cow::Borrowed(s)),
+		Err(_) if lossy => Ok(String::from_utf8_lossy(bytes)),
+		Err(e) => {
+			let offset = e.valid_up_to();
+			let valid = std::str::from_utf8(&bytes[..offset]).expect("the prefix is valid");
+			let line = valid.matches('\n').count() + 1;
+			let col = valid.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+			Err(CrustError::new(ErrorKind::InvalidUtf8(offset), Pos { line, col, offset }))
+		}
+	}
+}
+
+#[test]
+fn test_decode() {
+	assert_eq!("(+ 1 2)", decode(b"(+ 1 2)", false).unwrap());
+	let bytes = b"(display \"\xce\xbb\")\n\"\xff\"";
+	assert_eq!("2:2: invalid UTF-8 at byte offset 16", decode(bytes, false).unwrap_err().to_string());
+	assert_eq!("(display \"\u{3bb}\")\n\"\u{fffd}\"", decode(bytes, true).unwrap());
+	// A sequence cut short by the end of the input.
+	assert_eq!(Some(Pos { line: 1, col: 3, offset: 2 }), decode(b"'a\xce", false).unwrap_err().pos);
+}
+
+type Tokens<'t, 'a> = &'t [(Token<'a>, Span)];
+
+// Returns the token at `i`; running out of tokens means that the list
+// opened at `open` is never closed.
+fn token_at<'t, 'a>(tokens: Tokens<'t, 'a>, i: usize, open: Span) -> Result<&'t (Token<'a>, Span), CrustError> {
+	tokens.get(i).ok_or_else(|| CrustError::new(ErrorKind::UnbalancedParens, open.start))
+}
+
+fn unexpected(token: &(Token, Span)) -> CrustError {
+	CrustError::new(ErrorKind::UnexpectedToken(token.0.to_string()), token.1.start)
+}
+
+fn parse_ident(token: &(Token, Span)) -> Result<Ident, CrustError> {
+	match token.0 {
+		Token::Symbol(name) => Ok(Ident { name: Rc::from(name), span: token.1 }),
+		_	                => Err(unexpected(token))
+	}
+}
+
+/// How deeply lists and quotes may nest in source code. Deeper source
+/// fails to parse with [`ErrorKind::BadSyntax`] rather than overflowing the
+/// stack. Source nested this deeply needs a thread with a stack of
+/// [`STACK_SIZE`] bytes to be parsed and evaluated.
+pub const MAX_NESTING: usize = 1000;
+
+thread_local! {
+	// How deeply the expression or datum being parsed on this thread is
+	// nested.
+	static NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+// Leaves the expression or datum entered by `nest` when dropped.
+struct Nested;
+
+impl Drop for Nested {
+	fn drop(&mut self) {
+		NESTING.with(|n| n.set(n.get() - 1));
+	}
+}
+
+// Enters the expression or datum at `span`, failing if that would nest it
+// deeper than `MAX_NESTING`.
+fn nest(span: Span) -> Result<Nested, CrustError> {
+	let nesting = NESTING.with(Cell::get);
+	if nesting == MAX_NESTING {
+		return Err(bad_syntax("forms nested too deeply", span));
+	}
+	NESTING.with(|n| n.set(nesting + 1));
+	Ok(Nested)
+}
+
+// Parses expressions up to and including the closing paren of the list
+// opened at `open`.
+fn parse_list(tokens: Tokens, open: Span) -> Result<(usize, Vec<Node>), CrustError> {
+	let mut nodes = Vec::new();
+	let mut i = 0;
+	while token_at(tokens, i, open)?.0 != Token::RightParen {
+		let (n, node) = parse_exp(&tokens[i..])?;
+		nodes.push(node);
+		i += n;
+	}
+	Ok((i + 1, nodes))
+}
+
+// Parses the `param ...) body ...)` part of a lambda or procedure
+// definition, starting after the paren opening the parameter list at
+// `params_open`. The whole form was opened at `open`.
+fn parse_fun(name: Option<Rc<str>>, tokens: Tokens, params_open: Span, open: Span)
+                 -> Result<(usize, Fun), CrustError> {
+	let mut i = 0;
+	let mut params = Vec::new();
+	while token_at(tokens, i, params_open)?.0 != Token::RightParen {
+		params.push(parse_ident(&tokens[i])?);
+		i += 1;
+	}
+	let (n, body) = parse_list(&tokens[i + 1..], open)?;
+	if body.is_empty() {
+		return Err(unexpected(&tokens[i + n]));
+	}
+	Ok((i + 1 + n, Fun { name, params, body }))
+}
+
+fn bad_syntax(msg: &str, span: Span) -> CrustError {
+	CrustError::new(ErrorKind::BadSyntax(msg.to_string()), span.start)
+}
+
+// Fails unless the token at `i` closes the form opened at `open`.
+fn expect_close(tokens: Tokens, i: usize, open: Span) -> Result<(), CrustError> {
+	if token_at(tokens, i, open)?.0 != Token::RightParen {
+		return Err(unexpected(&tokens[i]));
+	}
+	Ok(())
+}
+
+// The node for a token that is neither a paren nor a quote.
+fn literal(token: &Token) -> NodeKind {
+	match *token {
+		Token::Integer(n) => NodeKind::Integer(n),
+		Token::Float(x) => NodeKind::Float(x),
+		Token::Boolean(b) => NodeKind::Boolean(b),
+		Token::Str(ref s) => NodeKind::Str(Rc::from(s.as_str())),
+		Token::Symbol(s) => NodeKind::Symbol(Rc::from(s)),
+		Token::LeftParen | Token::RightParen | Token::Quote => unreachable!("{} is not a literal", token)
+	}
+}
+
+// Parses the datum following `'` or `quote`. Lists are data here and not
+// forms, so `'(if x)` is just a list starting with the symbol `if`.
+fn parse_datum(tokens: Tokens) -> Result<(usize, Node), CrustError> {
+	let (ref token, span) = tokens[0];
+	let _nested = nest(span)?;
+	let (n, kind) = match *token {
+		Token::LeftParen => {
+			let mut items = Vec::new();
+			let mut i = 1;
+			while token_at(tokens, i, span)?.0 != Token::RightParen {
+				let (n, item) = parse_datum(&tokens[i..])?;
+				items.push(item);
+				i += n;
+			}
+			(i + 1, NodeKind::List(items))
+		}
+		Token::Quote => {
+			// 'x within a datum is the list (quote x).
+			let (n, datum) = parse_quoted(tokens)?;
+			let quote = Node { kind: NodeKind::Symbol(Rc::from("quote")), span };
+			(n, NodeKind::List(vec![quote, datum]))
+		}
+		Token::RightParen => return Err(CrustError::new(ErrorKind::UnbalancedParens, span.start)),
+		ref t => (1, literal(t))
+	};
+	let end = tokens[n - 1].1.end;
+	Ok((n, Node { kind, span: Span { start: span.start, end } }))
+}
+
+// Parses the datum following the `'` at the start of `tokens`.
+fn parse_quoted(tokens: Tokens) -> Result<(usize, Node), CrustError> {
+	if tokens.len() < 2 {
+		return Err(unexpected(&tokens[0]));
+	}
+	let (n, datum) = parse_datum(&tokens[1..])?;
+	Ok((n + 1, datum))
+}
+
+// Parses the clauses of a `cond` up to and including its closing paren.
+fn parse_clauses(tokens: Tokens, open: Span) -> Result<(usize, Vec<Clause>), CrustError> {
+	let mut clauses: Vec<Clause> = Vec::new();
+	let mut i = 0;
+	loop {
+		let (ref token, span) = *token_at(tokens, i, open)?;
+		match *token {
+			Token::RightParen => return Ok((i + 1, clauses)),
+			Token::LeftParen => {
+				if clauses.last().is_some_and(|c| c.test.is_none()) {
+					return Err(bad_syntax("else must be the last cond clause", span));
+				}
+				let (n, clause) = if token_at(tokens, i + 1, span)?.0 == Token::Symbol("else") {
+					let (n, body) = parse_list(&tokens[i + 2..], span)?;
+					if body.is_empty() {
+						return Err(bad_syntax("empty else clause", span));
+					}
+					(n + 2, Clause { test: None, body })
+				} else {
+					let (n, mut body) = parse_list(&tokens[i + 1..], span)?;
+					if body.is_empty() {
+						return Err(bad_syntax("empty cond clause", span));
+					}
+					let test = body.remove(0);
+					(n + 1, Clause { test: Some(test), body })
+				};
+				clauses.push(clause);
+				i += n;
+			}
+			_ => return Err(unexpected(&tokens[i]))
+		}
+	}
+}
+
+fn parse_exp(tokens: Tokens) -> Result<(usize, Node), CrustError> {
+	let (ref token, span) = tokens[0];
+	let _nested = nest(span)?;
+	let (n, kind) = match *token {
+		Token::LeftParen => {
+			match token_at(tokens, 1, span)?.0 {
+				Token::Symbol("lambda") => {
+					if token_at(tokens, 2, span)?.0 != Token::LeftParen {
+						return Err(unexpected(&tokens[2]));
+					}
+					let (n, fun) = parse_fun(None, &tokens[3..], tokens[2].1, span)?;
+					(n + 3, NodeKind::Lambda(Rc::new(fun)))
+				}
+				Token::Symbol("define") if token_at(tokens, 2, span)?.0 == Token::LeftParen => {
+					// (define (name param ...) body ...) is short for
+					// (define name (lambda (param ...) body ...)), where the
+					// lambda covers the whole form.
+					let name = parse_ident(token_at(tokens, 3, span)?)?;
+					let (n, fun) = parse_fun(Some(name.name.clone()), &tokens[4..], tokens[2].1, span)?;
+					let end = tokens[n + 3].1.end;
+					let lambda = Node { kind: NodeKind::Lambda(Rc::new(fun)), span: Span { start: span.start, end } };
+					(n + 4, NodeKind::Define(name, Box::new(lambda)))
+				}
+				Token::Symbol("define") => {
+					if tokens[2].0 == Token::RightParen || token_at(tokens, 3, span)?.0 == Token::RightParen {
+						return Err(bad_syntax("define needs a name and a value", span));
+					}
+					let name = parse_ident(&tokens[2])?;
+					let (n, value) = parse_exp(&tokens[3..])?;
+					expect_close(tokens, 3 + n, span)?;
+					(n + 4, NodeKind::Define(name, Box::new(value)))
+				}
+				Token::Symbol("quote") => {
+					token_at(tokens, 2, span)?;
+					let (n, datum) = parse_datum(&tokens[2..])?;
+					expect_close(tokens, 2 + n, span)?;
+					(n + 3, NodeKind::Quote(Box::new(datum)))
+				}
+				Token::Symbol("if") => {
+					let (n, mut nodes) = parse_list(&tokens[2..], span)?;
+					if nodes.len() < 2 || nodes.len() > 3 {
+						return Err(bad_syntax("if expects a test, a consequent and an optional alternative", span));
+					}
+					let alternative = if nodes.len() == 3 { nodes.pop().map(Box::new) } else { None };
+					let consequent = nodes.pop().unwrap();
+					let test = nodes.pop().unwrap();
+					(n + 2, NodeKind::If(Box::new(test), Box::new(consequent), alternative))
+				}
+				Token::Symbol("cond") => {
+					let (n, clauses) = parse_clauses(&tokens[2..], span)?;
+					(n + 2, NodeKind::Cond(clauses))
+				}
+				Token::Symbol("and") => {
+					let (n, nodes) = parse_list(&tokens[2..], span)?;
+					(n + 2, NodeKind::And(nodes))
+				}
+				Token::Symbol("or") => {
+					let (n, nodes) = parse_list(&tokens[2..], span)?;
+					(n + 2, NodeKind::Or(nodes))
+				}
+				Token::RightParen => return Err(unexpected(&tokens[1])),
+				_ => {
+					let (n, mut nodes) = parse_list(&tokens[1..], span)?;
+					let f = nodes.remove(0);
+					(n + 1, NodeKind::Application(Box::new(f), nodes))
+				}
+			}
+		}
+		Token::Quote => {
+			let (n, datum) = parse_quoted(tokens)?;
+			(n, NodeKind::Quote(Box::new(datum)))
+		}
+		Token::RightParen => return Err(CrustError::new(ErrorKind::UnbalancedParens, span.start)),
+		ref t => (1, literal(t))
+	};
+	let end = tokens[n - 1].1.end;
+	Ok((n, Node { kind, span: Span { start: span.start, end } }))
+}
+
+fn parse(tokens: Tokens) -> Result<Vec<Node>, CrustError> {
+	let mut n = 0;
+	let mut v = Vec::new();
+	while n < tokens.len() {
+		let (nn, root) = parse_exp(&tokens[n..])?;
+		v.push(root);
+		n += nn;
+	}
+	Ok(v)
+}
+
+#[test]
+fn test_wide_forms() {
+	// Generated code can have forms with a huge number of arguments, which
+	// must neither take quadratic time nor overflow the stack when the
+	// resulting lists are dropped.
+	let n = 100_000;
+	let ones = vec!["1"; n].join(" ");
+	assert_eq!(n.to_string(), eval_str(&format!("(+ {})", ones)).unwrap());
+	assert_eq!(n.to_string(), eval_str(&format!("(apply + (list {}))", ones)).unwrap());
+	assert_eq!(n.to_string(), eval_str(&format!("(define xs '({})) (apply + xs)", ones)).unwrap());
+}
+
+#[test]
+fn test_deep_forms() {
+	let run = || {
+		let nested = |n| format!("{}0{}", "(+ 1 ".repeat(n - 1), ")".repeat(n - 1));
+		assert_eq!((MAX_NESTING - 1).to_string(), eval_str(&nested(MAX_NESTING)).unwrap());
+		let list = format!("{}{}", "(".repeat(MAX_NESTING - 1), ")".repeat(MAX_NESTING - 1));
+		assert_eq!(list, eval_str(&format!("'{}", list)).unwrap());
+		assert!(emit_ast(&nested(MAX_NESTING)).is_ok());
+		// The `+` of the innermost list is one too deep.
+		assert_eq!(format!("1:{}: bad syntax: forms nested too deeply", 5 * (MAX_NESTING - 1) + 2),
+		           eval_str(&nested(MAX_NESTING + 1)).unwrap_err().to_string());
+		let parens = "(".repeat(1_000_000);
+		assert_eq!(format!("1:{}: bad syntax: forms nested too deeply", MAX_NESTING + 1),
+		           eval_str(&parens).unwrap_err().to_string());
+		assert!(emit_ast(&parens).is_err());
+		assert!(eval_str(&"'".repeat(1_000_000)).is_err());
+		// The failed parses left nothing behind.
+		assert!(eval_str(&nested(MAX_NESTING)).is_ok());
+	};
+	std::thread::Builder::new().stack_size(STACK_SIZE).spawn(run).unwrap().join().unwrap();
+}
+
+// The version of the `--emit=ast` format described in doc/ast-format.md.
+// Bump it whenever a node type or field changes or disappears.
+const AST_FORMAT_VERSION: u32 = 1;
+
+fn write_json_str(out: &mut String, s: &str) {
+	out.push('"');
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+			c => out.push(c)
+		}
+	}
+	out.push('"');
+}
+
+fn write_json_pos(out: &mut String, pos: Pos) {
+	let _ = write!(out, "{{\"line\":{},\"col\":{},\"offset\":{}}}", pos.line, pos.col, pos.offset);
+}
+
+fn write_json_span(out: &mut String, span: Span) {
+	out.push_str("\"span\":{\"start\":");
+	write_json_pos(out, span.start);
+	out.push_str(",\"end\":");
+	write_json_pos(out, span.end);
+	out.push('}');
+}
+
+fn write_json_ident(out: &mut String, ident: &Ident) {
+	out.push_str("{\"name\":");
+	write_json_str(out, &ident.name);
+	out.push(',');
+	write_json_span(out, ident.span);
+	out.push('}');
+}
+
+fn write_json_nodes(out: &mut String, nodes: &[Node]) {
+	out.push('[');
+	for (i, node) in nodes.iter().enumerate() {
+		if i > 0 {
+			out.push(',');
+		}
+		write_json_node(out, node);
+	}
+	out.push(']');
+}
+
+fn write_json_node(out: &mut String, node: &Node) {
+	out.push_str("{\"type\":");
+	match node.kind {
+		NodeKind::Symbol(ref name) => {
+			out.push_str("\"symbol\",\"name\":");
+			write_json_str(out, name);
+		}
+		NodeKind::Integer(n) => {
+			let _ = write!(out, "\"number\",\"value\":{}", n);
+		}
+		// JSON has no infinities or NaN, they are written as null.
+		NodeKind::Float(x) if x.is_finite() => {
+			let _ = write!(out, "\"number\",\"value\":{:?}", x);
+		}
+		NodeKind::Float(_) => out.push_str("\"number\",\"value\":null"),
+		NodeKind::Boolean(b) => {
+			let _ = write!(out, "\"boolean\",\"value\":{}", b);
+		}
+		NodeKind::Str(ref s) => {
+			out.push_str("\"string\",\"value\":");
+			write_json_str(out, s);
+		}
+		NodeKind::Quote(ref datum) => {
+			out.push_str("\"quote\",\"datum\":");
+			write_json_node(out, datum);
+		}
+		NodeKind::List(ref items) => {
+			out.push_str("\"list\",\"items\":");
+			write_json_nodes(out, items);
+		}
+		NodeKind::If(ref test, ref consequent, ref alternative) => {
+			out.push_str("\"if\",\"test\":");
+			write_json_node(out, test);
+			out.push_str(",\"then\":");
+			write_json_node(out, consequent);
+			out.push_str(",\"else\":");
+			match *alternative {
+				Some(ref alternative) => write_json_node(out, alternative),
+				None => out.push_str("null")
+			}
+		}
+		NodeKind::Cond(ref clauses) => {
+			out.push_str("\"cond\",\"clauses\":[");
+			for (i, clause) in clauses.iter().enumerate() {
+				if i > 0 {
+					out.push(',');
+				}
+				out.push_str("{\"test\":");
+				match clause.test {
+					Some(ref test) => write_json_node(out, test),
+					None => out.push_str("null")
+				}
+				out.push_str(",\"body\":");
+				write_json_nodes(out, &clause.body);
+				out.push('}');
+			}
+			out.push(']');
+		}
+		NodeKind::And(ref operands) => {
+			out.push_str("\"and\",\"operands\":");
+			write_json_nodes(out, operands);
+		}
+		NodeKind::Or(ref operands) => {
+			out.push_str("\"or\",\"operands\":");
+			write_json_nodes(out, operands);
+		}
+		NodeKind::Define(ref name, ref value) => {
+			out.push_str("\"define\",\"name\":");
+			write_json_ident(out, name);
+			out.push_str(",\"value\":");
+			write_json_node(out, value);
+		}
+		NodeKind::Lambda(ref fun) => {
+			out.push_str("\"lambda\",\"name\":");
+			match fun.name {
+				Some(ref name) => write_json_str(out, name),
+				None => out.push_str("null")
+			}
+			out.push_str(",\"params\":[");
+			for (i, param) in fun.params.iter().enumerate() {
+				if i > 0 {
+					out.push(',');
+				}
+				write_json_ident(out, param);
+			}
+			out.push_str("],\"body\":");
+			write_json_nodes(out, &fun.body);
+		}
+		NodeKind::Application(ref f, ref args) => {
+			out.push_str("\"application\",\"operator\":");
+			write_json_node(out, f);
+			out.push_str(",\"operands\":");
+			write_json_nodes(out, args);
+		}
+	}
+	out.push(',');
+	write_json_span(out, node.span);
+	out.push('}');
+}
+
+/// Parses `source` and renders it in the `--emit=ast` format described in
+/// doc/ast-format.md: a JSON object with the format version and the
+/// top-level nodes, one per line.
+pub fn emit_ast(source: &str) -> Result<String, CrustError> {
+	let tokens = lex(source)?;
+	let roots = parse(&tokens)?;
+	let mut out = String::new();
+	let _ = write!(out, "{{\"version\":{},\"nodes\":[", AST_FORMAT_VERSION);
+	for (i, root) in roots.iter().enumerate() {
+		out.push_str(if i > 0 { ",\n" } else { "\n" });
+		write_json_node(&mut out, root);
+	}
+	out.push_str("\n]}\n");
+	Ok(out)
+}
+
+/// Renders the outcome of an evaluation as the JSON object printed by
+/// `crust --output=json`: the printed value, or `null` if there is none,
+/// the error with its message and position, or `null` if there is none,
+/// and the fuel and time in microseconds from `usage`.
+pub fn emit_result(res: &Result<Value, CrustError>, usage: &Usage) -> String {
+	let mut out = String::from("{\"value\":");
+	match *res {
+		Ok(Value::Unspecified) | Err(_) => out.push_str("null"),
+		Ok(ref v) => write_json_str(&mut out, &v.to_string())
+	}
+	out.push_str(",\"error\":");
+	match *res {
+		Ok(_) => out.push_str("null"),
+		Err(ref e) => {
+			out.push_str("{\"message\":");
+			write_json_str(&mut out, &e.kind.to_string());
+			out.push_str(",\"pos\":");
+			match e.pos {
+				Some(pos) => write_json_pos(&mut out, pos),
+				None => out.push_str("null")
+			}
+			out.push('}');
+		}
+	}
+	let _ = writeln!(out, ",\"fuel\":{},\"time_us\":{}}}", usage.fuel, usage.wall_time.as_micros());
+	out
+}
+
+#[test]
+fn test_emit_ast() {
+	assert_eq!("{\"version\":1,\"nodes\":[\n\
+	            {\"type\":\"application\",\
+	             \"operator\":{\"type\":\"symbol\",\"name\":\"f\",\
+	              \"span\":{\"start\":{\"line\":1,\"col\":2,\"offset\":1},\"end\":{\"line\":1,\"col\":3,\"offset\":2}}},\
+	             \"operands\":[{\"type\":\"string\",\"value\":\"x\\\"\\\\\",\
+	              \"span\":{\"start\":{\"line\":1,\"col\":4,\"offset\":3},\"end\":{\"line\":1,\"col\":11,\"offset\":10}}}],\
+	             \"span\":{\"start\":{\"line\":1,\"col\":1,\"offset\":0},\"end\":{\"line\":2,\"col\":2,\"offset\":12}}}\n\
+	            ]}\n",
+	           emit_ast("(f \"x\\\"\\\\\"\n)").unwrap());
+}
+
+#[test]
+fn test_emit_result() {
+	let usage = Usage { fuel: 3, peak_heap: 0, wall_time: std::time::Duration::from_micros(12) };
+	assert_eq!("{\"value\":\"(\\\"a\\\" b)\",\"error\":null,\"fuel\":3,\"time_us\":12}\n",
+	           emit_result(&Interpreter::new().eval_str("(list \"a\" 'b)"), &usage));
+	assert_eq!("{\"value\":null,\"error\":null,\"fuel\":3,\"time_us\":12}\n",
+	           emit_result(&Ok(Value::Unspecified), &usage));
+	assert_eq!("{\"value\":null,\"error\":{\"message\":\"unbound symbol 'x'\",\
+	            \"pos\":{\"line\":2,\"col\":1,\"offset\":2}},\"fuel\":3,\"time_us\":12}\n",
+	           emit_result(&Interpreter::new().eval_str("1\nx"), &usage));
+}
+
+fn wrong_type(expected: &'static str, got: &Value) -> CrustError {
+	ErrorKind::WrongType { expected, got: got.to_string() }.into()
+}
+
+fn check_arity(name: &str, args: &[Value], min: usize, max: Option<usize>) -> Result<(), CrustError> {
+	if args.len() < min || max.is_some_and(|max| args.len() > max) {
+		return Err(ErrorKind::ArityMismatch { name: name.to_string(), min, max, got: args.len() }.into());
+	}
+	Ok(())
+}
+
+fn float(v: &Value) -> Result<f64, CrustError> {
+	match *v {
+		Value::Integer(n) => Ok(n as f64),
+		Value::Float(x) => Ok(x),
+		ref v => Err(wrong_type("a number", v))
+	}
+}
+
+// Applies an arithmetic operation to two numbers. Integers stay integers,
+// turning overflow into an error, and anything involving a float is done
+// in floating point.
+fn arith(a: &Value, b: &Value, int_op: fn(i64, i64) -> Option<i64>, float_op: fn(f64, f64) -> f64)
+             -> Result<Value, CrustError> {
+	match (a, b) {
+		(&Value::Integer(x), &Value::Integer(y)) => int_op(x, y).map(Value::Integer).ok_or(ErrorKind::Overflow.into()),
+		_ => Ok(Value::Float(float_op(float(a)?, float(b)?)))
+	}
+}
+
+fn add(a: &Value, b: &Value) -> Result<Value, CrustError> {
+	arith(a, b, i64::checked_add, |x, y| x + y)
+}
+
+fn sub(a: &Value, b: &Value) -> Result<Value, CrustError> {
+	arith(a, b, i64::checked_sub, |x, y| x - y)
+}
+
+fn mul(a: &Value, b: &Value) -> Result<Value, CrustError> {
+	arith(a, b, i64::checked_mul, |x, y| x * y)
+}
+
+// Integer division truncates, dividing a float by zero gives an infinity.
+fn div(a: &Value, b: &Value) -> Result<Value, CrustError> {
+	if let (&Value::Integer(_), &Value::Integer(0)) = (a, b) {
+		return Err(ErrorKind::DivisionByZero.into());
+	}
+	arith(a, b, i64::checked_div, |x, y| x / y)
+}
+
+fn builtin_add(args: &[Value]) -> Result<Value, CrustError> {
+	args.iter().try_fold(Value::Integer(0), |acc, a| add(&acc, a))
+}
+
+fn builtin_mul(args: &[Value]) -> Result<Value, CrustError> {
+	args.iter().try_fold(Value::Integer(1), |acc, a| mul(&acc, a))
+}
+
+// `-` and `/` with a single argument negate and invert it, with more they
+// subtract or divide the rest of the arguments from the first.
+fn inverse_fold(name: &str, args: &[Value], identity: i64,
+                    op: fn(&Value, &Value) -> Result<Value, CrustError>) -> Result<Value, CrustError> {
+	check_arity(name, args, 1, None)?;
+	match args.split_first() {
+		Some((first, [])) => op(&Value::Integer(identity), first),
+		Some((first, rest)) => {
+			float(first)?;
+			rest.iter().try_fold(first.clone(), |acc, a| op(&acc, a))
+		}
+		None => unreachable!()
+	}
+}
+
+fn builtin_sub(args: &[Value]) -> Result<Value, CrustError> {
+	inverse_fold("-", args, 0, sub)
+}
+
+fn builtin_div(args: &[Value]) -> Result<Value, CrustError> {
+	inverse_fold("/", args, 1, div)
+}
+
+fn compare_numbers(a: &Value, b: &Value) -> Result<Option<Ordering>, CrustError> {
+	match (a, b) {
+		(&Value::Integer(x), &Value::Integer(y)) => Ok(Some(x.cmp(&y))),
+		_ => Ok(float(a)?.partial_cmp(&float(b)?))
+	}
+}
+
+// The numeric comparisons take any number of arguments and hold if `holds`
+// is true for each adjacent pair. NaN compares false with everything.
+fn compare(name: &str, args: &[Value], holds: fn(Option<Ordering>) -> bool) -> Result<Value, CrustError> {
+	check_arity(name, args, 1, None)?;
+	let mut res = true;
+	for pair in args.windows(2) {
+		res &= holds(compare_numbers(&pair[0], &pair[1])?);
+	}
+	float(&args[0])?;
+	Ok(Value::Boolean(res))
+}
+
+fn builtin_eq(args: &[Value]) -> Result<Value, CrustError> {
+	compare("=", args, |o| o == Some(Ordering::Equal))
+}
+
+fn builtin_lt(args: &[Value]) -> Result<Value, CrustError> {
+	compare("<", args, |o| o == Some(Ordering::Less))
+}
+
+fn builtin_gt(args: &[Value]) -> Result<Value, CrustError> {
+	compare(">", args, |o| o == Some(Ordering::Greater))
+}
+
+fn builtin_le(args: &[Value]) -> Result<Value, CrustError> {
+	compare("<=", args, |o| matches!(o, Some(Ordering::Less | Ordering::Equal)))
+}
+
+fn builtin_ge(args: &[Value]) -> Result<Value, CrustError> {
+	compare(">=", args, |o| matches!(o, Some(Ordering::Greater | Ordering::Equal)))
+}
+
+fn builtin_not(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("not", args, 1, Some(1))?;
+	Ok(Value::Boolean(!args[0].is_true()))
+}
+
+fn builtin_cons(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("cons", args, 2, Some(2))?;
+	Ok(Value::cons(args[0].clone(), args[1].clone()))
+}
+
+fn pair<'v>(name: &str, args: &'v [Value]) -> Result<&'v Pair, CrustError> {
+	check_arity(name, args, 1, Some(1))?;
+	match args[0] {
+		Value::Pair(ref pair) => Ok(pair),
+		ref v => Err(wrong_type("a pair", v))
+	}
+}
+
+fn builtin_car(args: &[Value]) -> Result<Value, CrustError> {
+	Ok(pair("car", args)?.0.clone())
+}
+
+fn builtin_cdr(args: &[Value]) -> Result<Value, CrustError> {
+	Ok(pair("cdr", args)?.1.clone())
+}
+
+fn builtin_list(args: &[Value]) -> Result<Value, CrustError> {
+	Ok(Value::list(args.to_vec()))
+}
+
+fn predicate(name: &str, args: &[Value], test: fn(&Value) -> bool) -> Result<Value, CrustError> {
+	check_arity(name, args, 1, Some(1))?;
+	Ok(Value::Boolean(test(&args[0])))
+}
+
+fn builtin_is_null(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("null?", args, |v| matches!(*v, Value::Nil))
+}
+
+fn builtin_is_pair(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("pair?", args, |v| matches!(*v, Value::Pair(_)))
+}
+
+fn builtin_is_number(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("number?", args, |v| matches!(*v, Value::Integer(_) | Value::Float(_)))
+}
+
+fn builtin_is_integer(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("integer?", args, |v| matches!(*v, Value::Integer(_)))
+}
+
+fn builtin_is_float(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("float?", args, |v| matches!(*v, Value::Float(_)))
+}
+
+fn builtin_is_boolean(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("boolean?", args, |v| matches!(*v, Value::Boolean(_)))
+}
+
+fn builtin_is_string(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("string?", args, |v| matches!(*v, Value::Str(_)))
+}
+
+fn builtin_is_symbol(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("symbol?", args, |v| matches!(*v, Value::Symbol(_)))
+}
+
+fn builtin_is_procedure(args: &[Value]) -> Result<Value, CrustError> {
+	predicate("procedure?", args, |v| matches!(*v, Value::Builtin(..) | Value::Procedure(_)))
+}
+
+// (string->number s [radix]) reads a number exactly like the lexer does,
+// returning #f if `s` is not one.
+fn builtin_string_to_number(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("string->number", args, 1, Some(2))?;
+	let radix = match args.get(1) {
+		None => 10,
+		Some(&Value::Integer(n @ (2 | 8 | 10 | 16))) => n as u32,
+		Some(v) => return Err(wrong_type("a radix of 2, 8, 10 or 16", v))
+	};
+	match args[0] {
+		Value::Str(ref s) => Ok(match parse_number(s, radix) {
+			Some(Token::Integer(n)) => Value::Integer(n),
+			Some(Token::Float(x)) => Value::Float(x),
+			_ => Value::Boolean(false)
+		}),
+		ref v => Err(wrong_type("a string", v))
+	}
+}
+
+fn builtin_value_to_dot(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("value->dot", args, 1, Some(1))?;
+	Ok(Value::Str(Rc::from(dot::value(&args[0]))))
+}
+
+// Identity: numbers, booleans and symbols are equal if they have the same
+// value, strings, pairs and procedures only if they are the same object.
+fn is_eq(a: &Value, b: &Value) -> bool {
+	match (a, b) {
+		(Value::Unspecified, Value::Unspecified) | (Value::Nil, Value::Nil) => true,
+		(Value::Boolean(x), Value::Boolean(y)) => x == y,
+		(Value::Integer(x), Value::Integer(y)) => x == y,
+		(Value::Float(x), Value::Float(y)) => x == y,
+		(Value::Symbol(x), Value::Symbol(y)) => x == y,
+		(Value::Str(x), Value::Str(y)) => Rc::ptr_eq(x, y),
+		(Value::Pair(x), Value::Pair(y)) => Rc::ptr_eq(x, y),
+		(Value::Builtin(x), Value::Builtin(y)) => Rc::ptr_eq(x, y),
+		(Value::Procedure(x), Value::Procedure(y)) => Rc::ptr_eq(x, y),
+		_ => false
+	}
+}
+
+// Structural equality: strings and lists are compared by content.
+fn is_equal<'v>(mut a: &'v Value, mut b: &'v Value) -> bool {
+	loop {
+		match (a, b) {
+			(Value::Str(x), Value::Str(y)) => return x == y,
+			(Value::Pair(x), Value::Pair(y)) => {
+				if !is_equal(&x.0, &y.0) {
+					return false;
+				}
+				a = &x.1;
+				b = &y.1;
+			}
+			_ => return is_eq(a, b)
+		}
+	}
+}
+
+fn builtin_eq_p(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("eq?", args, 2, Some(2))?;
+	Ok(Value::Boolean(is_eq(&args[0], &args[1])))
+}
+
+fn builtin_equal_p(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("equal?", args, 2, Some(2))?;
+	Ok(Value::Boolean(is_equal(&args[0], &args[1])))
+}
+
+type BuiltinFn = fn(&[Value]) -> Result<Value, CrustError>;
+
+fn builtin<F>(name: &str, fun: F) -> Value where F: Fn(&[Value]) -> Result<Value, CrustError> + 'static {
+	Value::Builtin(Rc::new(Builtin { name: Rc::from(name), fun: Box::new(fun), is_apply: false }))
+}
+
+// The builtins whose results depend on nothing but their arguments and
+// that have no effects, which are all that configuration files get.
+const PURE_BUILTINS: &[&str] = &[
+	"+", "-", "*", "/", "=", "<", ">", "<=", ">=", "not", "cons", "car", "cdr", "list", "null?", "pair?",
+	"number?", "integer?", "float?", "boolean?", "string?", "symbol?", "procedure?", "string->number",
+	"value->dot", "svg-canvas", "line", "circle", "eq?", "equal?", "apply", "url-parse", "url-encode",
+	"url-decode", "query-string", "crc32", "format-number", "format-date",
+];
+
+// A global environment with only the builtins in `PURE_BUILTINS`.
+fn pure_env() -> Rc<Env> {
+	let env = global_env();
+	env.vars.borrow_mut().retain(|name, _| PURE_BUILTINS.contains(&&**name));
+	env
+}
+
+fn global_env() -> Rc<Env> {
+	let env = Env::new(None);
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("+", builtin_add),
+		("-", builtin_sub),
+		("*", builtin_mul),
+		("/", builtin_div),
+		("=", builtin_eq),
+		("<", builtin_lt),
+		(">", builtin_gt),
+		("<=", builtin_le),
+		(">=", builtin_ge),
+		("not", builtin_not),
+		("cons", builtin_cons),
+		("car", builtin_car),
+		("cdr", builtin_cdr),
+		("list", builtin_list),
+		("null?", builtin_is_null),
+		("pair?", builtin_is_pair),
+		("number?", builtin_is_number),
+		("integer?", builtin_is_integer),
+		("float?", builtin_is_float),
+		("boolean?", builtin_is_boolean),
+		("string?", builtin_is_string),
+		("symbol?", builtin_is_symbol),
+		("procedure?", builtin_is_procedure),
+		("string->number", builtin_string_to_number),
+		("value->dot", builtin_value_to_dot),
+		("svg-canvas", svg::builtin_svg_canvas),
+		("line", svg::builtin_line),
+		("circle", svg::builtin_circle),
+		("save", svg::builtin_save),
+		("eq?", builtin_eq_p),
+		("equal?", builtin_equal_p),
+		("current-stack-depth", builtin_current_stack_depth),
+		("call-stack", builtin_call_stack),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+	let apply = Builtin { name: Rc::from("apply"), fun: Box::new(builtin_apply), is_apply: true };
+	env.define(Rc::from("apply"), Value::Builtin(Rc::new(apply)));
+	define_tracing(&env);
+	term::define(&env);
+	url::define(&env);
+	hash::define(&env);
+	cache::define(&env);
+	tar::define(&env);
+	watch::define(&env);
+	timer::define(&env);
+	signal::define(&env);
+	process::define(&env);
+	serial::define(&env);
+	#[cfg(feature = "tui")]
+	tui::define(&env);
+	#[cfg(feature = "intl")]
+	intl::define(&env);
+	#[cfg(feature = "mail")]
+	mail::define(&env);
+	#[cfg(feature = "compress")]
+	compress::define(&env);
+	#[cfg(feature = "net")]
+	net::define(&env);
+	#[cfg(feature = "websocket")]
+	websocket::define(&env);
+	env
+}
+
+thread_local! {
+	// The number of traced procedures being called, for indenting the trace.
+	static TRACE_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Wraps `f` in a procedure that prints its calls and what they return to
+// stderr, indented by how many traced calls are in progress.
+fn traced(name: Rc<str>, f: Value) -> Value {
+	let wrapper_name = name.clone();
+	builtin(&wrapper_name, move |args| {
+		let depth = TRACE_DEPTH.with(|d| d.get());
+		let indent = "| ".repeat(depth);
+		let mut call = format!("({}", name);
+		for a in args {
+			let _ = write!(call, " {}", a);
+		}
+		eprintln!("trace: {}{})", indent, call);
+		TRACE_DEPTH.with(|d| d.set(depth + 1));
+		let res = apply(f.clone(), args.to_vec(), None);
+		TRACE_DEPTH.with(|d| d.set(depth));
+		match res {
+			Ok(ref v) => eprintln!("trace: {}=> {}", indent, v),
+			Err(ref e) => eprintln!("trace: {}error: {}", indent, e.kind)
+		}
+		res
+	})
+}
+
+fn symbol_arg<'v>(name: &str, args: &'v [Value]) -> Result<&'v Rc<str>, CrustError> {
+	check_arity(name, args, 1, Some(1))?;
+	match args[0] {
+		Value::Symbol(ref s) => Ok(s),
+		ref v => Err(wrong_type("a symbol", v))
+	}
+}
+
+// A procedure replaced by a traced wrapper.
+struct Traced {
+	name: Rc<str>,
+	original: Value,
+	wrapper: Value
+}
+
+// Defines `trace`, `untrace` and `traced?`, which rebind global procedures
+// in `env` to traced wrappers and back. The wrappers replace the bindings,
+// so calls through the name, recursive ones included, are traced.
+fn define_tracing(env: &Rc<Env>) {
+	// The traced procedures, in the order they were traced.
+	let traced_names: Rc<RefCell<Vec<Traced>>> = Rc::new(RefCell::new(Vec::new()));
+
+	// The builtins live in `env`, so they hold it weakly to avoid a cycle.
+	let (global, names) = (Rc::downgrade(env), traced_names.clone());
+	env.define(Rc::from("trace"), builtin("trace", move |args| {
+		let name = symbol_arg("trace", args)?;
+		let env = global.upgrade().expect("the global environment outlives its builtins");
+		if names.borrow().iter().any(|t| t.name == *name) {
+			return Ok(Value::Unspecified);
+		}
+		let f = match env.lookup(name) {
+			Some(f @ Value::Builtin(_)) | Some(f @ Value::Procedure(_)) => f,
+			Some(v) => return Err(wrong_type("a procedure", &v)),
+			None => return Err(ErrorKind::UnboundSymbol(name.to_string()).into())
+		};
+		let wrapper = traced(name.clone(), f.clone());
+		names.borrow_mut().push(Traced { name: name.clone(), original: f, wrapper: wrapper.clone() });
+		env.define(name.clone(), wrapper);
+		Ok(Value::Unspecified)
+	}));
+
+	let (global, names) = (Rc::downgrade(env), traced_names.clone());
+	env.define(Rc::from("untrace"), builtin("untrace", move |args| {
+		let name = symbol_arg("untrace", args)?;
+		let env = global.upgrade().expect("the global environment outlives its builtins");
+		let mut names = names.borrow_mut();
+		if let Some(i) = names.iter().position(|t| t.name == *name) {
+			let t = names.remove(i);
+			// Leave the name alone if it has been redefined since.
+			if env.lookup(&t.name).is_some_and(|v| is_eq(&v, &t.wrapper)) {
+				env.define(t.name, t.original);
+			}
+		}
+		Ok(Value::Unspecified)
+	}));
+
+	env.define(Rc::from("traced?"), builtin("traced?", move |args| {
+		check_arity("traced?", args, 0, Some(0))?;
+		Ok(Value::list(traced_names.borrow().iter().map(|t| Value::Symbol(t.name.clone())).collect()))
+	}));
+}
+
+fn eval_body(body: &[Node], env: &Rc<Env>) -> Result<Value, CrustError> {
+	let mut res = Value::Unspecified;
+	for node in body {
+		res = eval(node, env)?;
+	}
+	Ok(res)
+}
+
+// What is left to do to evaluate an expression once its tail call, if any,
+// has been reached.
+enum Step {
+	Done(Value),
+	// The closure called in tail position, its arguments and the position
+	// of the call.
+	Call(Rc<Closure>, Vec<Value>, Pos)
+}
+
+// An active call of a closure, for the stack inspection builtins.
+struct Frame {
+	name: Option<Rc<str>>,
+	// Where the closure was called, `None` if it was called from Rust.
+	pos: Option<Pos>
+}
+
+thread_local! {
+	// The frames of the closures being called, innermost last. A tail call
+	// replaces the frame of its caller.
+	static CALL_STACK: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+// Pops the frame pushed by `call` when it returns, also when it fails.
+struct FrameGuard;
+
+impl Drop for FrameGuard {
+	fn drop(&mut self) {
+		CALL_STACK.with(|stack| stack.borrow_mut().pop());
+	}
+}
+
+// Calls `c`, and then every closure called in tail position by the body of
+// the previous one, in a loop, so that tail calls run in constant space.
+// `pos` is where `c` is called, if it is called from crust code.
+fn call(mut c: Rc<Closure>, mut args: Vec<Value>, mut pos: Option<Pos>) -> Result<Value, CrustError> {
+	let _depth = limit::enter().map_err(|e| CrustError { pos, ..e })?;
+	CALL_STACK.with(|stack| stack.borrow_mut().push(Frame { name: c.fun.name.clone(), pos }));
+	let _guard = FrameGuard;
+	loop {
+		let n = c.fun.params.len();
+		if n != args.len() {
+			let name = c.fun.name.as_deref().unwrap_or("#<procedure>").to_string();
+			let kind = ErrorKind::ArityMismatch { name, min: n, max: Some(n), got: args.len() };
+			return Err(CrustError { kind, pos });
+		}
+		let env = Env::new(Some(c.env.clone()));
+		for (param, arg) in c.fun.params.iter().zip(args) {
+			env.define(param.name.clone(), arg);
+		}
+		match eval_tail_body(&c.fun.body, &env)? {
+			Step::Done(v) => return Ok(v),
+			Step::Call(next, next_args, next_pos) => {
+				c = next;
+				args = next_args;
+				pos = Some(next_pos);
+				CALL_STACK.with(|stack| {
+					if let Some(frame) = stack.borrow_mut().last_mut() {
+						*frame = Frame { name: c.fun.name.clone(), pos };
+					}
+				});
+			}
+		}
+	}
+}
+
+fn apply(f: Value, args: Vec<Value>, pos: Option<Pos>) -> Result<Value, CrustError> {
+	match f {
+		Value::Builtin(b) => (b.fun)(&args).map_err(|e| CrustError { pos: e.pos.or(pos), ..e }),
+		Value::Procedure(c) => call(c, args, pos),
+		v => Err(CrustError { kind: ErrorKind::NotAProcedure(v.to_string()), pos })
+	}
+}
+
+// The items of the proper list `v`.
+fn list_items(mut v: &Value) -> Option<Vec<Value>> {
+	let mut items = Vec::new();
+	loop {
+		match *v {
+			Value::Nil => return Some(items),
+			Value::Pair(ref pair) => {
+				items.push(pair.0.clone());
+				v = &pair.1;
+			}
+			_ => return None
+		}
+	}
+}
+
+// Splits the arguments of `(apply f arg ... list)` into `f` and the
+// arguments to call it with.
+fn spread_apply(args: &[Value]) -> Result<(Value, Vec<Value>), CrustError> {
+	check_arity("apply", args, 2, None)?;
+	let (last, init) = args.split_last().unwrap();
+	let mut values = init[1..].to_vec();
+	values.extend(list_items(last).ok_or_else(|| wrong_type("a list", last))?);
+	Ok((args[0].clone(), values))
+}
+
+fn builtin_current_stack_depth(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("current-stack-depth", args, 0, Some(0))?;
+	Ok(Value::Integer(CALL_STACK.with(|stack| stack.borrow().len()) as i64))
+}
+
+// Describes the active calls, innermost first, as lists of the name of
+// the procedure and the line and column of the call. Anonymous procedures
+// and calls made from Rust have #f in place of what they lack.
+fn builtin_call_stack(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("call-stack", args, 0, Some(0))?;
+	let frames = CALL_STACK.with(|stack| {
+		stack.borrow().iter().rev().map(|frame| {
+			let name = frame.name.clone().map_or(Value::Boolean(false), Value::Symbol);
+			let (line, col) = match frame.pos {
+				Some(pos) => (Value::Integer(pos.line as i64), Value::Integer(pos.col as i64)),
+				None => (Value::Boolean(false), Value::Boolean(false))
+			};
+			Value::list(vec![name, line, col])
+		}).collect()
+	});
+	Ok(Value::list(frames))
+}
+
+fn builtin_apply(args: &[Value]) -> Result<Value, CrustError> {
+	let (f, values) = spread_apply(args)?;
+	apply(f, values, None)
+}
+
+// The value of a quoted datum.
+fn quoted(node: &Node) -> Value {
+	match node.kind {
+		NodeKind::Symbol(ref name) => Value::Symbol(name.clone()),
+		NodeKind::Integer(n) => Value::Integer(n),
+		NodeKind::Float(x) => Value::Float(x),
+		NodeKind::Boolean(b) => Value::Boolean(b),
+		NodeKind::Str(ref s) => Value::Str(s.clone()),
+		NodeKind::List(ref items) => Value::list(items.iter().map(quoted).collect()),
+		// parse_datum only produces atoms and lists.
+		_ => unreachable!("not a datum: {:?}", node.kind)
+	}
+}
+
+fn eval(root: &Node, env: &Rc<Env>) -> Result<Value, CrustError> {
+	match eval_tail(root, env)? {
+		Step::Done(v) => Ok(v),
+		Step::Call(c, args, pos) => call(c, args, Some(pos))
+	}
+}
+
+// Evaluates a non-empty body, with its last expression in tail position.
+fn eval_tail_body(body: &[Node], env: &Rc<Env>) -> Result<Step, CrustError> {
+	let (last, init) = body.split_last().expect("bodies are never empty");
+	for node in init {
+		eval(node, env)?;
+	}
+	eval_tail(last, env)
+}
+
+// Evaluates `root` with it in tail position: a call to a closure there is
+// returned rather than made, so that the caller can make it without
+// growing the stack.
+fn eval_tail(root: &Node, env: &Rc<Env>) -> Result<Step, CrustError> {
+	let v = match root.kind {
+		NodeKind::Symbol(ref name) => match env.lookup(name) {
+			Some(v) => v,
+			None => return Err(CrustError::new(ErrorKind::UnboundSymbol(name.to_string()), root.span.start))
+		},
+		NodeKind::Integer(n) => Value::Integer(n),
+		NodeKind::Float(x) => Value::Float(x),
+		NodeKind::Boolean(b) => Value::Boolean(b),
+		NodeKind::Str(ref s) => Value::Str(s.clone()),
+		NodeKind::Quote(ref datum) => quoted(datum),
+		NodeKind::List(_) => quoted(root),
+		NodeKind::If(ref test, ref consequent, ref alternative) => {
+			if eval(test, env)?.is_true() {
+				return eval_tail(consequent, env);
+			}
+			match *alternative {
+				Some(ref alternative) => return eval_tail(alternative, env),
+				None => Value::Unspecified
+			}
+		}
+		NodeKind::Cond(ref clauses) => {
+			for clause in clauses {
+				let test = match clause.test {
+					Some(ref test) => eval(test, env)?,
+					None => return eval_tail_body(&clause.body, env)
+				};
+				if test.is_true() {
+					// A clause without a body yields the value of its test.
+					if clause.body.is_empty() {
+						return Ok(Step::Done(test));
+					}
+					return eval_tail_body(&clause.body, env);
+				}
+			}
+			Value::Unspecified
+		}
+		NodeKind::And(ref nodes) => match nodes.split_last() {
+			Some((last, init)) => {
+				for node in init {
+					let v = eval(node, env)?;
+					if !v.is_true() {
+						return Ok(Step::Done(v));
+					}
+				}
+				return eval_tail(last, env);
+			}
+			None => Value::Boolean(true)
+		},
+		NodeKind::Or(ref nodes) => match nodes.split_last() {
+			Some((last, init)) => {
+				for node in init {
+					let v = eval(node, env)?;
+					if v.is_true() {
+						return Ok(Step::Done(v));
+					}
+				}
+				return eval_tail(last, env);
+			}
+			None => Value::Boolean(false)
+		},
+		NodeKind::Define(ref name, ref value) => {
+			let value = eval(value, env)?;
+			env.define(name.name.clone(), value);
+			Value::Unspecified
+		}
+		NodeKind::Lambda(ref fun) => Value::procedure(fun.clone(), env.clone()),
+		NodeKind::Application(ref f, ref args) => {
+			signal::check().and_then(|_| limit::check()).map_err(|e| e.or_at(root.span.start))?;
+			let mut f = eval(f, env)?;
+			let mut values = Vec::with_capacity(args.len());
+			for a in args {
+				values.push(eval(a, env)?);
+			}
+			loop {
+				let (g, spread) = match f {
+					Value::Builtin(ref b) if b.is_apply => spread_apply(&values).map_err(|e| e.or_at(root.span.start))?,
+					_ => break
+				};
+				f = g;
+				values = spread;
+			}
+			if let Value::Procedure(c) = f {
+				return Ok(Step::Call(c, values, root.span.start));
+			}
+			apply(f, values, Some(root.span.start))?
+		}
+	};
+	Ok(Step::Done(v))
+}
+
+fn eval_program(roots: &[Node], env: &Rc<Env>) -> Result<Value, CrustError> {
+	eval_body(roots, env)
+}
+
+/// An interpreter with its own global environment. Definitions made by one
+/// call to [`Interpreter::eval_str`] are visible to the next. Dropping it
+/// frees its definitions, after which procedures it returned no longer
+/// find the variables they refer to.
+pub struct Interpreter {
+	env: Rc<Env>,
+	fold_case: bool,
+	time_limit: Option<Duration>,
+	fuel_limit: Option<u64>,
+	use_cache: bool,
+	depth_limit: bool,
+	// Whether only `PURE_BUILTINS` are defined.
+	pure: bool,
+	// The names given to `register`, which forks share.
+	registered: Vec<Rc<str>>
+}
+
+// Dropping an interpreter, or a fork of one, frees what its programs
+// defined; see `release`.
+impl Drop for Interpreter {
+	fn drop(&mut self) {
+		signal::clear(&self.env);
+		release(&self.env);
+	}
+}
+
+impl Interpreter {
+	/// Creates an interpreter with only the builtins defined.
+	pub fn new() -> Interpreter {
+		Interpreter {
+			env: global_env(),
+			fold_case: false,
+			time_limit: None,
+			fuel_limit: None,
+			use_cache: true,
+			depth_limit: false,
+			pure: false,
+			registered: Vec::new()
+		}
+	}
+
+	/// Creates an interpreter with only the builtins that have no effects
+	/// and depend on nothing but their arguments, so that evaluating a
+	/// program gives the same result every time and cannot change anything
+	/// outside of the interpreter. Together with a
+	/// [fuel limit](Interpreter::fuel_limit) this makes every evaluation
+	/// end. Procedures can still be added with [`Interpreter::register`].
+	pub fn pure() -> Interpreter {
+		Interpreter {
+			env: pure_env(),
+			fold_case: false,
+			time_limit: None,
+			fuel_limit: None,
+			use_cache: true,
+			depth_limit: false,
+			pure: true,
+			registered: Vec::new()
+		}
+	}
+
+	/// Makes symbols in programs evaluated from now on case-insensitive, as
+	/// in classic Lisps: `Foo`, `FOO` and `foo` are all read as `foo`,
+	/// including in quoted data. Strings are left alone, and names given to
+	/// [`Interpreter::register`] are used as they are.
+	pub fn fold_case(&mut self, fold: bool) {
+		self.fold_case = fold;
+	}
+
+	/// Makes evaluations from now on fail with [`ErrorKind::TimeLimit`] if
+	/// they run for longer than `limit`, or lifts the limit if it is
+	/// `None`. The limit is checked between procedure calls, so a single
+	/// builtin that blocks, such as one reading input, can overrun it.
+	///
+	/// With a time or fuel limit, evaluations also fail with
+	/// [`ErrorKind::DepthLimit`] if calls nest more than [`MAX_DEPTH`]
+	/// deep. They then need a thread with a stack of [`STACK_SIZE`] bytes.
+	pub fn time_limit(&mut self, limit: Option<Duration>) {
+		self.time_limit = limit;
+	}
+
+	/// Makes evaluations from now on fail with
+	/// [`ErrorKind::FuelExhausted`] once they have made `limit` procedure
+	/// calls, or lifts the limit if it is `None`. Unlike a time limit,
+	/// this stops a program at the same point every time.
+	pub fn fuel_limit(&mut self, limit: Option<u64>) {
+		self.fuel_limit = limit;
+	}
+
+	/// Makes evaluations from now on fail with [`ErrorKind::DepthLimit`] if
+	/// calls nest more than [`MAX_DEPTH`] deep, also without a time or fuel
+	/// limit, if `on` is true. They then need a thread with a stack of
+	/// [`STACK_SIZE`] bytes.
+	pub fn depth_limit(&mut self, on: bool) {
+		self.depth_limit = on;
+	}
+
+	/// Makes `cached` in evaluations from now on call its procedure every
+	/// time, without reading or writing the cache, if `on` is false.
+	pub fn use_cache(&mut self, on: bool) {
+		self.use_cache = on;
+	}
+
+	/// Evaluates the program `src`, returning the value of its last
+	/// expression or `Value::Unspecified` if it has none.
+	pub fn eval_str(&mut self, src: &str) -> Result<Value, CrustError> {
+		self.eval_in(src, &self.env)
+	}
+
+	/// Evaluates a configuration file, checking that its value is an
+	/// association list of `(key value)` lists with the keys and types
+	/// `schema` asks for. The file is evaluated on its own, not seeing the
+	/// definitions of this interpreter, and can only use the builtins of
+	/// [`Interpreter::pure`], so reading it cannot change anything. The
+	/// time and fuel limits of this interpreter apply.
+	pub fn eval_config(&mut self, src: &str, schema: &config::Schema) -> Result<Value, CrustError> {
+		let v = self.eval_in(src, &pure_env())?;
+		schema.validate(&v)?;
+		Ok(v)
+	}
+
+	fn eval_in(&self, src: &str, env: &Rc<Env>) -> Result<Value, CrustError> {
+		let mut names = Vec::new();
+		let tokens = if self.fold_case { fold_case(lex(src)?, &mut names) } else { lex(src)? };
+		let roots = parse(&tokens)?;
+		self.eval_roots(&roots, env)
+	}
+
+	fn eval_roots(&self, roots: &[Node], env: &Rc<Env>) -> Result<Value, CrustError> {
+		self.evaluating(|| eval_program(roots, env))
+	}
+
+	// Runs `f` with the interpreter's limits and cache setting.
+	fn evaluating<T, F: FnOnce() -> T>(&self, f: F) -> T {
+		cache::with_cache(self.use_cache, || limit::with_limits(self.time_limit, self.fuel_limit, || {
+			if self.depth_limit { limit::with_depth_limit(f) } else { f() }
+		}))
+	}
+
+	/// Like [`Interpreter::eval_str`], for a program that has not been
+	/// checked to be UTF-8. See [`decode`].
+	pub fn eval_bytes(&mut self, src: &[u8]) -> Result<Value, CrustError> {
+		self.eval_str(&decode(src, false)?)
+	}
+
+	/// Like [`Interpreter::eval_str`], also returning the resources the
+	/// evaluation used, whether it succeeded or not.
+	pub fn eval_measured(&mut self, src: &str) -> (Result<Value, CrustError>, Usage) {
+		limit::measure(self, src)
+	}
+
+	/// Runs an interactive session reading expressions from `input`, with
+	/// the definitions, limits and settings of this interpreter.
+	pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+		let mut lines = input.lines();
+		let mut buffer = String::new();
+		loop {
+			write!(output, "{}", if buffer.is_empty() { "crust> " } else { "  ...> " })?;
+			output.flush()?;
+			match lines.next() {
+				Some(line) => {
+					buffer.push_str(&line?);
+					buffer.push('\n');
+				}
+				None => {
+					writeln!(output)?;
+					break;
+				}
+			}
+			// Keep reading while a list or a string is still open.
+			match lex(&buffer) {
+				Ok(ref tokens) if depth(tokens) > 0 => continue,
+				Err(CrustError { kind: ErrorKind::UnterminatedString, .. }) => continue,
+				_ => ()
+			}
+
+			let source = std::mem::take(&mut buffer);
+			let mut names = Vec::new();
+			let tokens = lex(&source).map(|tokens| if self.fold_case { fold_case(tokens, &mut names) } else { tokens });
+			let roots = match tokens.and_then(|tokens| parse(&tokens)) {
+				Ok(roots) => roots,
+				Err(e) => {
+					writeln!(output, "error: {}", e)?;
+					continue;
+				}
+			};
+			for root in &roots {
+				match self.eval_roots(slice::from_ref(root), &self.env) {
+					Ok(Value::Unspecified) => (),
+					Ok(v) => writeln!(output, "{}", v)?,
+					Err(CrustError { kind: ErrorKind::Exit(_), .. }) => return Ok(()),
+					Err(e) => {
+						writeln!(output, "error: {}", e)?;
+						break;
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Saves the global definitions, and everything they refer to, so that
+	/// [`Interpreter::restore`] can set up other interpreters the same way
+	/// without evaluating their programs again. Fails if a global refers to
+	/// a builtin that holds state of its own, like the procedure returned
+	/// by `progress-bar`.
+	pub fn snapshot(&self) -> Result<Vec<u8>, CrustError> {
+		snapshot::snapshot(&self.env)
+	}
+
+	/// Defines the globals saved by [`Interpreter::snapshot`]. Builtins are
+	/// restored by name, so procedures the host registered in the original
+	/// interpreter must be registered in this one first.
+	pub fn restore(&mut self, bytes: &[u8]) -> Result<(), CrustError> {
+		snapshot::restore(&self.env, bytes)
+	}
+
+	/// Creates an interpreter with the same global definitions, which it
+	/// can then change without affecting this one, and the other way round.
+	/// Procedures defined in crust are copied along with the environments
+	/// they close over, while their syntax, strings and data that holds no
+	/// procedures are shared. The fork gets builtins of its own, except for
+	/// procedures registered with [`Interpreter::register`], which are
+	/// shared.
+	pub fn fork(&self) -> Interpreter {
+		let child = Interpreter {
+			env: if self.pure { pure_env() } else { global_env() },
+			fold_case: self.fold_case,
+			time_limit: self.time_limit,
+			fuel_limit: self.fuel_limit,
+			use_cache: self.use_cache,
+			depth_limit: self.depth_limit,
+			pure: self.pure,
+			registered: self.registered.clone()
+		};
+		for name in &self.registered {
+			if let Some(v @ Value::Builtin(_)) = self.env.lookup(name) {
+				child.env.define(name.clone(), v);
+			}
+		}
+		fork::fork(&self.env, &child.env);
+		child
+	}
+
+	/// Defines `name` as a procedure implemented by `fun`, replacing any
+	/// previous definition. `fun` is called with the evaluated arguments, and
+	/// errors it returns are reported at the position of the call.
+	pub fn register<F>(&mut self, name: &str, fun: F)
+		where F: Fn(&[Value]) -> Result<Value, CrustError> + 'static {
+		self.env.define(Rc::from(name), builtin(name, fun));
+		if !self.registered.iter().any(|r| **r == *name) {
+			self.registered.push(Rc::from(name));
+		}
+	}
+}
+
+impl Default for Interpreter {
+	fn default() -> Interpreter {
+		Interpreter::new()
+	}
+}
+
+fn eval_str(src: &str) -> Result<String, CrustError> {
+	Ok(Interpreter::new().eval_str(src)?.to_string())
+}
+
+// A new directory for a test, removed with everything in it when the test
+// is done. Each one has a name of its own, as tests run at the same time.
+#[cfg(test)]
+struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+	fn new() -> TempDir {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		static NEXT: AtomicUsize = AtomicUsize::new(0);
+		let n = NEXT.fetch_add(1, Ordering::Relaxed);
+		let dir = std::env::temp_dir().join(format!("crust-test-{}-{}", std::process::id(), n));
+		// Left over from an earlier run that had the same process id.
+		let _ = std::fs::remove_dir_all(&dir);
+		std::fs::create_dir_all(&dir).unwrap();
+		TempDir(dir)
+	}
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+	type Target = std::path::Path;
+
+	fn deref(&self) -> &std::path::Path {
+		&self.0
+	}
+}
+
+#[cfg(test)]
+impl AsRef<std::path::Path> for TempDir {
+	fn as_ref(&self) -> &std::path::Path {
+		&self.0
+	}
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
+
+#[test]
+fn test_drop_frees_procedures() {
+	let mut interp = Interpreter::new();
+	let global = Rc::downgrade(&interp.env);
+	let local = match interp.eval_str("(define (f x) x) (define (make) (define (g) (f 1)) g) (define h (make)) h").unwrap() {
+		Value::Procedure(c) => Rc::downgrade(&c.env),
+		_ => unreachable!("h is a procedure")
+	};
+	let fork = interp.fork();
+	let forked = Rc::downgrade(&fork.env);
+	drop(fork);
+	assert!(forked.upgrade().is_none());
+	assert_eq!("1", interp.eval_str("(h)").unwrap().to_string());
+	drop(interp);
+	assert!(global.upgrade().is_none());
+	assert!(local.upgrade().is_none());
+}
+
+#[test]
+fn test_interpreter() {
+	let mut interp = Interpreter::new();
+	let calls = Rc::new(RefCell::new(Vec::new()));
+	let log = calls.clone();
+	interp.register("log", move |args| {
+		log.borrow_mut().extend(args.iter().map(|v| v.to_string()));
+		Ok(Value::Unspecified)
+	});
+	interp.register("fail", |_| Err(ErrorKind::Host("failed".to_string()).into()));
+	interp.eval_str("(define (f x) (log x \"y\") (* x 2))").unwrap();
+	assert_eq!("6", interp.eval_str("(f 3)").unwrap().to_string());
+	assert_eq!(vec!["3", "\"y\""], *calls.borrow());
+	assert_eq!("#<builtin log>", interp.eval_str("log").unwrap().to_string());
+	assert_eq!("1:4: failed", interp.eval_str("(+ (fail))").unwrap_err().to_string());
+	// Definitions survive an error.
+	assert_eq!("6", interp.eval_str("(f 3)").unwrap().to_string());
+	assert_eq!("1:4: invalid UTF-8 at byte offset 3", interp.eval_bytes(b"(f \x80)").unwrap_err().to_string());
+	assert_eq!("8", interp.eval_bytes(b"(f 4)").unwrap().to_string());
+}
+
+#[test]
+fn test_fold_case() {
+	let mut interp = Interpreter::new();
+	interp.fold_case(true);
+	interp.eval_str("(DEFINE (Square x) (* X x))").unwrap();
+	assert_eq!("49", interp.eval_str("(square 7)").unwrap().to_string());
+	assert_eq!("(quote äpple \"Str\")", interp.eval_str("(LIST 'QUOTE 'Äpple \"Str\")").unwrap().to_string());
+	// Positions are those of the source as written.
+	assert_eq!("1:12: unbound symbol 'ünbound'", interp.eval_str("(Square 1) ÜNBOUND").unwrap_err().to_string());
+	interp.fold_case(false);
+	assert_eq!("1:1: unbound symbol 'SQUARE'", interp.eval_str("SQUARE").unwrap_err().to_string());
+}
+
+#[test]
+fn test_pure() {
+	let mut interp = Interpreter::pure();
+	interp.register("host", |_| Ok(Value::Integer(1)));
+	assert_eq!("(1 2 \"a%20b\")", interp.eval_str("(list (host) (apply + '(1 1)) (url-encode \"a b\"))").unwrap().to_string());
+	for name in ["save", "cached", "tar-create", "after", "exit", "trace", "process"] {
+		assert_eq!(ErrorKind::UnboundSymbol(name.to_string()), interp.eval_str(name).unwrap_err().kind);
+	}
+	assert_eq!(ErrorKind::UnboundSymbol("save".to_string()), interp.fork().eval_str("save").unwrap_err().kind);
+}
+
+#[test]
+fn test_procedures() {
+	assert_eq!("49", eval_str("(define (square x) (* x x)) (square 7)").unwrap());
+	assert_eq!("12", eval_str("((lambda (x y) (* x y)) 3 4)").unwrap());
+	assert_eq!("7", eval_str("((lambda () 7))").unwrap());
+	assert_eq!("#<procedure square>", eval_str("(define (square x) (* x x)) square").unwrap());
+	assert_eq!("#<procedure>", eval_str("(lambda (x) x)").unwrap());
+	assert_eq!("#<builtin +>", eval_str("+").unwrap());
+}
+
+#[test]
+fn test_closures() {
+	assert_eq!("5", eval_str("(define (adder n) (lambda (x) (+ x n))) ((adder 2) 3)").unwrap());
+	assert_eq!("8", eval_str("(define (twice f x) (f (f x))) (twice (lambda (x) (* x 2)) 2)").unwrap());
+	assert_eq!("6", eval_str("(define add +) (add 1 2 3)").unwrap());
+}
+
+#[test]
+fn test_lexical_scope() {
+	// `f` sees the global `x`, not the `x` bound where it is called.
+	assert_eq!("1", eval_str("(define x 1) (define (f) x) (define (g x) (f)) (g 2)").unwrap());
+	assert_eq!("2", eval_str("(define x 1) ((lambda (x) x) 2)").unwrap());
+	assert_eq!("1", eval_str("(define x 1) ((lambda (x) x) 2) x").unwrap());
+}
+
+#[test]
+fn test_values() {
+	assert_eq!("(1 2.5 #t \"a\\nb\" sym (x) ())", eval_str(r#"'(1 2.5 #t "a\nb" sym (x) ())"#).unwrap());
+	assert_eq!("(quote x)", eval_str("''x").unwrap());
+	assert_eq!("(1 2 . 3)", eval_str("(cons 1 (cons 2 3))").unwrap());
+	assert_eq!("-3.0", eval_str("(- 0.5 3.5)").unwrap());
+	assert_eq!("+inf.0", eval_str("(/ 1.0 0)").unwrap());
+	assert_eq!("#f", eval_str("(eq? \"a\" \"a\")").unwrap());
+	assert_eq!("#t", eval_str("(define s \"a\") (eq? s s)").unwrap());
+	assert_eq!("#t", eval_str("(and (= 1 1.0) (< 1 1.5 2) (not (> 2 2)) (>= 2 2 1))").unwrap());
+}
+
+#[test]
+fn test_string_to_number() {
+	// string->number reads what the lexer reads, and numbers print in a
+	// form that reads back.
+	for w in ["42", "-7", "#x1F", "1.5e3", "-0.0", "1e300", "+inf.0", "+nan.0"] {
+		let n = eval_str(w).unwrap();
+		assert_eq!(n, eval_str(&format!("(string->number \"{}\")", w)).unwrap());
+		assert_eq!(n, eval_str(&n).unwrap());
+	}
+	assert_eq!("255", eval_str("(string->number \"ff\" 16)").unwrap());
+	assert_eq!("#f", eval_str("(string->number \"1.5\" 16)").unwrap());
+	assert_eq!("#f", eval_str("(string->number \"abc\")").unwrap());
+	assert_eq!("#f", eval_str("(string->number \" 1\")").unwrap());
+	assert_eq!("1:1: expected a string, got 1", eval_str("(string->number 1)").unwrap_err().to_string());
+	assert_eq!("1:1: expected a radix of 2, 8, 10 or 16, got 3",
+	           eval_str("(string->number \"1\" 3)").unwrap_err().to_string());
+}
+
+#[test]
+fn test_conditionals() {
+	let fact = "(define (fact n) (if (< n 2) 1 (* n (fact (- n 1)))))";
+	assert_eq!("3628800", eval_str(&format!("{} (fact 10)", fact)).unwrap());
+	assert_eq!("#<unspecified>", eval_str("(if #f 1)").unwrap());
+	assert_eq!("2", eval_str("(cond (#f 1) (2) (else 3))").unwrap());
+	assert_eq!("#<unspecified>", eval_str("(cond (#f 1))").unwrap());
+	assert_eq!("#t", eval_str("(and)").unwrap());
+	assert_eq!("#f", eval_str("(or)").unwrap());
+	assert_eq!("#f", eval_str("(and 1 #f (car 1))").unwrap());
+	assert_eq!("1", eval_str("(or #f 1 (car 1))").unwrap());
+}
+
+#[test]
+fn test_tail_calls() {
+	// A million iterations is far deeper than the Rust stack would allow if
+	// tail calls grew it. The other kinds of tail position get fewer to keep
+	// debug builds of the tests quick.
+	assert_eq!("#t", eval_str("(define (even? n) (if (= n 0) #t (odd? (- n 1))))\n\
+	                           (define (odd? n) (if (= n 0) #f (even? (- n 1))))\n\
+	                           (even? 1000000)").unwrap());
+	let n = 100_000;
+	assert_eq!("done", eval_str(&format!("(define (loop n) (cond ((= n 0) 'done) (else (and #t (or #f (loop (- n 1)))))))\n\
+	                                      (loop {})", n)).unwrap());
+	assert_eq!("5000050000", eval_str(&format!("(define (sum n acc) (if (= n 0) acc ((lambda () (sum (- n 1) (+ acc n))))))\n\
+	                                            (sum {} 0)", n)).unwrap());
+	assert_eq!("0", eval_str(&format!("(define (loop n) (if (= n 0) 0 (apply apply loop (list (list (- n 1))))))\n\
+	                                   (loop {})", n)).unwrap());
+}
+
+#[test]
+fn test_call_stack() {
+	assert_eq!("0", eval_str("(current-stack-depth)").unwrap());
+	assert_eq!("11", eval_str("(define (d n) (if (= n 0) (current-stack-depth) (+ 0 (d (- n 1)))))\n(d 10)").unwrap());
+	let src = "(define (f) (call-stack))\n\
+	           (define (g) (list (f)))\n\
+	           (define (h) (f))\n";
+	assert_eq!("(((f 2 19) (g 4 1)))", eval_str(&format!("{}(g)", src)).unwrap());
+	// A tail call replaces the frame of its caller.
+	assert_eq!("((f 3 13))", eval_str(&format!("{}(h)", src)).unwrap());
+	assert_eq!("((#f 1 1))", eval_str("((lambda () (call-stack)))").unwrap());
+	// Frames are popped when a call fails.
+	let mut interp = Interpreter::new();
+	assert!(interp.eval_str("(define (f) (car 1)) (f)").is_err());
+	assert_eq!("0", interp.eval_str("(current-stack-depth)").unwrap().to_string());
+}
+
+#[test]
+fn test_trace() {
+	let mut interp = Interpreter::new();
+	interp.eval_str("(define (f x) x) (define (g x) x) (trace 'g) (trace 'f) (trace 'f)").unwrap();
+	assert_eq!("(g f)", interp.eval_str("(traced?)").unwrap().to_string());
+	assert_eq!("3", interp.eval_str("(f 3)").unwrap().to_string());
+	interp.eval_str("(untrace 'g) (untrace 'g)").unwrap();
+	assert_eq!("#<procedure g>", interp.eval_str("g").unwrap().to_string());
+	// A procedure redefined while traced keeps its new definition.
+	interp.eval_str("(define (f x) (* 2 x)) (untrace 'f)").unwrap();
+	assert_eq!("()", interp.eval_str("(traced?)").unwrap().to_string());
+	assert_eq!("6", interp.eval_str("(f 3)").unwrap().to_string());
+	assert_eq!("1:14: expected a procedure, got 1", interp.eval_str("(define x 1) (trace 'x)").unwrap_err().to_string());
+	assert_eq!("1:1: unbound symbol 'y'", interp.eval_str("(trace 'y)").unwrap_err().to_string());
+}
+
+#[test]
+fn test_apply() {
+	assert_eq!("10", eval_str("(apply + 1 2 '(3 4))").unwrap());
+	assert_eq!("(1 2)", eval_str("(apply list '(1 2))").unwrap());
+	assert_eq!("3", eval_str("(define (f x y) (- x y)) (apply f 5 '(2))").unwrap());
+	assert_eq!("1:1: expected a list, got 3", eval_str("(apply + 1 2 3)").unwrap_err().to_string());
+	assert_eq!("2:1: wrong number of arguments to f: expected 2, got 1",
+	           eval_str("(define (f x y) x)\n(apply f '(1))").unwrap_err().to_string());
+}
+
+#[test]
+fn test_errors() {
+	let error = |src| eval_str(src).unwrap_err().to_string();
+	assert_eq!("1:1: unbalanced parens", error("(+ 1 2"));
+	assert_eq!("2:11: unbalanced parens", error("(+ 1\n  (* 2 3)))"));
+	assert_eq!("1:9: unbalanced parens", error("(define (f x"));
+	assert_eq!("1:1: unbalanced parens", error("(define (f x) x"));
+	assert_eq!("1:2: unexpected token ')'", error("()"));
+	assert_eq!("1:9: unexpected token '1'", error("(define 1 2)"));
+	assert_eq!("1:13: unexpected token '3'", error("(define x 2 3)"));
+	assert_eq!("1:1: bad syntax: define needs a name and a value", error("(define x)"));
+	assert_eq!("1:1: bad syntax: define needs a name and a value", error("(define)"));
+	assert_eq!("1:1: unbalanced parens", error("(define x"));
+	assert_eq!("1:12: unexpected token ')'", error("(lambda (x))"));
+	assert_eq!("3:17: unbound symbol 'foo'", error("(define x 1)\n\n(+ x (* 2 3) 40 foo)"));
+	assert_eq!("1:26: wrong number of arguments to f: expected 1, got 2",
+	           error("(define (f x) x) (+ 1 2) (f 1 2)"));
+	assert_eq!("1:1: wrong number of arguments to /: expected at least 1, got 0", error("(/)"));
+	assert_eq!("1:6: division by zero", error("(+ 1 (/ 4 0))"));
+	assert_eq!("1:1: arithmetic overflow", error("(* 4294967296 4294967296)"));
+	assert_eq!("1:1: expected a number, got #<builtin +>", error("(+ 1 +)"));
+	assert_eq!("1:1: not a procedure: 1", error("(1 2)"));
+	assert_eq!("1:1: bad syntax: if expects a test, a consequent and an optional alternative", error("(if 1)"));
+	assert_eq!("1:16: bad syntax: else must be the last cond clause", error("(cond (else 1) (#t 2))"));
+	assert_eq!("1:1: expected a pair, got ()", error("(car '())"));
+	assert_eq!("1:1: expected a number, got \"1\"", error("(< 1 \"1\")"));
+	assert_eq!("1:1: wrong number of arguments to cons: expected 2, got 1", error("(cons 1)"));
+	// Errors keep the position where they happened, not where the
+	// procedure containing them was called.
+	assert_eq!("1:15: division by zero", error("(define (f x) (/ x 0))\n(f 1)"));
+}
+
+// The embedded conformance suite run by `crust doctor`: a name, a program
+// and what the last expression of the program must print as, or the error
+// it must fail with.
+const DOCTOR_CHECKS: &[(&str, &str, &str)] = &[
+	("empty sum", "(+)", "0"),
+	("empty product", "(*)", "1"),
+	("addition", "(+ 1 2 3)", "6"),
+	("multiplication", "(* 2 3 4)", "24"),
+	("division", "(/ 100 5 2)", "10"),
+	("nested application", "(+ (* 2 3) (/ 8 4))", "8"),
+	("additive identity", "(+ 4711 0)", "4711"),
+	("multiplicative identity", "(* 4711 1)", "4711"),
+	("commutativity", "(/ (* 3 4) (* 4 3))", "1"),
+	("distributivity", "(/ (* 3 (+ 4 5)) (+ (* 3 4) (* 3 5)))", "1"),
+	("define", "(define x 5) (+ x x)", "10"),
+	("procedures", "(define (square x) (* x x)) (square 7)", "49"),
+	("closures", "(define (adder n) (lambda (x) (+ x n))) ((adder 2) 3)", "5"),
+	("tail calls", "(define (loop n) (if (= n 0) 'done (loop (- n 1)))) (loop 100000)", "done"),
+	("mutual tail calls", "(define (f n) (if (= n 0) 'done (g (- n 1)))) (define (g n) (f n)) (f 100000)", "done"),
+	("apply", "(apply + 1 '(2 3))", "6"),
+	("stack depth", "(define (f) (current-stack-depth)) (define (g) (+ (f) 0)) (g)", "2"),
+	("unicode symbols", "(define λ 2) (define π 3) (* λ π)", "6"),
+	("unicode separators", "(define\u{3000}x 1)\u{2003}(+ x x)", "2"),
+	("unicode positions", "(+ 1\n   λ)", "error: 2:4: unbound symbol 'λ'"),
+	("division by zero", "(/ 1 0)", "error: 1:1: division by zero"),
+	("overflow", "(+ 9223372036854775807 1)", "error: 1:1: arithmetic overflow"),
+	("subtraction", "(- 10 4 3)", "3"),
+	("negation", "(- 5)", "-5"),
+	("float contagion", "(+ 1 0.5)", "1.5"),
+	("integer division", "(/ 7 2)", "3"),
+	("comparison", "(< 1 2 3)", "#t"),
+	("if", "(if (> 1 2) 'yes 'no)", "no"),
+	("cond", "(cond ((= 1 2) 1) ((= 1 1) 2) (else 3))", "2"),
+	("only #f is false", "(and 0 '() \"\")", "\"\""),
+	("short circuit", "(or #f (= 1 1) (car '()))", "#t"),
+	("lists", "(cons 1 (cdr (list 1 2 3)))", "(1 2 3)"),
+	("improper lists", "(cons 1 2)", "(1 . 2)"),
+	("strings", "\"λ \\\"x\\\"\"", "\"λ \\\"x\\\"\""),
+	("structural equality", "(equal? (list 1 \"a\") '(1 \"a\"))", "#t"),
+	("number syntax", "(list #xff -1.5e1 (string->number \"#b101\") (string->number \"1+\"))", "(255 -15.0 5 #f)"),
+];
+
+/// The outcome of one check run by [`doctor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+	pub name: &'static str,
+	/// What went wrong, or None if the check passed.
+	pub failure: Option<String>
+}
+
+/// Runs the built-in conformance suite and returns the outcome of each
+/// check, in order. A check that panics fails, but the panic hook still
+/// runs; it is up to the caller to silence it if it wants to.
+pub fn doctor() -> Vec<Check> {
+	DOCTOR_CHECKS.iter().map(|&(name, src, expected)| {
+		let res = panic::catch_unwind(|| match eval_str(src) {
+			Ok(v) => v,
+			Err(e) => format!("error: {}", e)
+		});
+		let failure = match res {
+			Ok(res) if res == expected => None,
+			Ok(res) => Some(format!("expected {}, got {}", expected, res)),
+			Err(_) => Some("evaluation panicked".to_string())
+		};
+		Check { name, failure }
+	}).collect()
+}
+
+#[test]
+fn test_doctor() {
+	let checks = doctor();
+	assert_eq!(checks.len(), DOCTOR_CHECKS.len());
+	assert_eq!(checks.iter().find(|c| c.failure.is_some()), None);
+	assert_eq!(checks[0].name, "empty sum");
+}
+
+// The number of parens left open at the end of `tokens`, used by the REPL
+// to decide whether an expression continues on the next line.
+fn depth(tokens: &[(Token, Span)]) -> isize {
+	tokens.iter().fold(0, |d, t| match t.0 {
+		Token::LeftParen => d + 1,
+		Token::RightParen => d - 1,
+		_ => d
+	})
+}
+
+/// Runs an interactive session reading expressions from `input`, in an
+/// interpreter of its own. See [`Interpreter::repl`].
+pub fn repl<R: BufRead, W: Write>(input: R, output: W) -> io::Result<()> {
+	Interpreter::new().repl(input, output)
+}
+
+#[test]
+fn test_repl() {
+	let input = "(define (square x) (* x x))\n(square\n  3)\n\n(+ 1 2) (+ 3 4)\n";
+	let mut output = Vec::new();
+	repl(input.as_bytes(), &mut output).unwrap();
+	assert_eq!("crust> crust>   ...> 9\ncrust> crust> 3\n7\ncrust> \n",
+	           String::from_utf8(output).unwrap());
+
+	// An error does not end the session.
+	let mut output = Vec::new();
+	repl("(define x 2)\n(y)\nx)\nx\n".as_bytes(), &mut output).unwrap();
+	assert_eq!("crust> crust> error: 1:2: unbound symbol 'y'\n\
+	            crust> error: 1:2: unbalanced parens\n\
+	            crust> 2\ncrust> \n",
+	           String::from_utf8(output).unwrap());
+
+	// A string can span several lines.
+	let mut output = Vec::new();
+	repl("\"a\nb\"\n".as_bytes(), &mut output).unwrap();
+	assert_eq!("crust>   ...> \"a\\nb\"\ncrust> \n", String::from_utf8(output).unwrap());
+
+	// The interpreter's limits apply.
+	let mut interp = Interpreter::new();
+	interp.fuel_limit(Some(10));
+	let mut output = Vec::new();
+	interp.repl("(define (f) (f))\n(f)\n".as_bytes(), &mut output).unwrap();
+	assert_eq!("crust> crust> error: 1:13: out of fuel\ncrust> \n", String::from_utf8(output).unwrap());
+}
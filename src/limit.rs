@@ -0,0 +1,270 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Limits on how long an evaluation may run, and accounting of what it
+// used. The limits belong to the thread doing the evaluation and are
+// checked on procedure calls, the deadline also by builtins that sleep.
+// While either is set, or the depth limit is on, calls may also only nest
+// `MAX_DEPTH` deep, so that a runaway recursion fails rather than
+// overflowing the stack.
+//
+// Fuel and heap are counted so that the same program uses the same amount
+// every time: fuel is the number of procedure calls, and the heap is the
+// size of the pairs, procedures and environments alive, each counted as
+// its size in memory plus that of each variable in an environment.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use super::{CrustError, ErrorKind, Interpreter, Value};
+
+thread_local! {
+	static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+	// The calls made on this thread.
+	static FUEL: Cell<u64> = const { Cell::new(0) };
+	// The value of `FUEL` at which the fuel limit is reached.
+	static FUEL_END: Cell<Option<u64>> = const { Cell::new(None) };
+	static HEAP: Cell<usize> = const { Cell::new(0) };
+	// The largest `HEAP` has been since the evaluation being measured
+	// started.
+	static PEAK: Cell<usize> = const { Cell::new(0) };
+	// The calls being made on this thread, and how many there may be if
+	// the depth is limited.
+	static DEPTH: Cell<usize> = const { Cell::new(0) };
+	static DEPTH_LIMIT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// How deeply calls may nest in an evaluation with a time or fuel limit,
+/// or with [`Interpreter::depth_limit`] on.
+pub const MAX_DEPTH: usize = 10_000;
+
+/// The stack a thread needs to make [`MAX_DEPTH`] nested calls, with room
+/// to spare for the builtins they call. Evaluations with limits should run
+/// on a thread with a stack this big, as the threads of a
+/// [`Pool`](super::Pool) do.
+pub const STACK_SIZE: usize = 512 << 20;
+
+// Reading the clock costs more than a call, so it is read every this many
+// calls.
+const CALLS_PER_CHECK: u64 = 256;
+
+pub(super) fn check() -> Result<(), CrustError> {
+	let fuel = FUEL.with(|f| {
+		f.set(f.get() + 1);
+		f.get()
+	});
+	if FUEL_END.with(Cell::get).is_some_and(|end| fuel > end) {
+		return Err(ErrorKind::FuelExhausted.into());
+	}
+	if !fuel.is_multiple_of(CALLS_PER_CHECK) {
+		return Ok(());
+	}
+	check_now()
+}
+
+// Leaves the call entered by `enter` when dropped.
+pub(super) struct Depth;
+
+impl Drop for Depth {
+	fn drop(&mut self) {
+		DEPTH.with(|d| d.set(d.get() - 1));
+	}
+}
+
+// Enters a call, failing if that would make calls nest deeper than the
+// depth limit.
+pub(super) fn enter() -> Result<Depth, CrustError> {
+	let depth = DEPTH.with(|d| d.get());
+	if DEPTH_LIMIT.with(Cell::get).is_some_and(|limit| depth >= limit) {
+		return Err(ErrorKind::DepthLimit.into());
+	}
+	DEPTH.with(|d| d.set(depth + 1));
+	Ok(Depth)
+}
+
+pub(super) fn allocated(bytes: usize) {
+	let heap = HEAP.with(|h| {
+		h.set(h.get() + bytes);
+		h.get()
+	});
+	PEAK.with(|p| p.set(p.get().max(heap)));
+}
+
+// Pairs made by the host were never counted as allocated, so this cannot
+// go below zero.
+pub(super) fn freed(bytes: usize) {
+	HEAP.with(|h| h.set(h.get().saturating_sub(bytes)));
+}
+
+#[cfg(test)]
+pub(super) fn heap() -> usize {
+	HEAP.with(Cell::get)
+}
+
+// Like `check`, but always reads the clock.
+pub(super) fn check_now() -> Result<(), CrustError> {
+	match DEADLINE.with(Cell::get) {
+		Some(deadline) if Instant::now() >= deadline => Err(ErrorKind::TimeLimit.into()),
+		_ => Ok(())
+	}
+}
+
+/// What a call of [`Interpreter::eval_measured`] used. `fuel` and
+/// `peak_heap` are the same every time a program is run the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+	/// The number of procedure calls made.
+	pub fuel: u64,
+	/// The most bytes held at once by pairs, procedures and environments
+	/// made during the evaluation. Strings are not counted.
+	pub peak_heap: usize,
+	pub wall_time: Duration
+}
+
+pub(super) fn measure(interp: &mut Interpreter, src: &str) -> (Result<Value, CrustError>, Usage) {
+	let start = Instant::now();
+	let fuel = FUEL.with(Cell::get);
+	let heap = HEAP.with(Cell::get);
+	let outer_peak = PEAK.with(|p| p.replace(heap));
+	let res = interp.eval_str(src);
+	let peak = PEAK.with(|p| p.replace(outer_peak.max(p.get())));
+	let usage = Usage {
+		fuel: FUEL.with(Cell::get) - fuel,
+		peak_heap: peak - heap,
+		wall_time: start.elapsed()
+	};
+	(res, usage)
+}
+
+// Puts back the limits `with_limits` replaced, also when the evaluation
+// panics.
+struct Restore(Option<Instant>, Option<u64>);
+
+impl Drop for Restore {
+	fn drop(&mut self) {
+		DEADLINE.with(|d| d.set(self.0));
+		FUEL_END.with(|f| f.set(self.1));
+	}
+}
+
+// The same for `with_depth_limit`.
+struct RestoreDepth(Option<usize>);
+
+impl Drop for RestoreDepth {
+	fn drop(&mut self) {
+		DEPTH_LIMIT.with(|d| d.set(self.0));
+	}
+}
+
+// The sooner of two limits, either of which may be missing.
+fn sooner<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+	match (a, b) {
+		(Some(a), Some(b)) => Some(a.min(b)),
+		(a, b) => a.or(b)
+	}
+}
+
+// Runs `f` with at most `time` to run and `fuel` calls to make, unless
+// limits that are set already are reached sooner. With either limit, the
+// calls `f` makes may nest at most `MAX_DEPTH` deep.
+pub(super) fn with_limits<T, F: FnOnce() -> T>(time: Option<Duration>, fuel: Option<u64>, f: F) -> T {
+	let (deadline, fuel_end) = (DEADLINE.with(Cell::get), FUEL_END.with(Cell::get));
+	let _restore = Restore(deadline, fuel_end);
+	let now = FUEL.with(Cell::get);
+	DEADLINE.with(|d| d.set(sooner(deadline, time.map(|time| Instant::now() + time))));
+	FUEL_END.with(|f| f.set(sooner(fuel_end, fuel.map(|fuel| now.saturating_add(fuel)))));
+	if time.is_some() || fuel.is_some() { with_depth_limit(f) } else { f() }
+}
+
+// Runs `f` with the calls it makes nesting at most `MAX_DEPTH` deep.
+pub(super) fn with_depth_limit<T, F: FnOnce() -> T>(f: F) -> T {
+	let limit = DEPTH_LIMIT.with(Cell::get);
+	let _restore = RestoreDepth(limit);
+	let depth = DEPTH.with(Cell::get);
+	DEPTH_LIMIT.with(|d| d.set(sooner(limit, Some(depth + MAX_DEPTH))));
+	f()
+}
+
+#[test]
+fn test_time_limit() {
+	let mut interp = super::Interpreter::new();
+	interp.time_limit(Some(Duration::from_millis(50)));
+	interp.eval_str("(define (loop n) (loop (+ n 1)))").unwrap();
+	assert_eq!(ErrorKind::TimeLimit, interp.eval_str("(define (f) 1) (loop 0)").unwrap_err().kind);
+	// Sleeping builtins stop too.
+	let err = interp.eval_str("(after 1000 (lambda () 1)) (run-scheduler)").unwrap_err();
+	assert_eq!(ErrorKind::TimeLimit, err.kind);
+	// Each evaluation gets the whole limit.
+	assert_eq!("1", interp.eval_str("(f)").unwrap().to_string());
+	interp.time_limit(None);
+	assert_eq!(None, DEADLINE.with(Cell::get));
+}
+
+#[test]
+fn test_usage() {
+	use std::mem;
+	let measure = |src| {
+		let (res, usage) = Interpreter::new().eval_measured(src);
+		(res.map(|v| v.to_string()), usage.fuel, usage.peak_heap)
+	};
+	let countdown = "(define (f n) (if (= n 0) 0 (f (- n 1)))) (f 10)";
+	let (res, fuel, _) = measure(countdown);
+	assert_eq!(Ok("0".to_string()), res);
+	assert_eq!(32, fuel);
+	assert_eq!(measure(countdown), measure(countdown));
+	let pair = mem::size_of::<super::Pair>();
+	assert_eq!((Ok("(1 2 3)".to_string()), 1, 3 * pair), measure("(list 1 2 3)"));
+	// Only the most held at once counts.
+	let (_, _, peak) = measure("(car (list 1 2 3)) (car (list 1 2 3))");
+	assert_eq!(3 * pair, peak);
+	let (res, fuel, _) = measure("(+ 1 (car '()))");
+	assert!(res.is_err());
+	assert_eq!(2, fuel);
+}
+
+#[test]
+fn test_fuel_limit() {
+	let mut interp = Interpreter::new();
+	interp.fuel_limit(Some(32));
+	let countdown = "(define (f n) (if (= n 0) 0 (f (- n 1))))";
+	// (f 10) makes 32 calls, see test_usage.
+	assert_eq!("0", interp.eval_str(&format!("{} (f 10)", countdown)).unwrap().to_string());
+	assert_eq!("1:29: out of fuel", interp.eval_str("(f 11)").unwrap_err().to_string());
+	interp.fuel_limit(None);
+	assert_eq!("0", interp.eval_str("(f 1000)").unwrap().to_string());
+}
+
+#[test]
+fn test_depth_limit() {
+	use std::thread;
+	let run = || {
+		let mut interp = Interpreter::new();
+		interp.fuel_limit(Some(u64::MAX));
+		let err = interp.eval_str("(define (f n) (+ 1 (f n))) (f 0)").unwrap_err();
+		assert_eq!(ErrorKind::DepthLimit, err.kind);
+		assert_eq!("1:20: too many nested calls", err.to_string());
+		// The calls that failed no longer count.
+		let deep = format!("(define (g n) (if (= n 0) 0 (+ 1 (g (- n 1))))) (g {})", MAX_DEPTH - 1);
+		assert_eq!((MAX_DEPTH - 1).to_string(), interp.eval_str(&deep).unwrap().to_string());
+		assert_eq!(0, DEPTH.with(Cell::get));
+		let mut interp = Interpreter::new();
+		interp.depth_limit(true);
+		assert_eq!(ErrorKind::DepthLimit, interp.eval_str("(define (f n) (+ 1 (f n))) (f 0)").unwrap_err().kind);
+		assert_eq!((MAX_DEPTH - 1).to_string(), interp.eval_str(&deep).unwrap().to_string());
+	};
+	thread::Builder::new().stack_size(STACK_SIZE).spawn(run).unwrap().join().unwrap();
+}
@@ -0,0 +1,458 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Building, reading and sending simple MIME messages, behind the `mail`
+// feature. Sending speaks plain SMTP without TLS or authentication, which
+// suits the local relay most machines that send mail from scripts have.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::base64::{base64_decode, base64_encode};
+use super::term::io_error;
+use super::{builtin, check_arity, list_items, wrong_type, BuiltinFn, CrustError, Env, Value};
+
+// Base64 in lines of 76 characters, as MIME wants it.
+fn base64_lines(bytes: &[u8]) -> String {
+	let encoded = base64_encode(bytes);
+	let lines: Vec<&str> = encoded.as_bytes().chunks(76).map(|l| std::str::from_utf8(l).unwrap()).collect();
+	lines.join("\r\n")
+}
+
+// A header value, as an RFC 2047 encoded word if it is not ASCII.
+fn header_value(s: &str) -> String {
+	if s.is_ascii() {
+		s.to_string()
+	} else {
+		format!("=?UTF-8?B?{}?=", base64_encode(s.as_bytes()))
+	}
+}
+
+// Whether `s` has no line breaks, which in a header or an SMTP command
+// would let it start another one.
+fn one_line(s: &str) -> bool {
+	!s.contains(['\r', '\n'])
+}
+
+// `s` as the inside of a quoted string.
+fn quote(s: &str) -> String {
+	s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// `message-id` is written `Message-Id`.
+fn header_name(name: &str) -> String {
+	let words: Vec<String> = name.split('-').map(|w| {
+		let mut chars = w.chars();
+		chars.next().map_or(String::new(), |c| c.to_uppercase().chain(chars).collect())
+	}).collect();
+	words.join("-")
+}
+
+struct Attachment {
+	name: String,
+	content_type: String,
+	content: String
+}
+
+// The body part headers and content of `text`.
+fn text_part(text: &str, out: &mut String) {
+	out.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+	if text.is_ascii() {
+		out.push_str("Content-Transfer-Encoding: 7bit\r\n\r\n");
+		out.push_str(&text.replace("\r\n", "\n").replace('\n', "\r\n"));
+	} else {
+		out.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+		out.push_str(&base64_lines(text.as_bytes()));
+	}
+	out.push_str("\r\n");
+}
+
+fn message(headers: &[(String, String)], body: &str, attachments: &[Attachment]) -> String {
+	let mut out = String::new();
+	for (name, value) in headers {
+		out.push_str(&format!("{}: {}\r\n", header_name(name), header_value(value)));
+	}
+	out.push_str("MIME-Version: 1.0\r\n");
+	if attachments.is_empty() {
+		text_part(body, &mut out);
+		return out;
+	}
+	// Every part is base64 in a multipart message, and "=_" never occurs in
+	// base64, so it makes the boundary safe.
+	let boundary = "=_crust";
+	out.push_str(&format!("Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n", boundary));
+	out.push_str(&format!("--{}\r\n", boundary));
+	out.push_str("Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n");
+	out.push_str(&base64_lines(body.as_bytes()));
+	out.push_str("\r\n");
+	for a in attachments {
+		out.push_str(&format!("--{}\r\n", boundary));
+		out.push_str(&format!("Content-Type: {}\r\n", a.content_type));
+		out.push_str(&format!("Content-Disposition: attachment; filename=\"{}\"\r\n", quote(&header_value(&a.name))));
+		out.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+		out.push_str(&base64_lines(a.content.as_bytes()));
+		out.push_str("\r\n");
+	}
+	out.push_str(&format!("--{}--\r\n", boundary));
+	out
+}
+
+fn string<'v>(v: &'v Value, expected: &'static str) -> Result<&'v str, CrustError> {
+	match *v {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type(expected, v))
+	}
+}
+
+// Like `string`, for a string that goes in a header or an SMTP command.
+fn line<'v>(v: &'v Value, expected: &'static str) -> Result<&'v str, CrustError> {
+	match *v {
+		Value::Str(ref s) if one_line(s) => Ok(s),
+		ref v => Err(wrong_type(expected, v))
+	}
+}
+
+// The (name value) entries of the association list `v`.
+fn headers(v: &Value) -> Result<Vec<(String, String)>, CrustError> {
+	let items = list_items(v).ok_or_else(|| wrong_type("an association list", v))?;
+	items.iter().map(|item| match list_items(item).as_deref() {
+		Some([Value::Symbol(name), Value::Str(value)]) if one_line(name) && one_line(value) =>
+			Ok((name.to_string(), value.to_string())),
+		_ => Err(wrong_type("a (name \"value\") header without line breaks", item))
+	}).collect()
+}
+
+// (mime-message headers body [attachments]) builds a message from an
+// association list of headers, like ((from "a@example.com") (subject
+// "Hi")), a text body and a list of (file-name content-type content)
+// attachments.
+fn builtin_mime_message(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("mime-message", args, 2, Some(3))?;
+	let headers = headers(&args[0])?;
+	let body = string(&args[1], "a string")?;
+	let mut attachments = Vec::new();
+	if let Some(v) = args.get(2) {
+		for item in list_items(v).ok_or_else(|| wrong_type("a list of attachments", v))? {
+			match list_items(&item).as_deref() {
+				Some([Value::Str(name), Value::Str(content_type), Value::Str(content)])
+					if one_line(name) && one_line(content_type) => attachments.push(Attachment {
+					name: name.to_string(),
+					content_type: content_type.to_string(),
+					content: content.to_string()
+				}),
+				_ => return Err(wrong_type("an attachment (file-name content-type content) with the name and type on one line", &item))
+			}
+		}
+	}
+	Ok(Value::Str(Rc::from(message(&headers, body, &attachments))))
+}
+
+fn hex(b: u8) -> Option<u8> {
+	(b as char).to_digit(16).map(|d| d as u8)
+}
+
+// Decodes the text of a Q-encoded word, where `_` is a space and `=XX` a
+// byte.
+fn q_decode(text: &str) -> Option<Vec<u8>> {
+	let mut bytes = Vec::new();
+	let mut it = text.bytes();
+	while let Some(b) = it.next() {
+		match b {
+			b'_' => bytes.push(b' '),
+			b'=' => bytes.push(hex(it.next()?)? << 4 | hex(it.next()?)?),
+			b => bytes.push(b)
+		}
+	}
+	Some(bytes)
+}
+
+// Decodes `charset?encoding?text`, the inside of an encoded word.
+fn decode_word(word: &str) -> Option<String> {
+	let mut parts = word.splitn(3, '?');
+	let (charset, encoding, text) = (parts.next()?, parts.next()?, parts.next()?);
+	let bytes = match encoding {
+		"B" | "b" => base64_decode(text)?,
+		"Q" | "q" => q_decode(text)?,
+		_ => return None
+	};
+	// Anything but Latin-1 is taken to be UTF-8.
+	if charset.eq_ignore_ascii_case("iso-8859-1") {
+		Some(bytes.iter().map(|&b| b as char).collect())
+	} else {
+		Some(String::from_utf8_lossy(&bytes).into_owned())
+	}
+}
+
+// Decodes the RFC 2047 encoded words in a header value.
+fn decode_words(value: &str) -> String {
+	let mut res = String::new();
+	let mut rest = value;
+	while let Some(start) = rest.find("=?") {
+		let word = &rest[start + 2..];
+		// The word ends at the first ?= after the encoding.
+		let end = word.match_indices('?').nth(1).and_then(|(i, _)| word[i + 1..].find("?=").map(|j| i + 1 + j));
+		match end.and_then(|end| Some((decode_word(&word[..end])?, end))) {
+			Some((text, end)) => {
+				res.push_str(&rest[..start]);
+				res.push_str(&text);
+				rest = &word[end + 2..];
+			}
+			None => {
+				res.push_str(&rest[..start + 2]);
+				rest = word;
+			}
+		}
+	}
+	res.push_str(rest);
+	res
+}
+
+// The headers, with names in lower case, and the decoded body of a
+// message. Multipart bodies are left as they are.
+fn parse(message: &str) -> (Vec<(String, String)>, String) {
+	let message = message.replace("\r\n", "\n");
+	let (head, body) = message.split_once("\n\n").unwrap_or((&message, ""));
+	let mut headers: Vec<(String, String)> = Vec::new();
+	for line in head.lines() {
+		if line.starts_with([' ', '\t']) {
+			if let Some(last) = headers.last_mut() {
+				last.1.push(' ');
+				last.1.push_str(line.trim());
+			}
+		} else if let Some((name, value)) = line.split_once(':') {
+			headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+		}
+	}
+	for header in &mut headers {
+		header.1 = decode_words(&header.1);
+	}
+	let base64 = headers.iter().any(|(name, value)| name == "content-transfer-encoding" && value.eq_ignore_ascii_case("base64"));
+	let body = match base64_decode(body) {
+		Some(bytes) if base64 => String::from_utf8_lossy(&bytes).into_owned(),
+		_ => body.to_string()
+	};
+	(headers, body)
+}
+
+// (mime-parse message) returns ((headers ((name "value") ...)) (body "..."))
+// with header names as lower-case symbols.
+fn builtin_mime_parse(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("mime-parse", args, 1, Some(1))?;
+	let (headers, body) = parse(string(&args[0], "a string")?);
+	let headers = headers.into_iter()
+		.map(|(name, value)| Value::list(vec![Value::Symbol(Rc::from(name)), Value::Str(Rc::from(value))]))
+		.collect();
+	Ok(Value::list(vec![
+		Value::list(vec![Value::Symbol(Rc::from("headers")), Value::list(headers)]),
+		Value::list(vec![Value::Symbol(Rc::from("body")), Value::Str(Rc::from(body))])
+	]))
+}
+
+struct Smtp {
+	reader: BufReader<TcpStream>,
+	writer: TcpStream
+}
+
+impl Smtp {
+	// Reads a possibly multi-line reply, failing unless its code is one of
+	// `expected`.
+	fn reply(&mut self, expected: &[u32]) -> io::Result<()> {
+		loop {
+			let mut line = String::new();
+			if self.reader.read_line(&mut line)? == 0 {
+				return Err(io::Error::other("the SMTP server closed the connection"));
+			}
+			let code = line.get(..3).and_then(|c| c.parse().ok());
+			if line.as_bytes().get(3) == Some(&b'-') {
+				continue;
+			}
+			return match code {
+				Some(code) if expected.contains(&code) => Ok(()),
+				_ => Err(io::Error::other(format!("the SMTP server replied: {}", line.trim_end())))
+			};
+		}
+	}
+
+	fn command(&mut self, command: &str, expected: &[u32]) -> io::Result<()> {
+		write!(self.writer, "{}\r\n", command)?;
+		self.reply(expected)
+	}
+}
+
+fn send(host: &str, port: u16, from: &str, to: &[&str], message: &str) -> io::Result<()> {
+	let stream = TcpStream::connect((host, port))?;
+	stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+	let mut smtp = Smtp { reader: BufReader::new(stream.try_clone()?), writer: stream };
+	smtp.reply(&[220])?;
+	smtp.command("EHLO localhost", &[250])?;
+	smtp.command(&format!("MAIL FROM:<{}>", from), &[250])?;
+	for to in to {
+		smtp.command(&format!("RCPT TO:<{}>", to), &[250, 251])?;
+	}
+	smtp.command("DATA", &[354])?;
+	let mut data = String::new();
+	for line in message.lines() {
+		// Lines starting with a dot get another one, so that none of them
+		// ends the data early.
+		if line.starts_with('.') {
+			data.push('.');
+		}
+		data.push_str(line);
+		data.push_str("\r\n");
+	}
+	data.push_str(".\r\n");
+	smtp.writer.write_all(data.as_bytes())?;
+	smtp.reply(&[250])?;
+	let _ = smtp.command("QUIT", &[221]);
+	Ok(())
+}
+
+// (smtp-send host port from to message) sends `message` to `to`, an
+// address or a list of them.
+fn builtin_smtp_send(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("smtp-send", args, 5, Some(5))?;
+	let host = string(&args[0], "a host name")?;
+	let port = match args[1] {
+		Value::Integer(n @ 1..=65535) => n as u16,
+		ref v => return Err(wrong_type("a port number", v))
+	};
+	let from = line(&args[2], "an address without line breaks")?;
+	let to: Vec<String> = match args[3] {
+		Value::Str(_) => vec![line(&args[3], "an address without line breaks")?.to_string()],
+		ref v => {
+			let items = list_items(v).filter(|items| !items.is_empty())
+				.ok_or_else(|| wrong_type("an address or a list of them", v))?;
+			items.iter().map(|item| line(item, "an address without line breaks").map(str::to_string)).collect::<Result<_, _>>()?
+		}
+	};
+	let to: Vec<&str> = to.iter().map(String::as_str).collect();
+	send(host, port, from, &to, string(&args[4], "a message")?).map_err(io_error)?;
+	Ok(Value::Unspecified)
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("mime-message", builtin_mime_message),
+		("mime-parse", builtin_mime_parse),
+		("smtp-send", builtin_smtp_send),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_mime() {
+	let headers = vec![("from".to_string(), "ada@example.com".to_string()), ("subject".to_string(), "Résumé".to_string())];
+	let msg = message(&headers, "Hello\n.\n", &[]);
+	assert_eq!("From: ada@example.com\r\nSubject: =?UTF-8?B?UsOpc3Vtw6k=?=\r\nMIME-Version: 1.0\r\n\
+	            Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 7bit\r\n\r\nHello\r\n.\r\n\r\n",
+	           msg);
+	let (parsed, body) = parse(&msg);
+	assert_eq!(("subject".to_string(), "Résumé".to_string()), parsed[1]);
+	assert_eq!("Hello\n.\n\n", body);
+	let attachment = Attachment { name: "a.csv".to_string(), content_type: "text/csv".to_string(), content: "x,y".to_string() };
+	let msg = message(&headers, "Hej då", &[attachment]);
+	assert!(msg.contains("Content-Type: multipart/mixed; boundary=\"=_crust\"\r\n"));
+	assert!(msg.contains("filename=\"a.csv\"\r\nContent-Transfer-Encoding: base64\r\n\r\neCx5\r\n--=_crust--\r\n"));
+	assert_eq!("Hej då", parse("Content-Transfer-Encoding: base64\n\nSGVqIGTD\n pQ==").1);
+	assert_eq!("caf\u{e9} =?x", decode_words("=?UTF-8?Q?caf=C3=A9_?==?x"));
+	assert_eq!("a caf\u{e9}", decode_words("a =?iso-8859-1?q?caf=E9?="));
+	let folded = parse("Subject: a\r\n  b\r\nX-Y: z\r\n\r\n").0;
+	assert_eq!(vec![("subject".to_string(), "a b".to_string()), ("x-y".to_string(), "z".to_string())], folded);
+	assert_eq!("Message-Id", header_name("message-id"));
+	let attachment = Attachment { name: "a \"b\".txt".to_string(), content_type: "text/plain".to_string(), content: String::new() };
+	assert!(message(&headers, "", &[attachment]).contains("filename=\"a \\\"b\\\".txt\"\r\n"));
+}
+
+#[test]
+fn test_mail_line_breaks() {
+	use super::ErrorKind;
+	let mut interp = super::Interpreter::new();
+	let mut fails = |src: &str| match interp.eval_str(src).unwrap_err().kind {
+		ErrorKind::WrongType { .. } => (),
+		kind => panic!("{}: {:?}", src, kind)
+	};
+	fails("(mime-message '((subject \"Hi\\r\\nBcc: eve@example.com\")) \"\")");
+	fails("(mime-message '() \"\" '((\"a.txt\\r\\nX-Y: z\" \"text/plain\" \"\")))");
+	fails("(mime-message '() \"\" '((\"a.txt\" \"text/plain\\nX-Y: z\" \"\")))");
+	// Symbols read from source cannot have line breaks, but those the host
+	// makes can.
+	let header = Value::list(vec![Value::list(vec![Value::Symbol(Rc::from("x\ny")), Value::Str(Rc::from("z"))])]);
+	assert!(headers(&header).is_err());
+	// The addresses are checked before connecting.
+	fails("(smtp-send \"127.0.0.1\" 25 \"a@example.com>\\r\\nRCPT TO:<eve@example.com\" \"b@example.com\" \"\")");
+	fails("(smtp-send \"127.0.0.1\" 25 \"a@example.com\" \"b@example.com\\nDATA\" \"\")");
+	fails("(smtp-send \"127.0.0.1\" 25 \"a@example.com\" '(\"b@example.com\" \"c\\r\") \"\")");
+}
+
+#[test]
+fn test_smtp() {
+	use std::net::TcpListener;
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let server = std::thread::spawn(move || {
+		let (stream, _) = listener.accept().unwrap();
+		let mut reader = BufReader::new(stream.try_clone().unwrap());
+		let mut writer = stream;
+		let mut transcript = String::new();
+		writer.write_all(b"220 test\r\n").unwrap();
+		let mut in_data = false;
+		loop {
+			let mut line = String::new();
+			if reader.read_line(&mut line).unwrap() == 0 {
+				break;
+			}
+			transcript.push_str(&line);
+			let reply: &[u8] = match line.as_str() {
+				".\r\n" if in_data => { in_data = false; b"250 queued\r\n" }
+				_ if in_data => continue,
+				"EHLO localhost\r\n" => b"250-test\r\n250 8BITMIME\r\n",
+				"DATA\r\n" => { in_data = true; b"354 go\r\n" }
+				"QUIT\r\n" => b"221 bye\r\n",
+				_ => b"250 ok\r\n"
+			};
+			writer.write_all(reply).unwrap();
+		}
+		transcript
+	});
+	let mut interp = super::Interpreter::new();
+	let program = format!("(smtp-send \"127.0.0.1\" {} \"a@example.com\" '(\"b@example.com\" \"c@example.com\") \
+	                       (mime-message '((subject \"Hi\")) \".dot\"))", port);
+	interp.eval_str(&program).unwrap();
+	assert_eq!("EHLO localhost\r\nMAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nRCPT TO:<c@example.com>\r\n\
+	            DATA\r\nSubject: Hi\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+	            Content-Transfer-Encoding: 7bit\r\n\r\n..dot\r\n.\r\nQUIT\r\n",
+	           server.join().unwrap());
+}
+
+#[test]
+fn test_smtp_rejection() {
+	use std::net::TcpListener;
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let server = std::thread::spawn(move || {
+		let (mut stream, _) = listener.accept().unwrap();
+		stream.write_all(b"554 go away\r\n").unwrap();
+	});
+	let program = format!("(smtp-send \"127.0.0.1\" {} \"a@example.com\" \"b@example.com\" \"x\")", port);
+	let error = super::Interpreter::new().eval_str(&program).unwrap_err();
+	server.join().unwrap();
+	assert_eq!("1:1: the SMTP server replied: 554 go away", error.to_string());
+}
@@ -1,187 +1,316 @@
-# Copyright 2016 Erik Duveblad
-#
-# This file is part of crust.
-#
-# crust is free software: you can redistribute it and/or modify
-# it under the terms of the GNU General Public License as published by
-# the Free Software Foundation, either version 3 of the License, or
-# (at your option) any later version.
-#
-# crust is distributed in the hope that it will be useful,
-# but WITHOUT ANY WARRANTY; without even the implied warranty of
-# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
-# GNU General Public License for more details.
-#
-# You should have received a copy of the GNU General Public License
-# along with crust.  If not, see <http://www.gnu.org/licenses/>.
-
-use std::str::FromStr;
-use std::collections::HashMap;
-
-#[derive(Debug, PartialEq, Eq)]
-enum Token<'a> {
-	LeftParen,
-    RightParen,
-	Number(u64),
-    Symbol(&'a str)
-}
-
-#[derive(Debug)]
-struct Fun<'a> {
-	name: &'a str,
-	args: Vec<Box<Node<'a>>>
-}
-
-#[derive(Debug)]
-enum Node<'a> {
-	Symbol(&'a str),
-	Application(Fun<'a>),
-	Number(u64)	
-}
-
-
-fn separate<'a>(s: &'a str, separators: &str) -> Vec<&'a str> {
-	let mut v = Vec::new();
-	let mut from = 0;
-	let mut to = 0;
-	let mut char_indices = s.char_indices();
-	while let Some((i, c)) = char_indices.next() {
-		if separators.contains(c) {
-			if to > from {
-				v.push(&s[from..to]);
-			}
-			v.push(&s[i..i+1]);
-			to = i + 1;
-			from = to;
-        } else {
-			to += 1;
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+extern crate crust;
+
+use std::fs;
+use std::io::{self, Write};
+use std::panic;
+use std::process::{self, Command, Stdio};
+use std::thread;
+
+use crust::{CrustError, ErrorKind, Interpreter, Value};
+
+// The fuel of a program run with --pure but without --fuel.
+const PURE_FUEL: u64 = 10_000_000;
+
+// How programs are evaluated, as set by the options before the command.
+#[derive(Clone, Copy)]
+struct Options {
+	cache: bool,
+	pure: bool,
+	fuel: Option<u64>
+}
+
+// Writes `text` to stdout, except that a reader that has gone away,
+// as in `crust -e ... | head -1`, is not an error: nothing more would be
+// read anyway. Any other failure to write ends crust.
+fn write_stdout(text: &str) {
+	let mut stdout = io::stdout();
+	match stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush()) {
+		Err(ref e) if e.kind() != io::ErrorKind::BrokenPipe => {
+			eprintln!("crust: stdout: {}", e);
+			process::exit(1);
 		}
+		_ => ()
+	}
+}
+
+fn interpreter(options: Options) -> Interpreter {
+	let mut interp = if options.pure { Interpreter::pure() } else { Interpreter::new() };
+	interp.use_cache(options.cache);
+	interp.depth_limit(true);
+	interp.fuel_limit(options.fuel.or(if options.pure { Some(PURE_FUEL) } else { None }));
+	interp
+}
+
+fn run(source: &str, options: Options) -> Result<(), CrustError> {
+	match interpreter(options).eval_str(source) {
+		Ok(Value::Unspecified) => (),
+		Ok(v) => write_stdout(&format!("{}\n", v)),
+		Err(CrustError { kind: ErrorKind::Exit(code), .. }) => process::exit(code),
+		Err(e) => return Err(e)
 	}
-	if to > from {
-		v.push(&s[from..to]);
+	Ok(())
+}
+
+// Evaluates `source` and prints the outcome as JSON, exiting with a
+// non-zero status if it failed.
+fn run_json(source: &str, options: Options) -> Result<(), CrustError> {
+	let (res, usage) = interpreter(options).eval_measured(source);
+	write_stdout(&crust::emit_result(&res, &usage));
+	match res {
+		Ok(_) => Ok(()),
+		Err(CrustError { kind: ErrorKind::Exit(code), .. }) => process::exit(code),
+		Err(_) => process::exit(1)
 	}
-	return v;
-}
-
-#[test]
-fn test_separate() {
-	assert_eq!(vec!["apa"], separate("apa", "()"));
-	assert_eq!(vec!["("], separate("(", "()"));
-	assert_eq!(vec!["(", ")"], separate("()", "()"));
-	assert_eq!(vec!["(", "apa", ")"], separate("(apa)", "()"));
-}
-
-fn lex<'a>(s: &'a str) -> Vec<Token<'a>> {
-	s.trim().split_whitespace().flat_map(|w| separate(w, "()")).map(|w| {
-		match w {
-			"(" 	=> Token::LeftParen,
-			")" 	=> Token::RightParen,
-			_	    => match u64::from_str(w) {
-					       Ok(n) => Token::Number(n),
-						   _     => Token::Symbol(w)		
-					   }
+}
+
+fn dump_ast(source: &str) -> Result<(), CrustError> {
+	write_stdout(&crust::emit_ast(source)?);
+	Ok(())
+}
+
+// Runs `f` on the contents of the file at `path`, prefixing errors with
+// the file name. Invalid UTF-8 in the file is an error unless `lossy` is
+// set.
+fn with_file<F: FnOnce(&str) -> Result<(), CrustError>>(path: &str, lossy: bool, f: F) -> Result<(), String> {
+	let located = |e: CrustError| match e.pos {
+		Some(_) => format!("{}:{}", path, e),
+		None => format!("{}: {}", path, e)
+	};
+	match fs::read(path) {
+		Ok(bytes) => {
+			let source = crust::decode(&bytes, lossy).map_err(located)?;
+			f(&source).map_err(located)
 		}
-	}).collect()
+		Err(e) => Err(format!("{}: {}", path, e))
+	}
 }
 
-fn parse_exp<'a>(tokens: &'a [Token]) -> (usize, Node<'a>) {
-	if tokens.len() == 0 {
-		panic!("No tokens!");
+// Prints the result of a refactoring of the file at `path`: the rewritten
+// file, or a unified diff against the original if `diff` is set.
+fn print_refactored(path: &str, source: &str, refactored: &str, diff: bool) {
+	if diff {
+		write_stdout(&crust::refactor::unified_diff(path, source, refactored));
+	} else {
+		write_stdout(refactored);
 	}
-	match tokens[0] {
-		Token::LeftParen => {
-			if tokens.len() < 3 {
-				panic!("Too few tokens!");
-			}
+}
 
-			let name = match tokens[1] {
-				Token::Symbol(s) => s,
-				_	         => panic!("Unexpected token: {:?}", tokens[1])
-			};
-			let mut args = Vec::new();
-			let mut i = 2;
-			while tokens[i] != Token::RightParen {
-				let (n, arg) = parse_exp(&tokens[i..]);
-				args.push(Box::new(arg));
-				i += n;
-			}
-			(i + 1, Node::Application(Fun { name: name, args: args }))
+fn refactor_command(args: &[&str], lossy: bool) -> Result<(), String> {
+	let (diff, args) = match args.split_first() {
+		Some((&"--diff", rest)) => (true, rest),
+		_ => (false, args)
+	};
+	match *args {
+		["rename", old, new, path] => with_file(path, lossy, |source| {
+			let res = crust::refactor::rename(source, old, new)?;
+			print_refactored(path, source, &res, diff);
+			Ok(())
+		}),
+		["extract-function", name, pos, path] => {
+			let pos = crust::refactor::parse_pos(pos).ok_or_else(|| format!("crust: invalid position '{}'", pos))?;
+			with_file(path, lossy, |source| {
+				let res = crust::refactor::extract_function(source, name, pos)?;
+				print_refactored(path, source, &res, diff);
+				Ok(())
+			})
 		}
-        	Token::Number(n) => (1, Node::Number(n)),
-		Token::Symbol(s) => (1, Node::Symbol(s)),
-		_ 				  => panic!("Unexpected token: {:?}", tokens[0])
-	}		
-}
-
-fn parse<'a>(tokens: &'a Vec<Token>) -> Vec<Node<'a>> {
-	let mut n = 0;
-	let mut v = Vec::new();
-	while n < tokens.len() {
-		let (nn, root) = parse_exp(&tokens[n..]);
-		v.push(root);
-		n += nn;
-		
+		_ => usage()
 	}
-	assert!(n == tokens.len(), "Did not parse all tokens");
-	return v;
-}
-
-fn eval<'a>(root: &'a Node<'a>, env: &mut HashMap<&'a str, &'a Node<'a>>) -> u64 {
-	match root {
-		&Node::Symbol(name) => eval(env.get(name).unwrap(), env),
-		&Node::Number(n) => n,
-		&Node::Application(ref f) => {
-			match f.name {
-				"+" => {
-			let args = f.args.iter().map(|a| eval(&*a, env));
-			args.fold(0, |acc, a| acc + a)},
-				"-" => {
-					let args = f.args.iter().map(|a| eval(&*a, env));
-					args.fold(0, |acc, a| acc - a)},
-				"*" => {
-					let args = f.args.iter().map(|a| eval(&*a, env));
-					args.fold(1, |acc, a| acc * a)},
-				"/" => {
-					let mut args = f.args.iter().map(|a| eval(&*a, env));
-					let mut res = args.next().unwrap();
-					for n in args {
-						res = res / n;
-					}	
-					res
-				},
-				"define" => {
-					let name = match *f.args[0] {
-						Node::Symbol(s) => s,
-						ref n @ _		 => panic!("Unexpected node: {:?}", n)
-					};
-					env.insert(name, &*f.args[1]);
-					0
-				},
-				_	=> panic!("Uknown function")
+}
+
+// Reduces the file at `path` for as long as the shell command `check`
+// succeeds, with `{}` in the command replaced by the name of a file
+// holding the candidate program.
+fn reduce_command(path: &str, check: &str, lossy: bool) -> Result<(), String> {
+	let file = std::env::temp_dir().join(format!("crust-reduce-{}.crust", process::id()));
+	let command = check.replace("{}", &format!("'{}'", file.display().to_string().replace('\'', "'\\''")));
+	let res = with_file(path, lossy, |source| {
+		let reduced = crust::reduce::reduce(source, &mut |candidate| {
+			fs::write(&file, candidate).is_ok() &&
+				Command::new("sh").arg("-c").arg(&command)
+					.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null())
+					.status()
+					.is_ok_and(|status| status.success())
+		})?;
+		write_stdout(&reduced);
+		Ok(())
+	});
+	let _ = fs::remove_file(&file);
+	res
+}
+
+// Writes the syntax tree of the file at `path` as a DOT graph to the file
+// `out`, or to stdout if there is none.
+fn viz_command(path: &str, out: Option<&str>, lossy: bool) -> Result<(), String> {
+	let mut graph = String::new();
+	with_file(path, lossy, |source| {
+		graph = crust::dot::ast(source)?;
+		Ok(())
+	})?;
+	match out {
+		Some(out) => fs::write(out, graph).map_err(|e| format!("{}: {}", out, e)),
+		None => {
+			write_stdout(&graph);
+			Ok(())
+		}
+	}
+}
+
+// Runs tasks from a task file, by default tasks.crust, or lists them with
+// --list.
+fn task_command(args: &[&str], lossy: bool, options: Options) -> Result<(), String> {
+	let (path, args) = match *args {
+		["--file", path, ref rest @ ..] => (path, rest),
+		_ => ("tasks.crust", args)
+	};
+	match *args {
+		["--list"] => with_file(path, lossy, |source| {
+			for name in crust::task::list(source)? {
+				write_stdout(&format!("{}\n", name));
+			}
+			Ok(())
+		}),
+		ref targets if !targets.iter().any(|t| t.starts_with('-')) => with_file(path, lossy, |source| {
+			let res = crust::task::run(&mut interpreter(options), source, targets, &mut |name, run| {
+				if run {
+					eprintln!("crust task: {}", name);
+				} else {
+					eprintln!("crust task: {} is up to date", name);
+				}
+			});
+			match res {
+				Err(CrustError { kind: ErrorKind::Exit(code), .. }) => process::exit(code),
+				res => res
+			}
+		}),
+		_ => usage()
+	}
+}
+
+// Prints a report of the checks run by crust::doctor and returns the exit
+// code, which is 1 if any failed.
+fn doctor_command() -> i32 {
+	// Failing checks are in the report, keep the default hook from
+	// printing a panic message in the middle of it. Nothing else runs
+	// on another thread yet.
+	let hook = panic::take_hook();
+	panic::set_hook(Box::new(|_| {}));
+	let checks = crust::doctor();
+	panic::set_hook(hook);
+	let mut failures = 0;
+	for check in &checks {
+		match check.failure {
+			None => write_stdout(&format!("ok      {}\n", check.name)),
+			Some(ref failure) => {
+				failures += 1;
+				write_stdout(&format!("FAILED  {}: {}\n", check.name, failure));
 			}
 		}
 	}
+	write_stdout(&format!("\n{} checks, {} failed\n", checks.len(), failures));
+	if failures == 0 { 0 } else { 1 }
 }
 
-fn eval_program(roots: &[Node]) -> u64 {
-  let mut res = 0;
-  let mut env = HashMap::new();
-  for root in roots {
-    println!("root: {:?}", root);
-    res = eval(root, &mut env);
-    println!("res is {}", res);
-  }
-  return res;
+fn usage() -> ! {
+	eprintln!("Usage: crust                  start a REPL");
+	eprintln!("       crust <file>           evaluate a file");
+	eprintln!("       crust -e <program>     evaluate a program given as an argument");
+	eprintln!("       crust --output=json <file> | -e <program>");
+	eprintln!("                              evaluate, printing the outcome as JSON");
+	eprintln!("       crust --emit=ast <file>");
+	eprintln!("                              print the syntax tree of a file as JSON");
+	eprintln!("       crust refactor [--diff] rename <old> <new> <file>");
+	eprintln!("       crust refactor [--diff] extract-function <name> <line>:<col> <file>");
+	eprintln!("                              rewrite a file, printing the result or a diff");
+	eprintln!("       crust reduce <file> --check <command>");
+	eprintln!("                              shrink a file while a shell command, run with {{}}");
+	eprintln!("                              replaced by a file name, keeps succeeding");
+	eprintln!("       crust viz <file> [-o <out>]");
+	eprintln!("                              write the syntax tree of a file as a DOT graph");
+	eprintln!("       crust task [--file <file>] [--list | <task>...]");
+	eprintln!("                              run tasks and what they depend on from tasks.crust");
+	eprintln!("       crust doctor           run the self-test suite");
+	eprintln!();
+	eprintln!("--lossy before a command that reads a file replaces invalid UTF-8 in it");
+	eprintln!("instead of failing. Before a command that evaluates a program, or alone");
+	eprintln!("for the REPL,");
+	eprintln!("  --no-cache    makes `cached` compute every value again");
+	eprintln!("  --pure        leaves out the builtins with effects, and limits the program");
+	eprintln!("                to {} procedure calls", PURE_FUEL);
+	eprintln!("  --fuel=<n>    limits the program to n procedure calls");
+	process::exit(2);
 }
 
 fn main() {
-	let mut args = std::env::args();
-	if args.len() != 2 {
-		panic!("Usage: crust <program>");
+	// Calls nest up to crust::MAX_DEPTH deep, which takes more stack than
+	// the main thread has.
+	let cli = thread::Builder::new().stack_size(crust::STACK_SIZE).spawn(cli).unwrap();
+	if cli.join().is_err() {
+		process::exit(101);
 	}
+}
 
-	let source = args.nth(1).unwrap();
-	let tokens = lex(&source);
-	let asts = parse(&tokens);
-	println!("{}", eval_program(&asts));
+fn cli() {
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	let args: Vec<&str> = args.iter().map(String::as_str).collect();
+	let (mut lossy, mut args) = (false, &args[..]);
+	let mut options = Options { cache: true, pure: false, fuel: None };
+	loop {
+		match args.split_first() {
+			Some((&"--lossy", rest)) if !rest.is_empty() => lossy = true,
+			Some((&"--no-cache", _)) => options.cache = false,
+			Some((&"--pure", _)) => options.pure = true,
+			Some((arg, _)) if arg.starts_with("--fuel=") => {
+				match arg["--fuel=".len()..].parse() {
+					Ok(fuel) => options.fuel = Some(fuel),
+					Err(_) => usage()
+				}
+			}
+			_ => break
+		}
+		args = &args[1..];
+	}
+	let res = match *args {
+		[] => {
+			let stdin = io::stdin();
+			if let Err(e) = interpreter(options).repl(stdin.lock(), io::stdout()) {
+				eprintln!("crust: {}", e);
+				process::exit(1);
+			}
+			return;
+		}
+		["doctor"] => process::exit(doctor_command()),
+		["-e", source] => run(source, options).map_err(|e| format!("crust: {}", e)),
+		["--output=json", "-e", source] => run_json(source, options).map_err(|e| format!("crust: {}", e)),
+		["--output=json", path] => with_file(path, lossy, |source| run_json(source, options)),
+		["--emit=ast", path] => with_file(path, lossy, dump_ast),
+		["refactor", ref rest @ ..] => refactor_command(rest, lossy),
+		["task", ref rest @ ..] => task_command(rest, lossy, options),
+		["viz", path] => viz_command(path, None, lossy),
+		["viz", path, "-o", out] | ["viz", "-o", out, path] => viz_command(path, Some(out), lossy),
+		["reduce", path, "--check", check] | ["reduce", "--check", check, path] => reduce_command(path, check, lossy),
+		[path] if !path.starts_with('-') => with_file(path, lossy, |source| run(source, options)),
+		_ => usage()
+	};
+	if let Err(msg) = res {
+		eprintln!("{}", msg);
+		process::exit(1);
+	}
 }
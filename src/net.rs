@@ -0,0 +1,115 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Network information for scripts that check on hosts, behind the `net`
+// feature.
+
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+use std::time::Duration;
+
+use super::{builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+fn host_arg(v: &Value) -> Result<&str, CrustError> {
+	match *v {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type("a host name", v))
+	}
+}
+
+fn addresses(host: &str, port: u16) -> Result<Vec<SocketAddr>, CrustError> {
+	let addrs = (host, port).to_socket_addrs().map_err(|e| ErrorKind::Io(format!("{}: {}", host, e)))?;
+	let mut unique: Vec<SocketAddr> = Vec::new();
+	for addr in addrs {
+		if !unique.contains(&addr) {
+			unique.push(addr);
+		}
+	}
+	Ok(unique)
+}
+
+// (resolve-host "example.com") is the list of addresses of a host, as
+// strings.
+fn builtin_resolve_host(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("resolve-host", args, 1, Some(1))?;
+	let addrs = addresses(host_arg(&args[0])?, 0)?;
+	Ok(Value::list(addrs.iter().map(|a| Value::Str(Rc::from(a.ip().to_string()))).collect()))
+}
+
+// (my-ip) is the address of the interface that traffic to the internet
+// leaves through. Connecting a UDP socket picks the interface without
+// sending anything.
+fn builtin_my_ip(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("my-ip", args, 0, Some(0))?;
+	let ip = UdpSocket::bind("0.0.0.0:0")
+		.and_then(|socket| socket.connect("192.0.2.1:9").map(|_| socket))
+		.and_then(|socket| socket.local_addr())
+		.map(|addr| addr.ip())
+		.map_err(|e| ErrorKind::Io(format!("my-ip: {}", e)))?;
+	Ok(Value::Str(Rc::from(ip.to_string())))
+}
+
+// (port-open? host port [timeout-ms]) is whether a TCP connection to the
+// port can be made within the timeout, one second by default, on any of
+// the host's addresses.
+fn builtin_port_open(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("port-open?", args, 2, Some(3))?;
+	let host = host_arg(&args[0])?;
+	let port = match args[1] {
+		Value::Integer(p) if (1..=65535).contains(&p) => p as u16,
+		ref v => return Err(wrong_type("a port number", v))
+	};
+	let timeout = match args.get(2) {
+		None => Duration::from_secs(1),
+		Some(&Value::Integer(ms)) if ms > 0 => Duration::from_millis(ms as u64),
+		Some(v) => return Err(wrong_type("a positive integer", v))
+	};
+	// A name that does not resolve has no open ports.
+	let addrs = match host.parse::<IpAddr>() {
+		Ok(ip) => vec![SocketAddr::new(ip, port)],
+		Err(_) => addresses(host, port).unwrap_or_default()
+	};
+	Ok(Value::Boolean(addrs.iter().any(|addr| TcpStream::connect_timeout(addr, timeout).is_ok())))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("resolve-host", builtin_resolve_host),
+		("my-ip", builtin_my_ip),
+		("port-open?", builtin_port_open),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_net() {
+	let s = |s: &str| Value::Str(Rc::from(s));
+	let addrs = builtin_resolve_host(&[s("localhost")]).unwrap().to_string();
+	assert!(addrs.contains("127.0.0.1") || addrs.contains("::1"), "{}", addrs);
+	assert!(builtin_resolve_host(&[s("no-such-host.invalid")]).is_err());
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port() as i64;
+	let open = |host: &str, port: i64| builtin_port_open(&[s(host), Value::Integer(port), Value::Integer(500)]).unwrap().is_true();
+	assert!(open("127.0.0.1", port));
+	drop(listener);
+	assert!(!open("127.0.0.1", port));
+	assert!(!open("no-such-host.invalid", 80));
+	assert!(builtin_port_open(&[s("localhost"), Value::Integer(0)]).is_err());
+}
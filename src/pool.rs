@@ -0,0 +1,221 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// A pool of worker threads evaluating programs for a host. Each worker
+// sets up an interpreter once and runs every program in a fork of it, so
+// programs cannot see each other's definitions. Values cannot leave the
+// thread that made them, so a result is the value as it is printed.
+// Whatever limits the host sets, calls in a program nest at most
+// `MAX_DEPTH` deep, which the stack of a worker has room for, so that one
+// program recursing too deeply fails rather than taking the process down.
+
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use super::{limit, CrustError, ErrorKind, Interpreter};
+
+type Outcome = Result<String, CrustError>;
+
+// Where a worker puts the outcome of a program for its `Eval`.
+struct Slot {
+	state: Mutex<(Option<Outcome>, Option<Waker>)>,
+	done: Condvar
+}
+
+impl Slot {
+	fn finish(&self, outcome: Outcome) {
+		let mut state = self.state.lock().unwrap();
+		state.0 = Some(outcome);
+		if let Some(waker) = state.1.take() {
+			waker.wake();
+		}
+		self.done.notify_all();
+	}
+}
+
+struct Task {
+	src: String,
+	slot: Arc<Slot>
+}
+
+/// The pending result of [`Pool::eval`]: the printed value of the
+/// program's last expression, or the error that stopped it. It can be
+/// awaited, or waited for with [`Eval::wait`].
+pub struct Eval {
+	slot: Arc<Slot>
+}
+
+impl Eval {
+	/// Blocks until the program has been evaluated.
+	pub fn wait(self) -> Result<String, CrustError> {
+		let mut state = self.slot.state.lock().unwrap();
+		loop {
+			if let Some(outcome) = state.0.take() {
+				return outcome;
+			}
+			state = self.slot.done.wait(state).unwrap();
+		}
+	}
+}
+
+impl Future for Eval {
+	type Output = Result<String, CrustError>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let mut state = self.slot.state.lock().unwrap();
+		match state.0.take() {
+			Some(outcome) => Poll::Ready(outcome),
+			None => {
+				state.1 = Some(cx.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+/// A fixed number of threads, each with its own interpreter, that evaluate
+/// programs in the order they are queued. Dropping the pool lets the
+/// programs already queued finish.
+pub struct Pool {
+	queue: Option<mpsc::Sender<Task>>,
+	workers: Vec<thread::JoinHandle<()>>
+}
+
+impl Pool {
+	/// Starts `threads` workers, each calling `setup` on a new interpreter
+	/// to register procedures, evaluate a prelude or set a
+	/// [time limit](Interpreter::time_limit) for the programs it runs.
+	/// Every program then runs in a [fork](Interpreter::fork) of that
+	/// interpreter.
+	pub fn new<F>(threads: usize, setup: F) -> Pool
+		where F: Fn(&mut Interpreter) + Send + Sync + 'static {
+		let (sender, receiver) = mpsc::channel::<Task>();
+		let receiver = Arc::new(Mutex::new(receiver));
+		let setup = Arc::new(setup);
+		let workers = (0..threads.max(1)).map(|_| {
+			let (receiver, setup) = (receiver.clone(), setup.clone());
+			thread::Builder::new().stack_size(limit::STACK_SIZE).spawn(move || {
+				let mut base = Interpreter::new();
+				setup(&mut base);
+				loop {
+					// The lock is held while waiting, so that one idle worker
+					// at a time takes the next task.
+					let task = match receiver.lock().unwrap().recv() {
+						Ok(task) => task,
+						Err(_) => return
+					};
+					let res = panic::catch_unwind(AssertUnwindSafe(|| {
+						limit::with_depth_limit(|| base.fork().eval_str(&task.src).map(|v| v.to_string()))
+					}));
+					task.slot.finish(res.unwrap_or_else(|_| Err(ErrorKind::Host("evaluation panicked".to_string()).into())));
+				}
+			}).expect("cannot start a worker thread")
+		}).collect();
+		Pool { queue: Some(sender), workers }
+	}
+
+	/// Queues the program `src` for the next free worker.
+	pub fn eval(&self, src: &str) -> Eval {
+		let slot = Arc::new(Slot { state: Mutex::new((None, None)), done: Condvar::new() });
+		let task = Task { src: src.to_string(), slot: slot.clone() };
+		self.queue.as_ref().expect("the queue is open until the pool is dropped").send(task)
+			.expect("workers run until the queue is closed");
+		Eval { slot }
+	}
+}
+
+impl Drop for Pool {
+	fn drop(&mut self) {
+		self.queue = None;
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+	}
+}
+
+#[test]
+fn test_pool() {
+	use std::time::Duration;
+	let pool = Pool::new(2, |interp| {
+		interp.register("answer", |_| Ok(super::Value::Integer(42)));
+		interp.eval_str("(define (add-answer n) (+ n (answer)))").unwrap();
+		interp.time_limit(Some(Duration::from_millis(100)));
+	});
+	let evals: Vec<Eval> = (0..8).map(|n| pool.eval(&format!("(define x {}) (add-answer x)", n))).collect();
+	let results: Vec<String> = evals.into_iter().map(|e| e.wait().unwrap()).collect();
+	assert_eq!((42..50).map(|n| n.to_string()).collect::<Vec<_>>(), results);
+	// Programs do not see each other's definitions.
+	assert_eq!("1:1: unbound symbol 'x'", pool.eval("x").wait().unwrap_err().to_string());
+	assert_eq!(ErrorKind::TimeLimit, pool.eval("(define (f) (f)) (f)").wait().unwrap_err().kind);
+}
+
+#[test]
+fn test_pool_deep_recursion() {
+	let pool = Pool::new(2, |_| ());
+	let deep = pool.eval("(define (f n) (+ 1 (f n))) (f 0)");
+	let evals: Vec<Eval> = (0..4).map(|n| pool.eval(&format!("(* {} 2)", n))).collect();
+	let err = deep.wait().unwrap_err();
+	assert_eq!(ErrorKind::DepthLimit, err.kind);
+	let results: Vec<String> = evals.into_iter().map(|e| e.wait().unwrap()).collect();
+	assert_eq!(vec!["0", "2", "4", "6"], results);
+	// The worker that ran it is still there.
+	let evals: Vec<Eval> = (0..4).map(|_| pool.eval("(define (g n) (if (= n 0) 0 (+ 1 (g (- n 1))))) (g 5000)")).collect();
+	assert!(evals.into_iter().all(|e| e.wait() == Ok("5000".to_string())));
+}
+
+#[test]
+fn test_pool_memory() {
+	let pool = Pool::new(1, |interp| {
+		interp.register("heap", |_| Ok(super::Value::Integer(super::limit::heap() as i64)));
+	});
+	let task = "(define (make n) (define (get) n) get) (define counter (make (list 1 2 3))) (counter)";
+	let run = |n| (0..n).map(|_| pool.eval(task)).for_each(|e| assert!(e.wait().is_ok()));
+	run(10);
+	let heap = pool.eval("(heap)").wait().unwrap();
+	run(1000);
+	assert_eq!(heap, pool.eval("(heap)").wait().unwrap());
+}
+
+#[test]
+fn test_pool_future() {
+	use std::task::Wake;
+
+	struct Unpark(thread::Thread);
+
+	impl Wake for Unpark {
+		fn wake(self: Arc<Self>) {
+			self.0.unpark();
+		}
+	}
+
+	let pool = Pool::new(1, |_| ());
+	let mut eval = pool.eval("(* 6 7)");
+	let waker = Waker::from(Arc::new(Unpark(thread::current())));
+	let mut cx = Context::from_waker(&waker);
+	let res = loop {
+		match Pin::new(&mut eval).poll(&mut cx) {
+			Poll::Ready(res) => break res,
+			Poll::Pending => thread::park()
+		}
+	};
+	assert_eq!(Ok("42".to_string()), res);
+}
@@ -0,0 +1,182 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Pipelines of child processes. `(process "cmd" "arg" ...)` only describes
+// a command, as the list `(process "cmd" "arg" ...)`, and `pipe` starts the
+// commands with the output of each going to the input of the next. The
+// output of the last is read a line at a time through the procedure `pipe`
+// returns, so a pipeline can produce more than fits in memory.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::rc::Rc;
+
+use super::{builtin, check_arity, list_items, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+fn string(v: &Value) -> Result<&str, CrustError> {
+	match *v {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type("a string", v))
+	}
+}
+
+fn builtin_process(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("process", args, 1, None)?;
+	for a in args {
+		string(a)?;
+	}
+	let mut items = vec![Value::Symbol(Rc::from("process"))];
+	items.extend_from_slice(args);
+	Ok(Value::list(items))
+}
+
+// The program and arguments of a value made by `process`.
+fn command(v: &Value) -> Result<Command, CrustError> {
+	let items = list_items(v).unwrap_or_default();
+	match items.split_first() {
+		Some((Value::Symbol(tag), words)) if &**tag == "process" && !words.is_empty() => {
+			let mut command = Command::new(string(&words[0])?);
+			for word in &words[1..] {
+				command.arg(string(word)?);
+			}
+			Ok(command)
+		}
+		_ => Err(wrong_type("a process", v))
+	}
+}
+
+struct Pipeline {
+	// The commands by program name.
+	children: Vec<(String, Child)>,
+	// The output of the last command, until it has been read to the end.
+	out: Option<BufReader<ChildStdout>>
+}
+
+impl Pipeline {
+	// The next line of output, or None at the end once all the commands
+	// have exited. As in a shell, the pipeline fails only if the last
+	// command does: `grep` exits with an error when nothing matches, and a
+	// command writing to one that has exited is stopped, and neither
+	// should fail a pipeline whose output is complete.
+	fn next_line(&mut self) -> Result<Option<String>, CrustError> {
+		let out = match self.out {
+			Some(ref mut out) => out,
+			None => return Ok(None)
+		};
+		let mut line = Vec::new();
+		if out.read_until(b'\n', &mut line).map_err(pipe_error)? == 0 {
+			self.out = None;
+			let mut failed = None;
+			for (program, mut child) in self.children.drain(..) {
+				let status = child.wait().map_err(pipe_error)?;
+				failed = if status.success() { None } else { Some(format!("{}: {}", program, status)) };
+			}
+			return match failed {
+				Some(msg) => Err(ErrorKind::Io(msg).into()),
+				None => Ok(None)
+			};
+		}
+		if line.last() == Some(&b'\n') {
+			line.pop();
+		}
+		Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+	}
+}
+
+// A pipeline that is dropped before its output has been read is killed,
+// as the commands could otherwise be left blocked on a full pipe.
+impl Drop for Pipeline {
+	fn drop(&mut self) {
+		self.out = None;
+		for &mut (_, ref mut child) in &mut self.children {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+}
+
+fn pipe_error(e: io::Error) -> CrustError {
+	ErrorKind::Io(format!("pipe: {}", e)).into()
+}
+
+// (pipe process ...) starts the processes and returns a procedure that
+// returns the next line of output of the last one each time it is called,
+// and #f at the end, or fails if the last one exited with an error. The
+// first reads the standard input of crust, and errors from all of them go
+// to its standard error.
+fn builtin_pipe(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("pipe", args, 1, None)?;
+	let mut commands = args.iter().map(command).collect::<Result<Vec<_>, _>>()?;
+	let mut pipeline = Pipeline { children: Vec::new(), out: None };
+	let mut previous: Option<ChildStdout> = None;
+	for command in &mut commands {
+		let program = command.get_program().to_string_lossy().into_owned();
+		let stdin = previous.take().map_or_else(Stdio::inherit, Stdio::from);
+		let mut child = command.stdin(stdin).stdout(Stdio::piped()).spawn()
+			.map_err(|e| ErrorKind::Io(format!("{}: {}", program, e)))?;
+		previous = child.stdout.take();
+		pipeline.children.push((program, child));
+	}
+	pipeline.out = previous.map(BufReader::new);
+	let pipeline = RefCell::new(pipeline);
+	Ok(builtin("read-pipe", move |args| {
+		check_arity("read-pipe", args, 0, Some(0))?;
+		match pipeline.borrow_mut().next_line()? {
+			Some(line) => Ok(Value::Str(Rc::from(line))),
+			None => Ok(Value::Boolean(false))
+		}
+	}))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("process", builtin_process),
+		("pipe", builtin_pipe),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_pipe() {
+	let mut interp = super::Interpreter::new();
+	interp.eval_str("(define next (pipe (process \"printf\" \"b\\na\\nb\\nc\") (process \"sort\") (process \"uniq\")))").unwrap();
+	let lines: Vec<String> = (0..5).map(|_| interp.eval_str("(next)").unwrap().to_string()).collect();
+	assert_eq!(lines, ["\"a\"", "\"b\"", "\"c\"", "#f", "#f"]);
+	let err = interp.eval_str("(pipe (process \"printf\" \"x\") (process \"no-such-command-here\"))").unwrap_err();
+	assert!(matches!(err.kind, ErrorKind::Io(ref msg) if msg.starts_with("no-such-command-here: ")));
+	// Only the status of the last command counts.
+	interp.eval_str("(define next (pipe (process \"printf\" \"a\\nb\") (process \"grep\" \"x\") (process \"wc\" \"-l\")))").unwrap();
+	assert_eq!(interp.eval_str("(next)").unwrap().to_string(), "\"0\"");
+	assert_eq!(interp.eval_str("(next)").unwrap().to_string(), "#f");
+	interp.eval_str("(define next (pipe (process \"printf\" \"a\\nb\") (process \"grep\" \"x\")))").unwrap();
+	let err = interp.eval_str("(next)").unwrap_err();
+	assert_eq!(err.to_string(), "1:1: grep: exit status: 1");
+	assert_eq!(interp.eval_str("(next)").unwrap().to_string(), "#f");
+	assert!(interp.eval_str("(pipe '(process))").is_err());
+	assert!(interp.eval_str("(process \"ls\" 1)").is_err());
+}
+
+#[test]
+fn test_pipe_dropped_early() {
+	// `yes` never stops on its own, so this only returns if the pipeline
+	// is killed when the reader goes away.
+	let mut interp = super::Interpreter::new();
+	assert_eq!(interp.eval_str("((pipe (process \"yes\")))").unwrap().to_string(), "\"y\"");
+}
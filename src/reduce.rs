@@ -0,0 +1,234 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// The test-case reducer behind `crust reduce`. It repeatedly deletes and
+// hoists parts of a program for as long as a check keeps holding, which
+// leaves a much smaller program showing the same behaviour. Candidates are
+// only required to have balanced parens, not to be valid crust, since the
+// behaviour being chased may well be a parse error.
+
+use super::{lex, CrustError, ErrorKind, Token};
+
+#[derive(Clone)]
+struct Tree {
+	// The number of `'` in front of the tree.
+	quotes: usize,
+	kind: TreeKind
+}
+
+#[derive(Clone)]
+enum TreeKind {
+	// A token other than a paren or quote, kept as written.
+	Atom(String),
+	List(Vec<Tree>)
+}
+
+fn read(source: &str) -> Result<Vec<Tree>, CrustError> {
+	let tokens = lex(source)?;
+	// The lists being read, innermost last, with their opening parens.
+	let mut open = Vec::new();
+	let mut items = Vec::new();
+	let mut quotes = 0;
+	for &(ref token, span) in &tokens {
+		let kind = match *token {
+			Token::Quote => {
+				quotes += 1;
+				continue;
+			}
+			Token::LeftParen => {
+				open.push((span, quotes, std::mem::take(&mut items)));
+				quotes = 0;
+				continue;
+			}
+			Token::RightParen if quotes > 0 => return Err(CrustError::new(ErrorKind::UnexpectedToken(")".to_string()), span.start)),
+			Token::RightParen => match open.pop() {
+				Some((_, outer_quotes, outer)) => {
+					let list = std::mem::replace(&mut items, outer);
+					quotes = outer_quotes;
+					TreeKind::List(list)
+				}
+				None => return Err(CrustError::new(ErrorKind::UnbalancedParens, span.start))
+			},
+			_ => TreeKind::Atom(source[span.start.offset..span.end.offset].to_string())
+		};
+		items.push(Tree { quotes, kind });
+		quotes = 0;
+	}
+	match open.pop() {
+		Some((span, _, _)) => Err(CrustError::new(ErrorKind::UnbalancedParens, span.start)),
+		None if quotes > 0 => Err(CrustError::new(ErrorKind::UnexpectedToken("'".to_string()), tokens[tokens.len() - 1].1.start)),
+		None => Ok(items)
+	}
+}
+
+fn write_tree(out: &mut String, tree: &Tree) {
+	for _ in 0..tree.quotes {
+		out.push('\'');
+	}
+	match tree.kind {
+		TreeKind::Atom(ref s) => out.push_str(s),
+		TreeKind::List(ref items) => {
+			out.push('(');
+			for (i, item) in items.iter().enumerate() {
+				if i > 0 {
+					out.push(' ');
+				}
+				write_tree(out, item);
+			}
+			out.push(')');
+		}
+	}
+}
+
+// Renders a program with one top-level form per line.
+fn render(forms: &[Tree]) -> String {
+	let mut out = String::new();
+	for form in forms {
+		write_tree(&mut out, form);
+		out.push('\n');
+	}
+	out
+}
+
+// The paths to the top level and to every list in `items`, in preorder.
+// A path is the list of indices leading from the top level to the list.
+fn list_paths(items: &[Tree], path: &mut Vec<usize>, paths: &mut Vec<Vec<usize>>) {
+	paths.push(path.clone());
+	for (i, item) in items.iter().enumerate() {
+		if let TreeKind::List(ref items) = item.kind {
+			path.push(i);
+			list_paths(items, path, paths);
+			path.pop();
+		}
+	}
+}
+
+fn list_at<'t>(forms: &'t mut Vec<Tree>, path: &[usize]) -> &'t mut Vec<Tree> {
+	let mut items = forms;
+	for &i in path {
+		items = match items[i].kind {
+			TreeKind::List(ref mut items) => items,
+			TreeKind::Atom(_) => unreachable!("paths only lead to lists")
+		};
+	}
+	items
+}
+
+struct Reducer<'c> {
+	forms: Vec<Tree>,
+	check: &'c mut dyn FnMut(&str) -> bool
+}
+
+impl<'c> Reducer<'c> {
+	// Keeps the edit of the list at `path` if the check still holds for the
+	// edited program.
+	fn attempt(&mut self, path: &[usize], edit: &dyn Fn(&mut Vec<Tree>)) -> bool {
+		let mut candidate = self.forms.clone();
+		edit(list_at(&mut candidate, path));
+		if (self.check)(&render(&candidate)) {
+			self.forms = candidate;
+			return true;
+		}
+		false
+	}
+
+	// Shrinks the list at `path`: first by deleting chunks of items, halving
+	// the chunk size down to single items, then by replacing items with one
+	// of their own items and by dropping quotes.
+	fn reduce_list(&mut self, path: &[usize]) -> bool {
+		let mut progress = false;
+		let mut n = list_at(&mut self.forms, path).len() / 2;
+		loop {
+			n = n.max(1);
+			let mut i = 0;
+			while i < list_at(&mut self.forms, path).len() {
+				if self.attempt(path, &|items| { items.drain(i..(i + n).min(items.len())); }) {
+					progress = true;
+				} else {
+					i += n;
+				}
+			}
+			if n == 1 {
+				break;
+			}
+			n /= 2;
+		}
+
+		let mut i = 0;
+		while i < list_at(&mut self.forms, path).len() {
+			let item = list_at(&mut self.forms, path)[i].clone();
+			let mut hoisted = false;
+			if let TreeKind::List(ref children) = item.kind {
+				for child in children {
+					if self.attempt(path, &|items| items[i] = child.clone()) {
+						hoisted = true;
+						break;
+					}
+				}
+			}
+			if !hoisted && item.quotes > 0 {
+				hoisted = self.attempt(path, &|items| items[i].quotes = 0);
+			}
+			if hoisted {
+				// The new item may shrink further.
+				progress = true;
+			} else {
+				i += 1;
+			}
+		}
+		progress
+	}
+}
+
+// Reduces `source` to a smaller program for which `check` still holds. The
+// lists are visited innermost first, so that edits never invalidate the
+// paths of lists still to be visited, and the whole program is revisited
+// until no edit is kept.
+pub fn reduce(source: &str, check: &mut dyn FnMut(&str) -> bool) -> Result<String, CrustError> {
+	let forms = read(source)?;
+	if !check(&render(&forms)) {
+		return Err(ErrorKind::CheckFails.into());
+	}
+	let mut reducer = Reducer { forms, check };
+	loop {
+		let mut paths = Vec::new();
+		list_paths(&reducer.forms, &mut Vec::new(), &mut paths);
+		let mut progress = false;
+		for path in paths.iter().rev() {
+			progress |= reducer.reduce_list(path);
+		}
+		if !progress {
+			return Ok(render(&reducer.forms));
+		}
+	}
+}
+
+#[test]
+fn test_reduce() {
+	let source = "; computes something\n\
+	              (define (f x) (+ 1 (car x)))\n\
+	              (define y 2)\n\
+	              (f (+ y 1))\n";
+	let mut fails_on_pair = |s: &str| super::eval_str(s).is_err_and(|e| e.to_string().contains("expected a pair"));
+	assert_eq!("(define (f x) (car x))\n(f +)\n", reduce(source, &mut fails_on_pair).unwrap());
+
+	let mut has_quoted_list = |s: &str| s.contains("'(");
+	assert_eq!("'()\n", reduce("(list 'a '(b \"c\"))", &mut has_quoted_list).unwrap());
+
+	assert_eq!(Err(ErrorKind::CheckFails.into()), reduce("(+ 1 2)", &mut |_| false));
+	assert_eq!("1:1: unbalanced parens", reduce("(+ 1", &mut |_| true).unwrap_err().to_string());
+}
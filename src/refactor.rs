@@ -0,0 +1,400 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Source-to-source refactorings behind `crust refactor`. They work on the
+// spanned syntax tree but only rewrite the source ranges that change, so the
+// layout of the rest of the file is left alone.
+
+use std::cmp;
+
+use super::{global_env, lex, parse, CrustError, ErrorKind, Fun, Node, NodeKind, Span, Token};
+
+// The names bound by the procedures enclosing a node, innermost last.
+#[derive(Clone)]
+struct Scopes<'a> {
+	frames: Vec<Vec<&'a str>>
+}
+
+impl<'a> Scopes<'a> {
+	fn new() -> Scopes<'a> {
+		Scopes { frames: Vec::new() }
+	}
+
+	fn is_local(&self, name: &str) -> bool {
+		self.frames.iter().any(|f| f.contains(&name))
+	}
+}
+
+// The names bound in the frame of a call to `fun`: its parameters and the
+// definitions directly in its body.
+fn frame<'a>(fun: &'a Fun) -> Vec<&'a str> {
+	let mut names: Vec<&'a str> = fun.params.iter().map(|p| &*p.name).collect();
+	for node in &fun.body {
+		if let NodeKind::Define(ref name, _) = node.kind {
+			names.push(&name.name);
+		}
+	}
+	names
+}
+
+// The expressions evaluated as part of `node`, other than through a
+// definition or a procedure body. Quoted data contains no expressions.
+fn subexpressions(node: &Node) -> Vec<&Node> {
+	match node.kind {
+		NodeKind::If(ref test, ref consequent, ref alternative) => {
+			let mut v: Vec<&Node> = vec![test, consequent];
+			v.extend(alternative.as_deref());
+			v
+		}
+		NodeKind::Cond(ref clauses) => clauses.iter()
+			.flat_map(|c| c.test.iter().chain(c.body.iter()))
+			.collect(),
+		NodeKind::And(ref nodes) | NodeKind::Or(ref nodes) => nodes.iter().collect(),
+		NodeKind::Application(ref op, ref args) => Some(&**op).into_iter().chain(args.iter()).collect(),
+		_ => Vec::new()
+	}
+}
+
+// A reference to a name, or the name being bound by a `define`.
+struct Occurrence<'a> {
+	name: &'a str,
+	span: Span,
+	definition: bool
+}
+
+// Calls `f` for every occurrence of a name in `node` together with the
+// scopes it appears in.
+fn walk<'a>(node: &'a Node, scopes: &mut Scopes<'a>, f: &mut dyn FnMut(&Scopes<'a>, Occurrence<'a>)) {
+	match node.kind {
+		NodeKind::Symbol(ref name) => f(scopes, Occurrence { name, span: node.span, definition: false }),
+		NodeKind::Define(ref name, ref value) => {
+			f(scopes, Occurrence { name: &name.name, span: name.span, definition: true });
+			walk(value, scopes, f);
+		}
+		NodeKind::Lambda(ref fun) => {
+			scopes.frames.push(frame(fun));
+			for node in &fun.body {
+				walk(node, scopes, f);
+			}
+			scopes.frames.pop();
+		}
+		_ => {
+			for node in subexpressions(node) {
+				walk(node, scopes, f);
+			}
+		}
+	}
+}
+
+fn refactor_error(msg: String) -> CrustError {
+	ErrorKind::Refactor(msg).into()
+}
+
+fn check_symbol(name: &str) -> Result<(), CrustError> {
+	match lex(name) {
+		Ok(ref tokens) if tokens.len() == 1 && tokens[0].0 == Token::Symbol(name) => Ok(()),
+		_ => Err(refactor_error(format!("'{}' is not a valid symbol", name)))
+	}
+}
+
+// Whether `name` is a builtin, defined at the top level or referenced as
+// a global anywhere in `roots`.
+fn is_global(roots: &[Node], name: &str) -> bool {
+	let mut found = global_env().lookup(name).is_some();
+	for root in roots {
+		walk(root, &mut Scopes::new(), &mut |scopes, occ| {
+			if occ.name == name && !scopes.is_local(name) {
+				found = true;
+			}
+		});
+	}
+	found
+}
+
+// Replaces the source ranges in `edits` (which must not overlap).
+fn rewrite(source: &str, mut edits: Vec<(Span, String)>) -> String {
+	edits.sort_by_key(|e| e.0.start.offset);
+	let mut res = String::new();
+	let mut from = 0;
+	for (span, text) in edits {
+		res.push_str(&source[from..span.start.offset]);
+		res.push_str(&text);
+		from = span.end.offset;
+	}
+	res.push_str(&source[from..]);
+	res
+}
+
+// Renames the global binding `old` to `new`: its top-level definitions and
+// every reference to it that is not shadowed by a local binding.
+pub fn rename(source: &str, old: &str, new: &str) -> Result<String, CrustError> {
+	check_symbol(new)?;
+	let tokens = lex(source)?;
+	let roots = parse(&tokens)?;
+	if is_global(&roots, new) {
+		return Err(refactor_error(format!("'{}' is already defined", new)));
+	}
+
+	let mut edits = Vec::new();
+	let mut captured = None;
+	for root in &roots {
+		walk(root, &mut Scopes::new(), &mut |scopes, occ| {
+			if occ.name != old || scopes.is_local(old) {
+				return;
+			}
+			if scopes.is_local(new) && captured.is_none() {
+				let msg = format!("'{}' would be captured by the local binding of '{}'", old, new);
+				captured = Some(CrustError::new(ErrorKind::Refactor(msg), occ.span.start));
+			}
+			edits.push((occ.span, new.to_string()));
+		});
+	}
+	if let Some(e) = captured {
+		return Err(e);
+	}
+	if edits.is_empty() {
+		return Err(refactor_error(format!("no global named '{}'", old)));
+	}
+	Ok(rewrite(source, edits))
+}
+
+// Finds the outermost expression starting at `line`:`col`, along with the
+// scopes it is evaluated in.
+fn find<'n>(node: &'n Node, line: usize, col: usize, scopes: &mut Scopes<'n>) -> Option<(&'n Node, Scopes<'n>)> {
+	if node.span.start.line == line && node.span.start.col == col {
+		return Some((node, scopes.clone()));
+	}
+	match node.kind {
+		NodeKind::Define(_, ref value) => find(value, line, col, scopes),
+		NodeKind::Lambda(ref fun) => {
+			scopes.frames.push(frame(fun));
+			let res = fun.body.iter().filter_map(|n| find(n, line, col, scopes)).next();
+			scopes.frames.pop();
+			res
+		}
+		_ => subexpressions(node).into_iter().filter_map(|n| find(n, line, col, scopes)).next()
+	}
+}
+
+// Moves the expression starting at `pos` into a new top-level procedure
+// `name`, placed before the top-level form containing the expression, and
+// replaces the expression with a call to it. Local variables the expression
+// refers to become the procedure's parameters.
+pub fn extract_function(source: &str, name: &str, pos: (usize, usize)) -> Result<String, CrustError> {
+	check_symbol(name)?;
+	let tokens = lex(source)?;
+	let roots = parse(&tokens)?;
+	let (line, col) = pos;
+	let (root, node, scopes) = roots.iter()
+		.filter_map(|root| find(root, line, col, &mut Scopes::new()).map(|(node, scopes)| (root, node, scopes)))
+		.next()
+		.ok_or_else(|| refactor_error(format!("no expression starts at {}:{}", line, col)))?;
+	let at = node.span.start;
+	if is_global(&roots, name) || scopes.is_local(name) {
+		return Err(CrustError::new(ErrorKind::Refactor(format!("'{}' is already defined", name)), at));
+	}
+
+	let mut params: Vec<&str> = Vec::new();
+	let mut definition = false;
+	walk(node, &mut Scopes::new(), &mut |inner, occ| {
+		if occ.definition && inner.frames.is_empty() {
+			definition = true;
+		} else if !inner.is_local(occ.name) && scopes.is_local(occ.name) && !params.contains(&occ.name) {
+			params.push(occ.name);
+		}
+	});
+	if definition {
+		return Err(CrustError::new(ErrorKind::Refactor("cannot extract a definition".to_string()), at));
+	}
+
+	let mut call = format!("({}", name);
+	let mut header = format!("(define ({}", name);
+	for p in &params {
+		call.push(' ');
+		call.push_str(p);
+		header.push(' ');
+		header.push_str(p);
+	}
+	call.push(')');
+	let body = &source[node.span.start.offset..node.span.end.offset];
+	let fun = format!("{})\n  {})\n\n", header, body);
+	let insert = Span { start: root.span.start, end: root.span.start };
+	Ok(rewrite(source, vec![(insert, fun), (node.span, call)]))
+}
+
+// Parses the `line:col` argument of `crust refactor extract-function`.
+pub fn parse_pos(s: &str) -> Option<(usize, usize)> {
+	let mut parts = s.splitn(2, ':');
+	let line = parts.next()?.parse().ok()?;
+	let col = parts.next()?.parse().ok()?;
+	Some((line, col))
+}
+
+enum Line<'a> {
+	Same(&'a str),
+	Removed(&'a str),
+	Added(&'a str)
+}
+
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+	// lcs[i][j] is the length of the longest common subsequence of old[i..]
+	// and new[j..].
+	let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+	for i in (0..old.len()).rev() {
+		for j in (0..new.len()).rev() {
+			lcs[i][j] = if old[i] == new[j] {
+				lcs[i + 1][j + 1] + 1
+			} else {
+				cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+			};
+		}
+	}
+
+	let mut lines = Vec::new();
+	let (mut i, mut j) = (0, 0);
+	while i < old.len() || j < new.len() {
+		if i < old.len() && j < new.len() && old[i] == new[j] {
+			lines.push(Line::Same(old[i]));
+			i += 1;
+			j += 1;
+		} else if i < old.len() && (j == new.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+			lines.push(Line::Removed(old[i]));
+			i += 1;
+		} else {
+			lines.push(Line::Added(new[j]));
+			j += 1;
+		}
+	}
+	lines
+}
+
+const CONTEXT: usize = 3;
+
+// The `start,count` part of a hunk header, where `before` is the number of
+// lines preceding the hunk.
+fn hunk_range(before: usize, count: usize) -> String {
+	match count {
+		0 => format!("{},0", before),
+		1 => format!("{}", before + 1),
+		_ => format!("{},{}", before + 1, count)
+	}
+}
+
+// A unified diff from `old` to `new` with three lines of context, like the
+// output of `diff -u`. Empty if the two are equal.
+pub fn unified_diff(path: &str, old: &str, new: &str) -> String {
+	let old_lines: Vec<&str> = old.lines().collect();
+	let new_lines: Vec<&str> = new.lines().collect();
+	let lines = diff_lines(&old_lines, &new_lines);
+	let changed: Vec<usize> = (0..lines.len())
+		.filter(|&k| !matches!(lines[k], Line::Same(_)))
+		.collect();
+	if changed.is_empty() {
+		return String::new();
+	}
+
+	// Group changes that are close enough to share context into hunks.
+	let mut hunks = Vec::new();
+	let mut start = changed[0];
+	let mut end = changed[0];
+	for &k in &changed[1..] {
+		if k - end > 2 * CONTEXT {
+			hunks.push((start, end));
+			start = k;
+		}
+		end = k;
+	}
+	hunks.push((start, end));
+
+	let mut out = format!("--- a/{}\n+++ b/{}\n", path, path);
+	for (start, end) in hunks {
+		let from = start.saturating_sub(CONTEXT);
+		let to = cmp::min(end + CONTEXT + 1, lines.len());
+		let in_old = |l: &&Line| !matches!(**l, Line::Added(_));
+		let in_new = |l: &&Line| !matches!(**l, Line::Removed(_));
+		let old_before = lines[..from].iter().filter(in_old).count();
+		let new_before = lines[..from].iter().filter(in_new).count();
+		let old_count = lines[from..to].iter().filter(in_old).count();
+		let new_count = lines[from..to].iter().filter(in_new).count();
+		out.push_str(&format!("@@ -{} +{} @@\n", hunk_range(old_before, old_count), hunk_range(new_before, new_count)));
+		for line in &lines[from..to] {
+			match *line {
+				Line::Same(l) => { out.push(' '); out.push_str(l); }
+				Line::Removed(l) => { out.push('-'); out.push_str(l); }
+				Line::Added(l) => { out.push('+'); out.push_str(l); }
+			}
+			out.push('\n');
+		}
+	}
+	out
+}
+
+#[test]
+fn test_rename() {
+	let src = "(define (area r) (* pi r r))\n(define pi 3)\n(area pi)\n";
+	assert_eq!("(define (area r) (* π r r))\n(define π 3)\n(area π)\n", rename(src, "pi", "π").unwrap());
+
+	// Local bindings of the same name are left alone.
+	let src = "(define x 1)\n(define (f x) (+ x 1))\n(f x)\n";
+	assert_eq!("(define y 1)\n(define (f x) (+ x 1))\n(f y)\n", rename(src, "x", "y").unwrap());
+	let src = "(define x 1)\n(define (f) (define x 2) x)\n(+ x (f))\n";
+	assert_eq!("(define y 1)\n(define (f) (define x 2) x)\n(+ y (f))\n", rename(src, "x", "y").unwrap());
+}
+
+#[test]
+fn test_rename_errors() {
+	let error = |src, old, new| rename(src, old, new).unwrap_err().to_string();
+	assert_eq!("2:18: 'x' would be captured by the local binding of 'y'",
+	           error("(define x 1)\n(define (f y) (+ x y))", "x", "y"));
+	assert_eq!("'y' is already defined", error("(define x 1)\n(define y 2)", "x", "y"));
+	assert_eq!("'+' is already defined", error("(define x 1)", "x", "+"));
+	assert_eq!("'(y' is not a valid symbol", error("(define x 1)", "x", "(y"));
+	assert_eq!("'12' is not a valid symbol", error("(define x 1)", "x", "12"));
+	assert_eq!("no global named 'z'", error("(define x 1)", "z", "y"));
+	assert_eq!("1:1: unbalanced parens", error("(define x 1", "x", "y"));
+}
+
+#[test]
+fn test_extract_function() {
+	let src = "(define (hyp a b)\n  (+ (* a a) (* b b)))\n";
+	assert_eq!("(define (square a)\n  (* a a))\n\n(define (hyp a b)\n  (+ (square a) (* b b)))\n",
+	           extract_function(src, "square", (2, 6)).unwrap());
+
+	// Globals and the expression's own locals do not become parameters.
+	let src = "(define k 2)\n(define (f x y) ((lambda (z) (* k x z y x)) 1))\n";
+	assert_eq!("(define k 2)\n(define (g x y)\n  (lambda (z) (* k x z y x)))\n\n\
+	            (define (f x y) ((g x y) 1))\n",
+	           extract_function(src, "g", (2, 18)).unwrap());
+
+	let error = |src, name, pos| extract_function(src, name, pos).unwrap_err().to_string();
+	assert_eq!("no expression starts at 1:3", error("(+ 1 2)", "f", (1, 3)));
+	assert_eq!("1:1: cannot extract a definition", error("(define x 1)", "f", (1, 1)));
+	assert_eq!("1:18: 'x' is already defined", error("(define (f x) (+ x 1))", "x", (1, 18)));
+	assert_eq!("1:15: 'f' is already defined", error("(define (f x) (+ x 1))", "f", (1, 15)));
+}
+
+#[test]
+fn test_unified_diff() {
+	assert_eq!("", unified_diff("f.crust", "a\nb\n", "a\nb\n"));
+	let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+	let new = "1\n2\n3\nfour\n5\n6\n7\n8\n9\n10\n11\n12\n13\n";
+	assert_eq!("--- a/f.crust\n+++ b/f.crust\n\
+	            @@ -1,7 +1,7 @@\n 1\n 2\n 3\n-4\n+four\n 5\n 6\n 7\n\
+	            @@ -10,3 +10,4 @@\n 10\n 11\n 12\n+13\n",
+	           unified_diff("f.crust", old, new));
+	assert_eq!("--- a/f.crust\n+++ b/f.crust\n@@ -0,0 +1 @@\n+x\n", unified_diff("f.crust", "", "x\n"));
+}
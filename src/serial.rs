@@ -0,0 +1,523 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// MessagePack and CBOR. crust has no bytevectors, so values are encoded to
+// files and decoded from them, as the compression builtins do. Lists,
+// numbers, booleans, strings and symbols survive a round trip. Symbols are
+// MessagePack extension type 1 and CBOR tag 39, which is registered for
+// identifiers. Data from elsewhere also has maps, which become association
+// lists of `(key value)` lists, nil, which becomes `()`, and byte strings,
+// which become strings with invalid UTF-8 replaced.
+
+use std::convert::TryFrom;
+use std::fs;
+use std::rc::Rc;
+
+use super::{builtin, check_arity, list_items, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+// Nesting deeper than this is taken to be corrupt rather than risking the
+// stack.
+const MAX_DEPTH: usize = 512;
+
+const SYMBOL_EXT: u8 = 1;
+const SYMBOL_TAG: u64 = 39;
+
+fn unencodable(v: &Value) -> CrustError {
+	wrong_type("a list, number, boolean, string or symbol", v)
+}
+
+pub(super) fn msgpack_encode(v: &Value, out: &mut Vec<u8>) -> Result<(), CrustError> {
+	// The marker for a length of `n`, with the marker for the smallest
+	// size in `small` and a fixed-size form for lengths below `fixed`.
+	fn length(out: &mut Vec<u8>, n: usize, fix: u8, fixed: usize, small: Option<u8>, large: u8) {
+		match small {
+			_ if n < fixed => out.push(fix | n as u8),
+			Some(marker) if n <= 0xff => out.extend([marker, n as u8]),
+			_ if n <= 0xffff => {
+				out.push(large);
+				out.extend((n as u16).to_be_bytes());
+			}
+			_ => {
+				out.push(large + 1);
+				out.extend((n as u32).to_be_bytes());
+			}
+		}
+	}
+	match *v {
+		Value::Nil => out.push(0x90),
+		Value::Boolean(b) => out.push(if b { 0xc3 } else { 0xc2 }),
+		Value::Integer(n) => match n {
+			0..=127 => out.push(n as u8),
+			-32..=-1 => out.push(n as i8 as u8),
+			n if n > 0 && n <= u8::MAX as i64 => out.extend([0xcc, n as u8]),
+			n if n > 0 && n <= u16::MAX as i64 => {
+				out.push(0xcd);
+				out.extend((n as u16).to_be_bytes());
+			}
+			n if n > 0 && n <= u32::MAX as i64 => {
+				out.push(0xce);
+				out.extend((n as u32).to_be_bytes());
+			}
+			n if n > 0 => {
+				out.push(0xcf);
+				out.extend((n as u64).to_be_bytes());
+			}
+			n if n >= i8::MIN as i64 => out.extend([0xd0, n as u8]),
+			n if n >= i16::MIN as i64 => {
+				out.push(0xd1);
+				out.extend((n as i16).to_be_bytes());
+			}
+			n if n >= i32::MIN as i64 => {
+				out.push(0xd2);
+				out.extend((n as i32).to_be_bytes());
+			}
+			n => {
+				out.push(0xd3);
+				out.extend(n.to_be_bytes());
+			}
+		},
+		Value::Float(x) => {
+			out.push(0xcb);
+			out.extend(x.to_be_bytes());
+		}
+		Value::Str(ref s) => {
+			length(out, s.len(), 0xa0, 32, Some(0xd9), 0xda);
+			out.extend(s.as_bytes());
+		}
+		Value::Symbol(ref s) => {
+			length(out, s.len(), 0, 0, Some(0xc7), 0xc8);
+			out.push(SYMBOL_EXT);
+			out.extend(s.as_bytes());
+		}
+		Value::Pair(_) => {
+			let items = list_items(v).ok_or_else(|| unencodable(v))?;
+			length(out, items.len(), 0x90, 16, None, 0xdc);
+			for item in &items {
+				msgpack_encode(item, out)?;
+			}
+		}
+		_ => return Err(unencodable(v))
+	}
+	Ok(())
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+	let major = major << 5;
+	match n {
+		0..=23 => out.push(major | n as u8),
+		24..=0xff => out.extend([major | 24, n as u8]),
+		0x100..=0xffff => {
+			out.push(major | 25);
+			out.extend((n as u16).to_be_bytes());
+		}
+		0x10000..=0xffff_ffff => {
+			out.push(major | 26);
+			out.extend((n as u32).to_be_bytes());
+		}
+		_ => {
+			out.push(major | 27);
+			out.extend(n.to_be_bytes());
+		}
+	}
+}
+
+fn cbor_encode(v: &Value, out: &mut Vec<u8>) -> Result<(), CrustError> {
+	match *v {
+		Value::Nil => out.push(0x80),
+		Value::Boolean(b) => out.push(if b { 0xf5 } else { 0xf4 }),
+		Value::Integer(n) if n >= 0 => cbor_head(out, 0, n as u64),
+		Value::Integer(n) => cbor_head(out, 1, !(n as u64)),
+		Value::Float(x) => {
+			out.push(0xfb);
+			out.extend(x.to_be_bytes());
+		}
+		Value::Str(ref s) => {
+			cbor_head(out, 3, s.len() as u64);
+			out.extend(s.as_bytes());
+		}
+		Value::Symbol(ref s) => {
+			cbor_head(out, 6, SYMBOL_TAG);
+			cbor_head(out, 3, s.len() as u64);
+			out.extend(s.as_bytes());
+		}
+		Value::Pair(_) => {
+			let items = list_items(v).ok_or_else(|| unencodable(v))?;
+			cbor_head(out, 4, items.len() as u64);
+			for item in &items {
+				cbor_encode(item, out)?;
+			}
+		}
+		_ => return Err(unencodable(v))
+	}
+	Ok(())
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize
+}
+
+impl<'a> Reader<'a> {
+	fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+		let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+		self.pos += n;
+		Some(bytes)
+	}
+
+	fn byte(&mut self) -> Option<u8> {
+		Some(self.bytes(1)?[0])
+	}
+
+	fn uint(&mut self, size: usize) -> Option<u64> {
+		Some(self.bytes(size)?.iter().fold(0, |n, &b| n << 8 | b as u64))
+	}
+
+	fn len(&mut self, size: usize) -> Option<usize> {
+		usize::try_from(self.uint(size)?).ok()
+	}
+
+	fn int(&mut self, size: usize) -> Option<i64> {
+		// Sign-extend from the top bit of the field.
+		let shift = 64 - 8 * size as u32;
+		Some((self.uint(size)? << shift) as i64 >> shift)
+	}
+}
+
+fn text(bytes: &[u8]) -> Value {
+	Value::Str(Rc::from(String::from_utf8_lossy(bytes).as_ref()))
+}
+
+fn symbol(bytes: &[u8]) -> Option<Value> {
+	Some(Value::Symbol(Rc::from(std::str::from_utf8(bytes).ok()?)))
+}
+
+fn items<F>(n: usize, mut item: F) -> Option<Value> where F: FnMut() -> Option<Value> {
+	let mut items = Vec::new();
+	for _ in 0..n {
+		items.push(item()?);
+	}
+	Some(Value::list(items))
+}
+
+// A map as an association list of `(key value)` lists.
+fn map<F>(n: usize, mut item: F) -> Option<Value> where F: FnMut() -> Option<Value> {
+	items(n, || {
+		let key = item()?;
+		Some(Value::list(vec![key, item()?]))
+	})
+}
+
+fn msgpack_item(r: &mut Reader, depth: usize) -> Option<Value> {
+	if depth > MAX_DEPTH {
+		return None;
+	}
+	let next = |r: &mut Reader| msgpack_item(r, depth + 1);
+	let marker = r.byte()?;
+	Some(match marker {
+		0x00..=0x7f => Value::Integer(marker as i64),
+		0x80..=0x8f => map((marker & 0x0f) as usize, || next(r))?,
+		0x90..=0x9f => items((marker & 0x0f) as usize, || next(r))?,
+		0xa0..=0xbf => text(r.bytes((marker & 0x1f) as usize)?),
+		0xc0 => Value::Nil,
+		0xc2 => Value::Boolean(false),
+		0xc3 => Value::Boolean(true),
+		0xc4..=0xc6 => {
+			let n = r.len(1 << (marker - 0xc4))?;
+			text(r.bytes(n)?)
+		}
+		0xc7..=0xc9 | 0xd4..=0xd8 => {
+			let n = match marker {
+				0xc7..=0xc9 => r.len(1 << (marker - 0xc7))?,
+				_ => 1 << (marker - 0xd4)
+			};
+			match r.byte()? {
+				SYMBOL_EXT => symbol(r.bytes(n)?)?,
+				_ => return None
+			}
+		}
+		0xca => Value::Float(f32::from_bits(r.uint(4)? as u32) as f64),
+		0xcb => Value::Float(f64::from_bits(r.uint(8)?)),
+		0xcc..=0xcf => Value::Integer(i64::try_from(r.uint(1 << (marker - 0xcc))?).ok()?),
+		0xd0..=0xd3 => Value::Integer(r.int(1 << (marker - 0xd0))?),
+		0xd9..=0xdb => {
+			let n = r.len(1 << (marker - 0xd9))?;
+			text(r.bytes(n)?)
+		}
+		0xdc | 0xdd => {
+			let n = r.len(2 << (marker - 0xdc))?;
+			items(n, || next(r))?
+		}
+		0xde | 0xdf => {
+			let n = r.len(2 << (marker - 0xde))?;
+			map(n, || next(r))?
+		}
+		0xe0..=0xff => Value::Integer(marker as i8 as i64),
+		_ => return None
+	})
+}
+
+fn msgpack_decode(r: &mut Reader) -> Option<Value> {
+	msgpack_item(r, 0)
+}
+
+fn half_to_f64(bits: u16) -> f64 {
+	let (sign, exp, frac) = (bits >> 15, (bits >> 10) & 0x1f, (bits & 0x3ff) as f64);
+	let magnitude = match exp {
+		0 => frac * 2f64.powi(-24),
+		31 if frac == 0.0 => f64::INFINITY,
+		31 => f64::NAN,
+		_ => (1.0 + frac / 1024.0) * 2f64.powi(exp as i32 - 15)
+	};
+	if sign == 1 { -magnitude } else { magnitude }
+}
+
+// A CBOR item, where `None` for the break that ends indefinite-length
+// items is told apart from errors by the outer `Option`.
+fn cbor_item(r: &mut Reader, depth: usize) -> Option<Option<Value>> {
+	if depth > MAX_DEPTH {
+		return None;
+	}
+	let initial = r.byte()?;
+	if initial == 0xff {
+		return Some(None);
+	}
+	let (major, info) = (initial >> 5, initial & 0x1f);
+	let argument = match info {
+		0..=23 => Some(info as u64),
+		24..=27 => Some(r.uint(1 << (info - 24))?),
+		31 if (2..=5).contains(&major) => None,
+		_ => return None
+	};
+	Some(Some(match major {
+		0 => Value::Integer(i64::try_from(argument?).ok()?),
+		// -1 - n, which is !n in two's complement.
+		1 => Value::Integer(!i64::try_from(argument?).ok()?),
+		2 | 3 => {
+			let bytes = match argument {
+				Some(n) => r.bytes(usize::try_from(n).ok()?)?.to_vec(),
+				// Definite-length chunks up to a break.
+				None => {
+					let mut bytes = Vec::new();
+					loop {
+						let chunk = r.byte()?;
+						if chunk == 0xff {
+							break bytes;
+						}
+						if chunk >> 5 != major || chunk & 0x1f > 27 {
+							return None;
+						}
+						let n = match chunk & 0x1f {
+							n @ 0..=23 => n as usize,
+							n => r.len(1 << (n - 24))?
+						};
+						bytes.extend_from_slice(r.bytes(n)?);
+					}
+				}
+			};
+			text(&bytes)
+		}
+		4 => Value::list(cbor_items(r, argument, depth)?),
+		5 => {
+			let items = cbor_items(r, argument.map(|n| n.saturating_mul(2)), depth)?;
+			if items.len() % 2 != 0 {
+				return None;
+			}
+			Value::list(items.chunks(2).map(|kv| Value::list(kv.to_vec())).collect())
+		}
+		6 => match (argument?, cbor_item(r, depth + 1)??) {
+			(SYMBOL_TAG, Value::Str(ref name)) => Value::Symbol(name.clone()),
+			// Other tags only say how to read the item.
+			(_, item) => item
+		},
+		_ => match info {
+			20 => Value::Boolean(false),
+			21 => Value::Boolean(true),
+			22 | 23 => Value::Nil,
+			25 => Value::Float(half_to_f64(argument? as u16)),
+			26 => Value::Float(f32::from_bits(argument? as u32) as f64),
+			27 => Value::Float(f64::from_bits(argument?)),
+			_ => return None
+		}
+	}))
+}
+
+// `n` items, or items up to a break if `n` is None.
+fn cbor_items(r: &mut Reader, n: Option<u64>, depth: usize) -> Option<Vec<Value>> {
+	let mut items = Vec::new();
+	match n {
+		Some(n) => {
+			for _ in 0..n {
+				items.push(cbor_item(r, depth + 1)??);
+			}
+		}
+		None => {
+			while let Some(item) = cbor_item(r, depth + 1)? {
+				items.push(item);
+			}
+		}
+	}
+	Some(items)
+}
+
+fn cbor_decode(r: &mut Reader) -> Option<Value> {
+	cbor_item(r, 0)?
+}
+
+fn path_arg(v: &Value) -> Result<&str, CrustError> {
+	match *v {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type("a file name", v))
+	}
+}
+
+fn encode_to_file(name: &str, args: &[Value], encode: fn(&Value, &mut Vec<u8>) -> Result<(), CrustError>) -> Result<Value, CrustError> {
+	check_arity(name, args, 2, Some(2))?;
+	let path = path_arg(&args[1])?;
+	let mut out = Vec::new();
+	encode(&args[0], &mut out)?;
+	fs::write(path, out).map_err(|e| ErrorKind::Io(format!("{}: {}", path, e)))?;
+	Ok(Value::Unspecified)
+}
+
+fn decode_file(name: &str, args: &[Value], decode: fn(&mut Reader) -> Option<Value>, format: &str) -> Result<Value, CrustError> {
+	check_arity(name, args, 1, Some(1))?;
+	let path = path_arg(&args[0])?;
+	let data = fs::read(path).map_err(|e| ErrorKind::Io(format!("{}: {}", path, e)))?;
+	decode_all(&data, decode).ok_or_else(|| ErrorKind::Io(format!("{}: not valid {}", path, format)).into())
+}
+
+// The value `data` holds, if it holds exactly one.
+fn decode_all(data: &[u8], decode: fn(&mut Reader) -> Option<Value>) -> Option<Value> {
+	let mut r = Reader { data, pos: 0 };
+	decode(&mut r).filter(|_| r.pos == data.len())
+}
+
+pub(super) fn msgpack_value(data: &[u8]) -> Option<Value> {
+	decode_all(data, msgpack_decode)
+}
+
+// (msgpack-encode value file) writes a value to a file, and
+// (msgpack-decode file) reads it back; likewise for CBOR.
+fn builtin_msgpack_encode(args: &[Value]) -> Result<Value, CrustError> {
+	encode_to_file("msgpack-encode", args, msgpack_encode)
+}
+
+fn builtin_msgpack_decode(args: &[Value]) -> Result<Value, CrustError> {
+	decode_file("msgpack-decode", args, msgpack_decode, "MessagePack")
+}
+
+fn builtin_cbor_encode(args: &[Value]) -> Result<Value, CrustError> {
+	encode_to_file("cbor-encode", args, cbor_encode)
+}
+
+fn builtin_cbor_decode(args: &[Value]) -> Result<Value, CrustError> {
+	decode_file("cbor-decode", args, cbor_decode, "CBOR")
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("msgpack-encode", builtin_msgpack_encode),
+		("msgpack-decode", builtin_msgpack_decode),
+		("cbor-encode", builtin_cbor_encode),
+		("cbor-decode", builtin_cbor_decode),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[cfg(test)]
+fn unhex(s: &str) -> Vec<u8> {
+	(0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+#[cfg(test)]
+fn decoded(decode: fn(&mut Reader) -> Option<Value>, hex: &str) -> String {
+	let data = unhex(hex);
+	let mut r = Reader { data: &data, pos: 0 };
+	let v = decode(&mut r).unwrap();
+	assert_eq!(r.pos, data.len(), "{}", hex);
+	v.to_string()
+}
+
+#[test]
+fn test_cbor() {
+	// From the examples in RFC 8949, appendix A.
+	let examples = [
+		("0", "00"), ("23", "17"), ("24", "1818"), ("1000", "1903e8"), ("1000000000000", "1b000000e8d4a51000"),
+		("-1", "20"), ("-1000", "3903e7"), ("1.1", "fb3ff199999999999a"), ("#f", "f4"), ("#t", "f5"),
+		("\"IETF\"", "6449455446"), ("()", "80"), ("(1 (2 3) (4 5))", "8301820203820405"), ("abc", "d82763616263")
+	];
+	for &(crust, hex) in &examples {
+		let v = super::Interpreter::new().eval_str(&format!("'{}", crust)).unwrap();
+		let mut out = Vec::new();
+		cbor_encode(&v, &mut out).unwrap();
+		assert_eq!(out, unhex(hex), "{}", crust);
+		assert_eq!(decoded(cbor_decode, hex), crust);
+	}
+	assert_eq!(decoded(cbor_decode, "a26161016162820203"), "((\"a\" 1) (\"b\" (2 3)))");
+	assert_eq!(decoded(cbor_decode, "f93c00"), "1.0");
+	assert_eq!(decoded(cbor_decode, "f97bff"), "65504.0");
+	assert_eq!(decoded(cbor_decode, "fa47c35000"), "100000.0");
+	assert_eq!(decoded(cbor_decode, "f6"), "()");
+	assert_eq!(decoded(cbor_decode, "9f018202039f0405ffff"), "(1 (2 3) (4 5))");
+	assert_eq!(decoded(cbor_decode, "7f657374726561646d696e67ff"), "\"streaming\"");
+	assert_eq!(decoded(cbor_decode, "c11a514b67b0"), "1363896240");
+	let mut r = Reader { data: &[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff], pos: 0 };
+	assert!(cbor_decode(&mut r).is_none());
+	let nested = vec![0x81; MAX_DEPTH + 10];
+	assert!(cbor_decode(&mut Reader { data: &nested, pos: 0 }).is_none());
+}
+
+#[test]
+fn test_msgpack() {
+	let examples = [
+		("1", "01"), ("-1", "ff"), ("200", "ccc8"), ("-200", "d1ff38"), ("70000", "ce00011170"),
+		("-9223372036854775808", "d38000000000000000"), ("1.5", "cb3ff8000000000000"), ("\"hi\"", "a26869"),
+		("(1 #t #f)", "9301c3c2"), ("()", "90"), ("abc", "c70301616263")
+	];
+	for &(crust, hex) in &examples {
+		let v = super::Interpreter::new().eval_str(&format!("'{}", crust)).unwrap();
+		let mut out = Vec::new();
+		msgpack_encode(&v, &mut out).unwrap();
+		assert_eq!(out, unhex(hex), "{}", crust);
+		assert_eq!(decoded(msgpack_decode, hex), crust);
+	}
+	assert_eq!(decoded(msgpack_decode, "81a16101"), "((\"a\" 1))");
+	assert_eq!(decoded(msgpack_decode, "c0"), "()");
+	assert_eq!(decoded(msgpack_decode, "ca3fc00000"), "1.5");
+	assert!(msgpack_decode(&mut Reader { data: &unhex("cfffffffffffffffff"), pos: 0 }).is_none());
+	let long: String = "x".repeat(300);
+	let mut out = Vec::new();
+	msgpack_encode(&Value::Str(Rc::from(long.as_str())), &mut out).unwrap();
+	assert_eq!(&out[..3], &[0xda, 0x01, 0x2c]);
+}
+
+#[test]
+fn test_serial_files() {
+	let dir = super::TempDir::new();
+	let path = dir.join("data");
+	let mut interp = super::Interpreter::new();
+	for format in ["msgpack", "cbor"] {
+		let program = format!("({0}-encode '(1 -2.5 \"λ\" sym (nested (list)) #t) {1:?}) ({0}-decode {1:?})",
+			format, path.to_str().unwrap());
+		assert_eq!(interp.eval_str(&program).unwrap().to_string(), "(1 -2.5 \"λ\" sym (nested (list)) #t)");
+		assert!(interp.eval_str(&format!("({}-encode car \"x\")", format)).is_err());
+	}
+	fs::write(&path, [0x01, 0x02]).unwrap();
+	assert!(interp.eval_str(&format!("(cbor-decode {:?})", path.to_str().unwrap())).is_err());
+}
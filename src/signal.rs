@@ -0,0 +1,273 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Signal handlers written in crust. The operating system's handler only
+// records that a signal arrived; the crust procedure runs at the next
+// procedure call, where the interpreter is in a consistent state, or when
+// a sleeping builtin wakes up to check. Each interpreter has a table of
+// handlers of its own, held by its `on-signal` like the timers of
+// timer.rs, and every interpreter on the thread that handles a signal gets
+// it. The table is cleared when the interpreter is dropped.
+
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{apply, builtin, check_arity, wrong_type, CrustError, Env, ErrorKind, Value};
+
+// The signals that can be handled. Their numbers are the same on every
+// Unix.
+const SIGNALS: &[(&str, u32)] = &[("sighup", 1), ("sigint", 2), ("sigterm", 15)];
+
+// A bit for each signal that has arrived but not been handled yet.
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+// How many tables, on any thread, have a handler for each signal. The
+// operating system's handler is installed while there are any.
+static CATCHERS: Mutex<[usize; 32]> = Mutex::new([0; 32]);
+
+fn catch(signum: u32) {
+	let mut catchers = CATCHERS.lock().unwrap();
+	catchers[signum as usize] += 1;
+	if catchers[signum as usize] == 1 {
+		os::catch(signum, true);
+	}
+}
+
+fn release(signum: u32) {
+	let mut catchers = CATCHERS.lock().unwrap();
+	catchers[signum as usize] -= 1;
+	if catchers[signum as usize] == 0 {
+		os::catch(signum, false);
+	}
+}
+
+// The handlers of one interpreter, by signal.
+#[derive(Default)]
+struct Handlers(Vec<(u32, Value)>);
+
+impl Handlers {
+	// Sets or removes the handler of `signum`, returning the one it had.
+	fn set(&mut self, signum: u32, handler: Option<Value>) -> Option<Value> {
+		let old = self.0.iter().position(|&(s, _)| s == signum).map(|i| self.0.remove(i).1);
+		match (handler, &old) {
+			(Some(f), _) => {
+				self.0.push((signum, f));
+				if old.is_none() {
+					catch(signum);
+				}
+			}
+			(None, Some(_)) => release(signum),
+			(None, None) => ()
+		}
+		old
+	}
+}
+
+impl Drop for Handlers {
+	fn drop(&mut self) {
+		for &(signum, _) in &self.0 {
+			release(signum);
+		}
+	}
+}
+
+// The table of an interpreter on this thread, as `check` finds it.
+struct Table {
+	// The interpreter's global environment.
+	env: *const Env,
+	handlers: Weak<RefCell<Handlers>>
+}
+
+thread_local! {
+	static TABLES: RefCell<Vec<Table>> = const { RefCell::new(Vec::new()) };
+	// Whether a handler is running. Signals arriving meanwhile wait for it
+	// to return rather than interrupt it.
+	static HANDLING: Cell<bool> = const { Cell::new(false) };
+}
+
+#[cfg(unix)]
+mod os {
+	use std::os::raw::c_int;
+	use std::sync::atomic::Ordering;
+
+	const SIG_DFL: usize = 0;
+
+	extern "C" {
+		fn signal(signum: c_int, handler: usize) -> usize;
+	}
+
+	extern "C" fn record(signum: c_int) {
+		super::PENDING.fetch_or(1 << signum, Ordering::SeqCst);
+	}
+
+	// Makes the signal `signum` be recorded, or get its default action
+	// back.
+	pub(super) fn catch(signum: u32, on: bool) {
+		let handler = if on { record as extern "C" fn(c_int) as usize } else { SIG_DFL };
+		// Safe as `record` only touches an atomic.
+		unsafe {
+			signal(signum as c_int, handler);
+		}
+	}
+}
+
+#[cfg(not(unix))]
+mod os {
+	pub(super) fn catch(_signum: u32, _on: bool) {}
+}
+
+// Runs the handlers on this thread of the signals that have arrived since
+// the last check. This is called on every procedure call, so the common
+// case of no signals is a single load.
+pub(super) fn check() -> Result<(), CrustError> {
+	if PENDING.load(Ordering::Relaxed) == 0 || HANDLING.with(Cell::get) {
+		return Ok(());
+	}
+	let handlers: Vec<(u32, Value)> = TABLES.with(|tables| {
+		let mut tables = tables.borrow_mut();
+		tables.retain(|table| table.handlers.strong_count() > 0);
+		tables.iter().filter_map(|table| table.handlers.upgrade()).flat_map(|h| h.borrow().0.clone()).collect()
+	});
+	let mine = handlers.iter().fold(0, |mask, &(signum, _)| mask | 1 << signum);
+	let pending = PENDING.fetch_and(!mine, Ordering::SeqCst) & mine;
+	for (signum, handler) in handlers {
+		if pending & 1 << signum == 0 {
+			continue;
+		}
+		let name = SIGNALS.iter().find(|s| s.1 == signum).expect("only known signals have handlers").0;
+		HANDLING.with(|h| h.set(true));
+		let res = apply(handler, vec![Value::Symbol(Rc::from(name))], None);
+		HANDLING.with(|h| h.set(false));
+		res?;
+	}
+	Ok(())
+}
+
+// Sleeps for `d`, waking up to run signal handlers and to see whether the
+// time limit has passed.
+pub(super) fn sleep(d: Duration) -> Result<(), CrustError> {
+	let end = Instant::now() + d;
+	loop {
+		check()?;
+		super::limit::check_now()?;
+		let now = Instant::now();
+		if now >= end {
+			return Ok(());
+		}
+		thread::sleep((end - now).min(Duration::from_millis(50)));
+	}
+}
+
+// Removes the handlers of the interpreter with the global environment
+// `env`. Handlers usually refer to that environment, which refers to them
+// through `on-signal`, so they would otherwise outlive the interpreter.
+pub(super) fn clear(env: &Env) {
+	let table = TABLES.with(|tables| {
+		let tables = tables.borrow();
+		tables.iter().find(|table| std::ptr::eq(table.env, env)).and_then(|table| table.handlers.upgrade())
+	});
+	if let Some(table) = table {
+		// Dropped after the table is no longer borrowed.
+		let _handlers = std::mem::take(&mut *table.borrow_mut());
+	}
+}
+
+// (on-signal 'sigint handler) calls (handler 'sigint) when the process gets
+// SIGINT, instead of it being killed; #f as the handler restores that.
+fn on_signal(handlers: &RefCell<Handlers>, args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("on-signal", args, 2, Some(2))?;
+	let signum = match args[0] {
+		Value::Symbol(ref name) => SIGNALS.iter().find(|s| s.0 == &**name).map(|s| s.1),
+		_ => None
+	};
+	let signum = signum.ok_or_else(|| wrong_type("sighup, sigint or sigterm", &args[0]))?;
+	let handler = match args[1] {
+		Value::Boolean(false) => None,
+		Value::Builtin(_) | Value::Procedure(_) => Some(args[1].clone()),
+		ref v => return Err(wrong_type("a procedure or #f", v))
+	};
+	// The old handler is dropped after the table is no longer borrowed.
+	let _old = handlers.borrow_mut().set(signum, handler);
+	Ok(Value::Unspecified)
+}
+
+// (exit [code]) stops the program, with the exit status `code` or 0.
+fn builtin_exit(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("exit", args, 0, Some(1))?;
+	match args.first() {
+		None => Err(ErrorKind::Exit(0).into()),
+		Some(&Value::Integer(code)) if (0..=255).contains(&code) => Err(ErrorKind::Exit(code as i32).into()),
+		Some(v) => Err(wrong_type("an exit status from 0 to 255", v))
+	}
+}
+
+pub(super) fn define(env: &Env) {
+	let handlers = Rc::new(RefCell::new(Handlers::default()));
+	TABLES.with(|tables| {
+		let mut tables = tables.borrow_mut();
+		tables.retain(|table| table.handlers.strong_count() > 0);
+		tables.push(Table { env, handlers: Rc::downgrade(&handlers) });
+	});
+	env.define(Rc::from("on-signal"), builtin("on-signal", move |args| on_signal(&handlers, args)));
+	env.define(Rc::from("exit"), builtin("exit", builtin_exit));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_on_signal() {
+	extern "C" {
+		fn raise(signum: std::os::raw::c_int) -> std::os::raw::c_int;
+	}
+	let got = Rc::new(RefCell::new(Vec::new()));
+	let mut interp = super::Interpreter::new();
+	let g = got.clone();
+	interp.register("got", move |args| {
+		g.borrow_mut().push(args[0].to_string());
+		Ok(Value::Unspecified)
+	});
+	interp.eval_str("(on-signal 'sighup got)").unwrap();
+	unsafe {
+		raise(1);
+	}
+	// The handler runs at the next call.
+	assert!(got.borrow().is_empty());
+	interp.eval_str("(car '(1))").unwrap();
+	assert_eq!(*got.borrow(), ["sighup"]);
+	interp.eval_str("(on-signal 'sighup (lambda (s) (exit 3)))").unwrap();
+	unsafe {
+		raise(1);
+	}
+	assert_eq!(interp.eval_str("(car '(1))").unwrap_err().kind, ErrorKind::Exit(3));
+	interp.eval_str("(on-signal 'sighup #f)").unwrap();
+	assert_eq!(0, CATCHERS.lock().unwrap()[1]);
+	assert!(interp.eval_str("(on-signal 'sigkill #f)").is_err());
+
+	// Handlers belong to the interpreter that installed them, and are
+	// removed when it is dropped even though they refer to its
+	// environment.
+	interp.eval_str("(define (handle s) (got s)) (on-signal 'sighup handle)").unwrap();
+	let other = super::Interpreter::new();
+	assert_eq!(1, CATCHERS.lock().unwrap()[1]);
+	drop(other);
+	assert_eq!(1, CATCHERS.lock().unwrap()[1]);
+	drop(interp);
+	assert_eq!(0, CATCHERS.lock().unwrap()[1]);
+}
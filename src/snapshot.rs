@@ -0,0 +1,661 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Snapshots of the global definitions of an interpreter. A snapshot holds
+// every global with everything it reaches: data, procedures with the syntax
+// of their bodies, and the environments they close over, keeping what is
+// shared shared. Builtins are saved by name and looked up again on restore,
+// so hosts have to register their procedures before restoring. Builtins
+// that carry state of their own, like the procedures `progress-bar` and
+// `pipe` return, cannot be saved.
+//
+// The format is a header followed by four tables, each item refers only
+// to items before it: the lambdas, the environments with their parents, the
+// pairs, and then the values of the globals and of the environments'
+// variables. Pairs are saved once each and referred to by index, so that
+// what was `eq?` still is after a restore. Numbers are LEB128, zigzagged if
+// signed.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use super::{Builtin, Clause, CrustError, Env, ErrorKind, Fun, Ident, Node, NodeKind, Pair, Pos, Span, Value};
+
+const MAGIC: &[u8] = b"crust snapshot 2\n";
+
+// How deeply the syntax of a procedure may nest, so that restoring a
+// corrupt snapshot cannot overflow the stack. This is as deep as the parser
+// lets source nest, so every procedure can be saved. Like parsing,
+// restoring the most deeply nested takes a stack of `STACK_SIZE` bytes.
+const MAX_SYNTAX_DEPTH: usize = super::MAX_NESTING;
+
+fn snapshot_error(msg: String) -> CrustError {
+	ErrorKind::Snapshot(msg).into()
+}
+
+struct Writer<'g> {
+	global: &'g Rc<Env>,
+	funs: Vec<u8>,
+	fun_ids: HashMap<*const Fun, usize>,
+	envs: Vec<Rc<Env>>,
+	env_ids: HashMap<*const Env, usize>,
+	// The parents of `envs`, where 0 is the global environment and i + 1
+	// is `envs[i]`.
+	parents: Vec<usize>,
+	pairs: Vec<u8>,
+	pair_ids: HashMap<*const Pair, usize>,
+	// How deeply the node being written is nested in its procedure.
+	depth: usize
+}
+
+fn write_uint(out: &mut Vec<u8>, mut n: u64) {
+	while n >= 0x80 {
+		out.push(n as u8 | 0x80);
+		n >>= 7;
+	}
+	out.push(n as u8);
+}
+
+fn write_int(out: &mut Vec<u8>, n: i64) {
+	write_uint(out, ((n << 1) ^ (n >> 63)) as u64);
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+	write_uint(out, s.len() as u64);
+	out.extend(s.as_bytes());
+}
+
+fn write_span(out: &mut Vec<u8>, span: Span) {
+	for pos in [span.start, span.end] {
+		write_uint(out, pos.line as u64);
+		write_uint(out, pos.col as u64);
+		write_uint(out, pos.offset as u64);
+	}
+}
+
+fn write_ident(out: &mut Vec<u8>, ident: &Ident) {
+	write_str(out, &ident.name);
+	write_span(out, ident.span);
+}
+
+impl<'g> Writer<'g> {
+	// The index of `fun`, writing it and the lambdas inside it first if it
+	// has not been written yet.
+	fn fun(&mut self, fun: &Rc<Fun>) -> Result<usize, CrustError> {
+		if let Some(&id) = self.fun_ids.get(&Rc::as_ptr(fun)) {
+			return Ok(id);
+		}
+		let mut out = Vec::new();
+		match fun.name {
+			Some(ref name) => {
+				out.push(1);
+				write_str(&mut out, name);
+			}
+			None => out.push(0)
+		}
+		write_uint(&mut out, fun.params.len() as u64);
+		for param in &fun.params {
+			write_ident(&mut out, param);
+		}
+		let depth = std::mem::replace(&mut self.depth, 0);
+		let body = self.nodes(&mut out, &fun.body);
+		self.depth = depth;
+		body?;
+		self.funs.extend(out);
+		let id = self.fun_ids.len();
+		self.fun_ids.insert(Rc::as_ptr(fun), id);
+		Ok(id)
+	}
+
+	fn nodes(&mut self, out: &mut Vec<u8>, nodes: &[Node]) -> Result<(), CrustError> {
+		write_uint(out, nodes.len() as u64);
+		for node in nodes {
+			self.node(out, node)?;
+		}
+		Ok(())
+	}
+
+	fn node(&mut self, out: &mut Vec<u8>, node: &Node) -> Result<(), CrustError> {
+		if self.depth == MAX_SYNTAX_DEPTH {
+			return Err(snapshot_error("cannot save a procedure nested this deeply".to_string()));
+		}
+		self.depth += 1;
+		let res = self.node_kind(out, node);
+		self.depth -= 1;
+		res
+	}
+
+	fn node_kind(&mut self, out: &mut Vec<u8>, node: &Node) -> Result<(), CrustError> {
+		let tag = match node.kind {
+			NodeKind::Symbol(_) => 0,
+			NodeKind::Integer(_) => 1,
+			NodeKind::Float(_) => 2,
+			NodeKind::Boolean(_) => 3,
+			NodeKind::Str(_) => 4,
+			NodeKind::Quote(_) => 5,
+			NodeKind::List(_) => 6,
+			NodeKind::If(..) => 7,
+			NodeKind::Cond(_) => 8,
+			NodeKind::And(_) => 9,
+			NodeKind::Or(_) => 10,
+			NodeKind::Define(..) => 11,
+			NodeKind::Lambda(_) => 12,
+			NodeKind::Application(..) => 13
+		};
+		out.push(tag);
+		write_span(out, node.span);
+		match node.kind {
+			NodeKind::Symbol(ref s) | NodeKind::Str(ref s) => write_str(out, s),
+			NodeKind::Integer(n) => write_int(out, n),
+			NodeKind::Float(x) => write_uint(out, x.to_bits()),
+			NodeKind::Boolean(b) => out.push(b as u8),
+			NodeKind::Quote(ref datum) => self.node(out, datum)?,
+			NodeKind::List(ref nodes) | NodeKind::And(ref nodes) | NodeKind::Or(ref nodes) => self.nodes(out, nodes)?,
+			NodeKind::If(ref test, ref consequent, ref alternative) => {
+				self.node(out, test)?;
+				self.node(out, consequent)?;
+				match *alternative {
+					Some(ref alternative) => {
+						out.push(1);
+						self.node(out, alternative)?;
+					}
+					None => out.push(0)
+				}
+			}
+			NodeKind::Cond(ref clauses) => {
+				write_uint(out, clauses.len() as u64);
+				for clause in clauses {
+					match clause.test {
+						Some(ref test) => {
+							out.push(1);
+							self.node(out, test)?;
+						}
+						None => out.push(0)
+					}
+					self.nodes(out, &clause.body)?;
+				}
+			}
+			NodeKind::Define(ref name, ref value) => {
+				write_ident(out, name);
+				self.node(out, value)?;
+			}
+			NodeKind::Lambda(ref fun) => {
+				let id = self.fun(fun)?;
+				write_uint(out, id as u64);
+			}
+			NodeKind::Application(ref f, ref args) => {
+				self.node(out, f)?;
+				self.nodes(out, args)?;
+			}
+		}
+		Ok(())
+	}
+
+	// The reference to `env` in `parents`, adding it and its ancestors if
+	// they are new.
+	fn env(&mut self, env: &Rc<Env>) -> Result<usize, CrustError> {
+		if Rc::ptr_eq(env, self.global) {
+			return Ok(0);
+		}
+		if let Some(&id) = self.env_ids.get(&Rc::as_ptr(env)) {
+			return Ok(id + 1);
+		}
+		let parent = match env.parent {
+			Some(ref parent) => self.env(parent)?,
+			None => return Err(snapshot_error("cannot save a procedure from another interpreter".to_string()))
+		};
+		self.env_ids.insert(Rc::as_ptr(env), self.envs.len());
+		self.envs.push(env.clone());
+		self.parents.push(parent);
+		Ok(self.envs.len())
+	}
+
+	// The index of `first`, writing it first if it has not been written
+	// yet. The new pairs of its chain are written last first, each
+	// referring to the rest of the chain by index, so that long lists do
+	// not nest.
+	fn pair(&mut self, first: &Rc<Pair>) -> Result<usize, CrustError> {
+		let mut chain = Vec::new();
+		let mut rest = first;
+		while !self.pair_ids.contains_key(&Rc::as_ptr(rest)) {
+			chain.push(rest.clone());
+			match rest.1 {
+				Value::Pair(ref next) => rest = next,
+				_ => break
+			}
+		}
+		for pair in chain.iter().rev() {
+			// The cdr is either written already or not a pair.
+			let mut entry = Vec::new();
+			self.value(&mut entry, &pair.0)?;
+			self.value(&mut entry, &pair.1)?;
+			self.pairs.extend(entry);
+			self.pair_ids.insert(Rc::as_ptr(pair), self.pair_ids.len());
+		}
+		Ok(self.pair_ids[&Rc::as_ptr(first)])
+	}
+
+	fn builtin(&self, b: &Rc<Builtin>) -> Result<(), CrustError> {
+		match self.global.lookup(&b.name) {
+			Some(Value::Builtin(ref global)) if Rc::ptr_eq(global, b) => Ok(()),
+			_ => Err(snapshot_error(format!("cannot save #<builtin {}>", b.name)))
+		}
+	}
+
+	fn value(&mut self, out: &mut Vec<u8>, v: &Value) -> Result<(), CrustError> {
+		match *v {
+			Value::Unspecified => out.push(0),
+			Value::Nil => out.push(1),
+			Value::Boolean(b) => out.push(2 + b as u8),
+			Value::Integer(n) => {
+				out.push(4);
+				write_int(out, n);
+			}
+			Value::Float(x) => {
+				out.push(5);
+				write_uint(out, x.to_bits());
+			}
+			Value::Str(ref s) => {
+				out.push(6);
+				write_str(out, s);
+			}
+			Value::Symbol(ref s) => {
+				out.push(7);
+				write_str(out, s);
+			}
+			Value::Pair(ref pair) => {
+				let id = self.pair(pair)?;
+				out.push(8);
+				write_uint(out, id as u64);
+			}
+			Value::Builtin(ref b) => {
+				self.builtin(b)?;
+				out.push(9);
+				write_str(out, &b.name);
+			}
+			Value::Procedure(ref c) => {
+				let fun = self.fun(&c.fun)?;
+				let env = self.env(&c.env)?;
+				out.push(10);
+				write_uint(out, fun as u64);
+				write_uint(out, env as u64);
+			}
+		}
+		Ok(())
+	}
+}
+
+pub(super) fn snapshot(global: &Rc<Env>) -> Result<Vec<u8>, CrustError> {
+	let mut w = Writer {
+		global,
+		funs: Vec::new(),
+		fun_ids: HashMap::new(),
+		envs: Vec::new(),
+		env_ids: HashMap::new(),
+		parents: Vec::new(),
+		pairs: Vec::new(),
+		pair_ids: HashMap::new(),
+		depth: 0
+	};
+	let mut values = Vec::new();
+	let mut globals: Vec<(Rc<str>, Value)> = global.vars.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+	globals.sort_by(|a, b| a.0.cmp(&b.0));
+	// The builtins under their own names are already there on restore.
+	globals.retain(|(name, v)| !matches!(*v, Value::Builtin(ref b) if b.name == *name));
+	write_uint(&mut values, globals.len() as u64);
+	for (name, v) in &globals {
+		write_str(&mut values, name);
+		w.value(&mut values, v)?;
+	}
+	// Saving variables can reach more environments, which go at the end.
+	let mut i = 0;
+	while i < w.envs.len() {
+		let env = w.envs[i].clone();
+		let mut vars: Vec<(Rc<str>, Value)> = env.vars.borrow().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+		vars.sort_by(|a, b| a.0.cmp(&b.0));
+		write_uint(&mut values, vars.len() as u64);
+		for (name, v) in &vars {
+			write_str(&mut values, name);
+			w.value(&mut values, v)?;
+		}
+		i += 1;
+	}
+	let mut out = MAGIC.to_vec();
+	write_uint(&mut out, w.fun_ids.len() as u64);
+	out.extend(w.funs);
+	write_uint(&mut out, w.parents.len() as u64);
+	for &parent in &w.parents {
+		write_uint(&mut out, parent as u64);
+	}
+	write_uint(&mut out, w.pair_ids.len() as u64);
+	out.extend(w.pairs);
+	out.extend(values);
+	Ok(out)
+}
+
+struct Reader<'a> {
+	data: &'a [u8],
+	pos: usize,
+	funs: Vec<Rc<Fun>>,
+	// The global environment followed by the saved ones.
+	envs: Vec<Rc<Env>>,
+	pairs: Vec<Value>,
+	// How deeply the node being read is nested in its procedure.
+	depth: usize,
+	// The builtins of the interpreter restored into, as it was before.
+	builtins: HashMap<Rc<str>, Value>
+}
+
+// Reading fails with None on anything malformed; the caller reports it.
+impl<'a> Reader<'a> {
+	fn byte(&mut self) -> Option<u8> {
+		let b = *self.data.get(self.pos)?;
+		self.pos += 1;
+		Some(b)
+	}
+
+	fn uint(&mut self) -> Option<u64> {
+		let mut n = 0u64;
+		for shift in (0..64).step_by(7) {
+			let b = self.byte()?;
+			n |= ((b & 0x7f) as u64).checked_shl(shift)?;
+			if b & 0x80 == 0 {
+				return Some(n);
+			}
+		}
+		None
+	}
+
+	fn usize(&mut self) -> Option<usize> {
+		usize::try_from(self.uint()?).ok()
+	}
+
+	fn int(&mut self) -> Option<i64> {
+		let n = self.uint()?;
+		Some((n >> 1) as i64 ^ -((n & 1) as i64))
+	}
+
+	fn str(&mut self) -> Option<Rc<str>> {
+		let n = self.usize()?;
+		let bytes = self.data.get(self.pos..self.pos.checked_add(n)?)?;
+		self.pos += n;
+		Some(Rc::from(std::str::from_utf8(bytes).ok()?))
+	}
+
+	fn span(&mut self) -> Option<Span> {
+		let mut pos = || Some(Pos { line: self.usize()?, col: self.usize()?, offset: self.usize()? });
+		Some(Span { start: pos()?, end: pos()? })
+	}
+
+	fn ident(&mut self) -> Option<Ident> {
+		Some(Ident { name: self.str()?, span: self.span()? })
+	}
+
+	// A count of items that each take at least a byte, checked against
+	// what is left so that a corrupt count cannot allocate without bound.
+	fn count(&mut self) -> Option<usize> {
+		let n = self.usize()?;
+		if n > self.data.len() - self.pos {
+			return None;
+		}
+		Some(n)
+	}
+
+	fn fun(&mut self) -> Option<Fun> {
+		let name = match self.byte()? {
+			0 => None,
+			1 => Some(self.str()?),
+			_ => return None
+		};
+		let params = (0..self.count()?).map(|_| self.ident()).collect::<Option<_>>()?;
+		Some(Fun { name, params, body: self.nodes()? })
+	}
+
+	fn nodes(&mut self) -> Option<Vec<Node>> {
+		(0..self.count()?).map(|_| self.node()).collect()
+	}
+
+	fn optional_node(&mut self) -> Option<Option<Node>> {
+		match self.byte()? {
+			0 => Some(None),
+			1 => Some(Some(self.node()?)),
+			_ => None
+		}
+	}
+
+	fn node(&mut self) -> Option<Node> {
+		if self.depth == MAX_SYNTAX_DEPTH {
+			return None;
+		}
+		self.depth += 1;
+		let node = self.node_kind();
+		self.depth -= 1;
+		node
+	}
+
+	fn node_kind(&mut self) -> Option<Node> {
+		let tag = self.byte()?;
+		let span = self.span()?;
+		let kind = match tag {
+			0 => NodeKind::Symbol(self.str()?),
+			1 => NodeKind::Integer(self.int()?),
+			2 => NodeKind::Float(f64::from_bits(self.uint()?)),
+			3 => NodeKind::Boolean(self.byte()? != 0),
+			4 => NodeKind::Str(self.str()?),
+			5 => NodeKind::Quote(Box::new(self.node()?)),
+			6 => NodeKind::List(self.nodes()?),
+			7 => {
+				let test = self.node()?;
+				let consequent = self.node()?;
+				NodeKind::If(Box::new(test), Box::new(consequent), self.optional_node()?.map(Box::new))
+			}
+			8 => NodeKind::Cond((0..self.count()?).map(|_| {
+				let test = self.optional_node()?;
+				Some(Clause { test, body: self.nodes()? })
+			}).collect::<Option<_>>()?),
+			9 => NodeKind::And(self.nodes()?),
+			10 => NodeKind::Or(self.nodes()?),
+			11 => {
+				let name = self.ident()?;
+				NodeKind::Define(name, Box::new(self.node()?))
+			}
+			12 => {
+				let id = self.usize()?;
+				NodeKind::Lambda(self.funs.get(id)?.clone())
+			}
+			13 => {
+				let f = self.node()?;
+				NodeKind::Application(Box::new(f), self.nodes()?)
+			}
+			_ => return None
+		};
+		Some(Node { kind, span })
+	}
+
+	fn value(&mut self) -> Option<Value> {
+		Some(match self.byte()? {
+			0 => Value::Unspecified,
+			1 => Value::Nil,
+			2 => Value::Boolean(false),
+			3 => Value::Boolean(true),
+			4 => Value::Integer(self.int()?),
+			5 => Value::Float(f64::from_bits(self.uint()?)),
+			6 => Value::Str(self.str()?),
+			7 => Value::Symbol(self.str()?),
+			8 => {
+				let id = self.usize()?;
+				self.pairs.get(id)?.clone()
+			}
+			9 => {
+				let name = self.str()?;
+				self.builtins.get(&name)?.clone()
+			}
+			10 => {
+				let (fun, env) = (self.usize()?, self.usize()?);
+				let (fun, env) = (self.funs.get(fun)?.clone(), self.envs.get(env)?.clone());
+				Value::procedure(fun, env)
+			}
+			_ => return None
+		})
+	}
+
+	fn vars(&mut self) -> Option<Vec<(Rc<str>, Value)>> {
+		(0..self.count()?).map(|_| Some((self.str()?, self.value()?))).collect()
+	}
+}
+
+// Defines the globals saved in `bytes` in `global`. Nothing is defined if
+// the snapshot cannot be read.
+pub(super) fn restore(global: &Rc<Env>, bytes: &[u8]) -> Result<(), CrustError> {
+	let invalid = || snapshot_error("not a valid snapshot".to_string());
+	let data = bytes.strip_prefix(MAGIC).ok_or_else(invalid)?;
+	let builtins = global.vars.borrow().iter()
+		.filter(|(_, v)| matches!(*v, Value::Builtin(_)))
+		.map(|(k, v)| (k.clone(), v.clone()))
+		.collect();
+	let mut r = Reader { data, pos: 0, funs: Vec::new(), envs: vec![global.clone()], pairs: Vec::new(), depth: 0, builtins };
+	let globals = (|| {
+		for _ in 0..r.count()? {
+			let fun = r.fun()?;
+			r.funs.push(Rc::new(fun));
+		}
+		for _ in 0..r.count()? {
+			let parent = r.usize()?;
+			let parent = r.envs.get(parent)?.clone();
+			r.envs.push(Env::new(Some(parent)));
+		}
+		for _ in 0..r.count()? {
+			let (car, cdr) = (r.value()?, r.value()?);
+			r.pairs.push(Value::cons(car, cdr));
+		}
+		let globals = r.vars()?;
+		for i in 1..r.envs.len() {
+			let env = r.envs[i].clone();
+			for (name, v) in r.vars()? {
+				env.define(name, v);
+			}
+		}
+		if r.pos != r.data.len() {
+			return None;
+		}
+		Some(globals)
+	})().ok_or_else(invalid)?;
+	for (name, v) in globals {
+		global.define(name, v);
+	}
+	Ok(())
+}
+
+#[test]
+fn test_snapshot() {
+	let mut interp = super::Interpreter::new();
+	interp.eval_str("
+		(define (make-adder n) (lambda (x) (+ x n)))
+		(define add2 (make-adder 2))
+		(define data '(1 \"two\" three 4.5 #t ()))
+		(define plus +)
+		(define (fact n) (if (< n 2) 1 (* n (fact (- n 1)))))
+		(define (bad) (car 1))").unwrap();
+	let bytes = interp.snapshot().unwrap();
+	let mut restored = super::Interpreter::new();
+	restored.restore(&bytes).unwrap();
+	let v = restored.eval_str("(list (add2 3) data (plus 1 2) (fact 10) ((make-adder 5) 1))").unwrap();
+	assert_eq!(v.to_string(), "(5 (1 \"two\" three 4.5 #t ()) 3 3628800 6)");
+	// Positions in restored procedures are those of the original source.
+	let err = restored.eval_str("(bad)").unwrap_err();
+	assert_eq!(err.pos.map(|p| (p.line, p.col)), Some((7, 17)));
+	// Snapshots are deterministic.
+	assert_eq!(restored.snapshot().unwrap(), bytes);
+}
+
+#[test]
+fn test_snapshot_builtins() {
+	let mut interp = super::Interpreter::new();
+	interp.register("host", |_| Ok(Value::Integer(7)));
+	interp.eval_str("(define h host)").unwrap();
+	let bytes = interp.snapshot().unwrap();
+	let mut restored = super::Interpreter::new();
+	assert!(restored.restore(&bytes).is_err());
+	restored.register("host", |_| Ok(Value::Integer(8)));
+	restored.restore(&bytes).unwrap();
+	assert_eq!(restored.eval_str("(h)").unwrap().to_string(), "8");
+
+	interp.eval_str("(define p (progress-bar 10))").unwrap();
+	let err = interp.snapshot().unwrap_err();
+	assert_eq!(err.kind, ErrorKind::Snapshot("cannot save #<builtin progress>".to_string()));
+}
+
+#[test]
+fn test_restore_invalid() {
+	let mut interp = super::Interpreter::new();
+	interp.eval_str("(define (f x) (cond ((= x 0) 'zero) (else (list x \"s\" 1.5))))").unwrap();
+	let bytes = interp.snapshot().unwrap();
+	for n in 0..bytes.len() {
+		assert!(super::Interpreter::new().restore(&bytes[..n]).is_err(), "{}", n);
+	}
+	let mut extra = bytes.clone();
+	extra.push(0);
+	assert!(super::Interpreter::new().restore(&extra).is_err());
+}
+
+#[test]
+fn test_snapshot_long_list() {
+	let mut interp = super::Interpreter::new();
+	interp.eval_str("(define (range n acc) (if (= n 0) acc (range (- n 1) (cons n acc)))) (define xs (range 100000 '()))").unwrap();
+	let mut restored = super::Interpreter::new();
+	restored.restore(&interp.snapshot().unwrap()).unwrap();
+	assert_eq!(restored.eval_str("(car xs)").unwrap().to_string(), "1");
+}
+
+#[test]
+fn test_snapshot_sharing() {
+	let mut interp = super::Interpreter::new();
+	interp.eval_str("(define a (list 1 2)) (define b a) (define c (cons 0 (cdr a))) (define (f) a)").unwrap();
+	let mut restored = super::Interpreter::new();
+	restored.restore(&interp.snapshot().unwrap()).unwrap();
+	let v = restored.eval_str("(list (eq? a b) (eq? (cdr a) (cdr c)) (eq? (f) a) c)").unwrap();
+	assert_eq!(v.to_string(), "(#t #t #t (0 2))");
+}
+
+#[test]
+fn test_restore_deep() {
+	let run = || {
+		// One lambda, whose body is a 0 in quotes nested more deeply than
+		// any saved procedure can be, and no environments, pairs or globals.
+		let span = [0; 6];
+		let mut bytes = MAGIC.to_vec();
+		bytes.extend([1, 0, 0, 1]);
+		for _ in 0..MAX_SYNTAX_DEPTH + 10 {
+			bytes.push(5);
+			bytes.extend(span);
+		}
+		bytes.push(1);
+		bytes.extend(span);
+		bytes.extend([0, 0, 0, 0]);
+		let err = super::Interpreter::new().restore(&bytes).unwrap_err();
+		assert_eq!(err.kind, ErrorKind::Snapshot("not a valid snapshot".to_string()));
+		// The most deeply nested procedure the parser accepts is saved.
+		let list = format!("{}{}", "(".repeat(super::MAX_NESTING - 2), ")".repeat(super::MAX_NESTING - 2));
+		let mut interp = super::Interpreter::new();
+		interp.eval_str(&format!("(define (f) '{})", list)).unwrap();
+		let mut restored = super::Interpreter::new();
+		restored.restore(&interp.snapshot().unwrap()).unwrap();
+		assert_eq!(list, restored.eval_str("(f)").unwrap().to_string());
+	};
+	std::thread::Builder::new().stack_size(super::STACK_SIZE).spawn(run).unwrap().join().unwrap();
+}
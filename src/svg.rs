@@ -0,0 +1,163 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Drawing builtins that build up an SVG picture. crust has no mutation, so a
+// canvas is an ordinary list, `(canvas width height shapes)` with the shape
+// drawn last first, and drawing on a canvas returns a new one.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::rc::Rc;
+
+use super::{check_arity, float, list_items, wrong_type, CrustError, ErrorKind, Value};
+
+fn coord(v: &Value) -> Result<f64, CrustError> {
+	match float(v)? {
+		x if x.is_finite() => Ok(x),
+		_ => Err(wrong_type("a finite number", v))
+	}
+}
+
+fn symbol(name: &str) -> Value {
+	Value::Symbol(Rc::from(name))
+}
+
+struct Canvas {
+	width: Value,
+	height: Value,
+	shapes: Value
+}
+
+fn canvas(v: &Value) -> Result<Canvas, CrustError> {
+	match list_items(v).as_deref() {
+		Some([Value::Symbol(tag), width, height, shapes]) if &**tag == "canvas" => {
+			Ok(Canvas { width: width.clone(), height: height.clone(), shapes: shapes.clone() })
+		}
+		_ => Err(wrong_type("a canvas", v))
+	}
+}
+
+pub(super) fn builtin_svg_canvas(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("svg-canvas", args, 2, Some(2))?;
+	for v in args {
+		if coord(v)? <= 0.0 {
+			return Err(wrong_type("a positive number", v));
+		}
+	}
+	Ok(Value::list(vec![symbol("canvas"), args[0].clone(), args[1].clone(), Value::Nil]))
+}
+
+// Draws the shape `name`, given by `coords` numbers and an optional colour,
+// on the canvas that is the first argument.
+fn draw(name: &str, args: &[Value], coords: usize) -> Result<Value, CrustError> {
+	check_arity(name, args, coords + 1, Some(coords + 2))?;
+	let c = canvas(&args[0])?;
+	let mut shape = vec![symbol(name)];
+	for v in &args[1..=coords] {
+		coord(v)?;
+		shape.push(v.clone());
+	}
+	shape.push(match args.get(coords + 1) {
+		None => Value::Str(Rc::from("black")),
+		Some(v @ Value::Str(_)) => v.clone(),
+		Some(v) => return Err(wrong_type("a colour string", v))
+	});
+	Ok(Value::list(vec![symbol("canvas"), c.width, c.height, Value::cons(Value::list(shape), c.shapes)]))
+}
+
+// (line canvas x1 y1 x2 y2 [colour])
+pub(super) fn builtin_line(args: &[Value]) -> Result<Value, CrustError> {
+	draw("line", args, 4)
+}
+
+// (circle canvas cx cy r [colour])
+pub(super) fn builtin_circle(args: &[Value]) -> Result<Value, CrustError> {
+	draw("circle", args, 3)
+}
+
+fn escape(s: &str) -> String {
+	s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn render(v: &Value) -> Result<String, CrustError> {
+	let c = canvas(v)?;
+	let (width, height) = (coord(&c.width)?, coord(&c.height)?);
+	let mut out = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+	                      width, height);
+	let mut shapes = list_items(&c.shapes).ok_or_else(|| wrong_type("a canvas", v))?;
+	shapes.reverse();
+	for shape in &shapes {
+		let _ = match list_items(shape).as_deref() {
+			Some([Value::Symbol(name), x1, y1, x2, y2, Value::Str(colour)]) if &**name == "line" => {
+				writeln!(out, "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"{}\"/>",
+				         coord(x1)?, coord(y1)?, coord(x2)?, coord(y2)?, escape(colour))
+			}
+			Some([Value::Symbol(name), cx, cy, r, Value::Str(colour)]) if &**name == "circle" => {
+				writeln!(out, "<circle cx=\"{}\" cy=\"{}\" r=\"{}\" stroke=\"{}\" fill=\"none\"/>",
+				         coord(cx)?, coord(cy)?, coord(r)?, escape(colour))
+			}
+			_ => return Err(wrong_type("a canvas", v))
+		};
+	}
+	out.push_str("</svg>\n");
+	Ok(out)
+}
+
+// (save canvas file) writes the canvas to `file` as an SVG document.
+pub(super) fn builtin_save(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("save", args, 2, Some(2))?;
+	let svg = render(&args[0])?;
+	match args[1] {
+		Value::Str(ref path) => {
+			fs::write(&**path, svg).map_err(|e| ErrorKind::Io(format!("{}: {}", path, e)))?;
+			Ok(Value::Unspecified)
+		}
+		ref v => Err(wrong_type("a file name", v))
+	}
+}
+
+#[test]
+fn test_render() {
+	let mut interp = super::Interpreter::new();
+	let c = interp.eval_str("(define c (svg-canvas 100 50)) (circle (line c 0 0 100 50.5) 50 25 10 \"a&b\")").unwrap();
+	assert_eq!("(canvas 100 50 ((circle 50 25 10 \"a&b\") (line 0 0 100 50.5 \"black\")))", c.to_string());
+	assert_eq!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"100\" height=\"50\" viewBox=\"0 0 100 50\">\n\
+	            <line x1=\"0\" y1=\"0\" x2=\"100\" y2=\"50.5\" stroke=\"black\"/>\n\
+	            <circle cx=\"50\" cy=\"25\" r=\"10\" stroke=\"a&amp;b\" fill=\"none\"/>\n\
+	            </svg>\n",
+	           render(&c).unwrap());
+	// Drawing returns a new canvas and leaves the old one alone.
+	assert_eq!("(canvas 100 50 ())", interp.eval_str("c").unwrap().to_string());
+	let mut error = |src| interp.eval_str(src).unwrap_err().to_string();
+	assert_eq!("1:1: expected a positive number, got 0", error("(svg-canvas 0 10)"));
+	assert_eq!("1:1: expected a canvas, got (1 2)", error("(line '(1 2) 0 0 1 1)"));
+	assert_eq!("1:1: expected a finite number, got +inf.0", error("(circle c 0 0 (/ 1.0 0))"));
+	assert_eq!("1:1: expected a colour string, got red", error("(circle c 0 0 1 'red)"));
+	assert_eq!("1:1: expected a canvas, got (canvas 1 1 ((square 1)))", error("(save '(canvas 1 1 ((square 1))) \"x.svg\")"));
+}
+
+#[test]
+fn test_save() {
+	let dir = super::TempDir::new();
+	let path = dir.join("x.svg");
+	let program = format!("(save (line (svg-canvas 10 10) 0 0 10 10) \"{}\")", path.display());
+	super::Interpreter::new().eval_str(&program).unwrap();
+	let svg = fs::read_to_string(&path).unwrap();
+	assert!(svg.contains("<line x1=\"0\" y1=\"0\" x2=\"10\" y2=\"10\" stroke=\"black\"/>"));
+	let error = super::Interpreter::new().eval_str("(save (svg-canvas 1 1) \"/nonexistent/x.svg\")").unwrap_err();
+	assert!(error.to_string().starts_with("1:1: /nonexistent/x.svg: "));
+}
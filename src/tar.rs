@@ -0,0 +1,292 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// tar archives in the POSIX ustar format. Files are copied in and out a
+// buffer at a time rather than read into memory, since archives for backups
+// can be large.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use super::{builtin, check_arity, list_items, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+const BLOCK: usize = 512;
+
+fn file_error(path: &Path, e: io::Error) -> CrustError {
+	ErrorKind::Io(format!("{}: {}", path.display(), e)).into()
+}
+
+fn path_arg(v: &Value) -> Result<&Path, CrustError> {
+	match *v {
+		Value::Str(ref s) => Ok(Path::new(&**s)),
+		ref v => Err(wrong_type("a file name", v))
+	}
+}
+
+// Writes `n` as a zero-padded octal number filling all but the last byte
+// of `field`, which stays zero.
+fn octal(field: &mut [u8], n: u64) -> bool {
+	let digits = format!("{:0width$o}", n, width = field.len() - 1);
+	if digits.len() >= field.len() {
+		return false;
+	}
+	field[..digits.len()].copy_from_slice(digits.as_bytes());
+	true
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+	let mut digits = field.iter().take_while(|&&b| b != 0 && b != b' ').skip_while(|&&b| b == b' ');
+	digits.try_fold(0u64, |n, &b| match b {
+		b'0'..=b'7' => n.checked_mul(8)?.checked_add((b - b'0') as u64),
+		_ => None
+	})
+}
+
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+	// The checksum field itself counts as spaces.
+	header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 }).sum()
+}
+
+// The header for the entry `name` with the metadata of `meta`, or None if
+// the name does not fit. Names of up to 100 bytes fit as they are, and
+// longer ones if they can be split at a slash into a prefix of up to 155
+// bytes and a name of up to 100.
+fn header(name: &str, meta: &fs::Metadata) -> Option<[u8; BLOCK]> {
+	let mut h = [0; BLOCK];
+	let bytes = name.as_bytes();
+	if bytes.len() <= 100 {
+		h[..bytes.len()].copy_from_slice(bytes);
+	} else {
+		let split = (0..bytes.len()).rev().find(|&i| bytes[i] == b'/' && i <= 155 && bytes.len() - i - 1 <= 100)?;
+		h[..bytes.len() - split - 1].copy_from_slice(&bytes[split + 1..]);
+		h[345..345 + split].copy_from_slice(&bytes[..split]);
+	}
+	#[cfg(unix)]
+	let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777;
+	#[cfg(not(unix))]
+	let mode = if meta.is_dir() { 0o755 } else { 0o644 };
+	let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+	let size = if meta.is_dir() { 0 } else { meta.len() };
+	if !(octal(&mut h[100..108], mode as u64) && octal(&mut h[108..116], 0) && octal(&mut h[116..124], 0) &&
+		octal(&mut h[124..136], size) && octal(&mut h[136..148], mtime)) {
+		return None;
+	}
+	h[156] = if meta.is_dir() { b'5' } else { b'0' };
+	h[257..263].copy_from_slice(b"ustar\0");
+	h[263..265].copy_from_slice(b"00");
+	let sum = checksum(&h);
+	octal(&mut h[148..155], sum);
+	h[155] = b' ';
+	Some(h)
+}
+
+// The name `path` is stored under. Names are made relative and `..` is
+// resolved, or dropped where there is nothing left to go up from, as
+// extracting an absolute name or one with `..` in it would write outside
+// of the destination.
+fn entry_name(path: &Path) -> String {
+	let mut parts = Vec::new();
+	for c in path.components() {
+		match c {
+			Component::Normal(part) => parts.push(part.to_string_lossy()),
+			Component::ParentDir => {
+				parts.pop();
+			}
+			_ => {}
+		}
+	}
+	parts.join("/")
+}
+
+// Adds the file or directory at `path` to the archive, directories with
+// everything in them. Symbolic links are followed.
+fn append<W: Write>(out: &mut W, path: &Path) -> Result<(), CrustError> {
+	let meta = fs::metadata(path).map_err(|e| file_error(path, e))?;
+	let mut name = entry_name(path);
+	if name.is_empty() {
+		return Err(ErrorKind::Io(format!("{}: nothing to name the entry by", path.display())).into());
+	}
+	if meta.is_dir() && !name.ends_with('/') {
+		name.push('/');
+	}
+	let h = header(&name, &meta).ok_or_else(|| ErrorKind::Io(format!("{}: name too long for a tar archive", name)))?;
+	out.write_all(&h).map_err(|e| file_error(path, e))?;
+	if meta.is_dir() {
+		let mut children = fs::read_dir(path)
+			.and_then(|dir| dir.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<_>>>())
+			.map_err(|e| file_error(path, e))?;
+		children.sort();
+		for child in children {
+			append(out, &child)?;
+		}
+		return Ok(());
+	}
+	let mut file = File::open(path).map_err(|e| file_error(path, e))?;
+	let copied = io::copy(&mut file, out).map_err(|e| file_error(path, e))?;
+	if copied != meta.len() {
+		return Err(ErrorKind::Io(format!("{}: file changed while being archived", path.display())).into());
+	}
+	let padding = (BLOCK - copied as usize % BLOCK) % BLOCK;
+	out.write_all(&[0; BLOCK][..padding]).map_err(|e| file_error(path, e))
+}
+
+// (tar-create archive paths) writes the files and directories in `paths`
+// to a new archive, under the names they are given by.
+fn builtin_tar_create(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("tar-create", args, 2, Some(2))?;
+	let archive = path_arg(&args[0])?;
+	let paths = list_items(&args[1]).ok_or_else(|| wrong_type("a list of file names", &args[1]))?;
+	let file = File::create(archive).map_err(|e| file_error(archive, e))?;
+	let mut out = BufWriter::new(file);
+	for path in &paths {
+		append(&mut out, path_arg(path)?)?;
+	}
+	out.write_all(&[0; 2 * BLOCK]).and_then(|_| out.flush()).map_err(|e| file_error(archive, e))?;
+	Ok(Value::Unspecified)
+}
+
+// Where the entry `name` goes under `dest`, or None if that would be
+// outside of it.
+fn destination(dest: &Path, name: &str) -> Option<std::path::PathBuf> {
+	let name = Path::new(name);
+	let mut components = name.components().peekable();
+	components.peek()?;
+	if components.all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+		Some(dest.join(name))
+	} else {
+		None
+	}
+}
+
+// (tar-extract archive dest) extracts the files and directories in an
+// archive into the directory `dest` and returns their names. Entries that
+// would end up outside of `dest` are refused, as are links and devices.
+fn builtin_tar_extract(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("tar-extract", args, 2, Some(2))?;
+	let (archive, dest) = (path_arg(&args[0])?, path_arg(&args[1])?);
+	let corrupt = || -> CrustError { ErrorKind::Io(format!("{}: not a valid tar archive", archive.display())).into() };
+	let file = File::open(archive).map_err(|e| file_error(archive, e))?;
+	let mut input = BufReader::new(file);
+	let mut names = Vec::new();
+	loop {
+		let mut h = [0; BLOCK];
+		input.read_exact(&mut h).map_err(|_| corrupt())?;
+		if h.iter().all(|&b| b == 0) {
+			break;
+		}
+		if parse_octal(&h[148..156]) != Some(checksum(&h)) {
+			return Err(corrupt());
+		}
+		let size = parse_octal(&h[124..136]).ok_or_else(corrupt)?;
+		let field = |range: std::ops::Range<usize>| {
+			let bytes = &h[range];
+			String::from_utf8_lossy(&bytes[..bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len())]).into_owned()
+		};
+		let (prefix, name) = (field(345..500), field(0..100));
+		let name = if &h[257..262] == b"ustar" && !prefix.is_empty() { format!("{}/{}", prefix, name) } else { name };
+		let path = destination(dest, &name)
+			.ok_or_else(|| ErrorKind::Io(format!("{}: unsafe entry name {}", archive.display(), name)))?;
+		let padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+		match h[156] {
+			b'0' | 0 => {
+				if let Some(parent) = path.parent() {
+					fs::create_dir_all(parent).map_err(|e| file_error(parent, e))?;
+				}
+				let mut out = File::create(&path).map_err(|e| file_error(&path, e))?;
+				let copied = io::copy(&mut (&mut input).take(size), &mut out).map_err(|e| file_error(&path, e))?;
+				if copied != size {
+					return Err(corrupt());
+				}
+				#[cfg(unix)]
+				{
+					use std::os::unix::fs::PermissionsExt;
+					let mode = parse_octal(&h[100..108]).ok_or_else(corrupt)? as u32 & 0o777;
+					fs::set_permissions(&path, fs::Permissions::from_mode(mode)).map_err(|e| file_error(&path, e))?;
+				}
+			}
+			b'5' => fs::create_dir_all(&path).map_err(|e| file_error(&path, e))?,
+			// Extended headers hold metadata that is not kept.
+			b'x' | b'g' => {
+				io::copy(&mut (&mut input).take(size), &mut io::sink()).map_err(|_| corrupt())?;
+			}
+			_ => return Err(ErrorKind::Io(format!("{}: {} is not a file or a directory", archive.display(), name)).into())
+		}
+		io::copy(&mut (&mut input).take(padding), &mut io::sink()).map_err(|_| corrupt())?;
+		if h[156] != b'x' && h[156] != b'g' {
+			names.push(Value::Str(Rc::from(name)));
+		}
+	}
+	Ok(Value::list(names))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("tar-create", builtin_tar_create),
+		("tar-extract", builtin_tar_extract),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_header() {
+	let meta = fs::metadata("Cargo.toml").unwrap();
+	let h = header("a/b.txt", &meta).unwrap();
+	assert_eq!(&h[..8], b"a/b.txt\0");
+	assert_eq!(parse_octal(&h[124..136]), Some(meta.len()));
+	assert_eq!(parse_octal(&h[148..156]), Some(checksum(&h)));
+	let long = format!("{}/{}", "d".repeat(150), "f".repeat(100));
+	let h = header(&long, &meta).unwrap();
+	assert_eq!(&h[..100], "f".repeat(100).as_bytes());
+	assert_eq!(&h[345..495], "d".repeat(150).as_bytes());
+	assert!(header(&"f".repeat(101), &meta).is_none());
+}
+
+#[test]
+fn test_tar() {
+	let dir = super::TempDir::new();
+	let s = |p: &Path| Value::Str(Rc::from(p.to_str().unwrap()));
+	fs::create_dir_all(dir.join("in/sub")).unwrap();
+	fs::write(dir.join("in/a.txt"), "hello").unwrap();
+	fs::write(dir.join("in/sub/b.bin"), vec![7; 1000]).unwrap();
+	let archive = dir.join("x.tar");
+	builtin_tar_create(&[s(&archive), Value::list(vec![s(&dir.join("in"))])]).unwrap();
+	assert_eq!(fs::metadata(&archive).unwrap().len() % BLOCK as u64, 0);
+	let names = builtin_tar_extract(&[s(&archive), s(&dir.join("out"))]).unwrap();
+	assert_eq!(list_items(&names).unwrap().len(), 4);
+	let extracted = dir.join("out").join(dir.strip_prefix("/").unwrap());
+	assert_eq!(fs::read(extracted.join("in/a.txt")).unwrap(), b"hello");
+	assert_eq!(fs::read(extracted.join("in/sub/b.bin")).unwrap(), vec![7; 1000]);
+
+	assert_eq!(entry_name(Path::new("../f")), "f");
+	assert_eq!(entry_name(Path::new("/a/./b/../../../c/d")), "c/d");
+	fs::write(dir.join("f"), "up").unwrap();
+	let archive = dir.join("up.tar");
+	builtin_tar_create(&[s(&archive), Value::list(vec![s(&dir.join("in/../f"))])]).unwrap();
+	let names = builtin_tar_extract(&[s(&archive), s(&dir.join("up"))]).unwrap();
+	let name = entry_name(&dir.join("f"));
+	assert_eq!(names.to_string(), format!("(\"{}\")", name));
+	assert_eq!(fs::read(dir.join("up").join(name)).unwrap(), b"up");
+
+	assert!(destination(&dir, "../evil").is_none());
+	assert!(destination(&dir, "/etc/passwd").is_none());
+	assert_eq!(destination(&dir, "./a/b"), Some(dir.join("./a/b")));
+}
@@ -0,0 +1,284 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// The task runner behind `crust task`. A task file defines tasks with
+//
+//     (deftask name (deps task ...) (inputs path ...) (outputs path ...) body ...)
+//
+// where the three clauses are optional and the paths are expressions
+// evaluating to strings or lists of strings. The other top-level forms are
+// evaluated first, so the file can define helpers. A task runs after its
+// dependencies, at most once, and is skipped if it is up to date: when it
+// has outputs, none of them is older than its inputs, and none of its
+// dependencies ran.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use super::{eval, eval_body, lex, list_items, parse, CrustError, Env, ErrorKind, Interpreter, Node, NodeKind, Pos, Value};
+
+struct Task<'n> {
+	name: &'n str,
+	deps: Vec<&'n str>,
+	inputs: Vec<&'n Node>,
+	outputs: Vec<&'n Node>,
+	body: &'n [Node],
+	pos: Pos
+}
+
+fn task_error(msg: String, pos: Pos) -> CrustError {
+	CrustError::new(ErrorKind::Task(msg), pos)
+}
+
+fn symbol(node: &Node) -> Option<&str> {
+	match node.kind {
+		NodeKind::Symbol(ref s) => Some(s),
+		_ => None
+	}
+}
+
+// The task `node` defines, if it is a `deftask` form.
+fn deftask(node: &Node) -> Result<Option<Task<'_>>, CrustError> {
+	let args = match node.kind {
+		NodeKind::Application(ref f, ref args) if symbol(f) == Some("deftask") => args,
+		_ => return Ok(None)
+	};
+	let bad = || CrustError::new(ErrorKind::BadSyntax("deftask expects a name, clauses and a body".to_string()), node.span.start);
+	let name = args.first().and_then(symbol).ok_or_else(bad)?;
+	let mut task = Task { name, deps: Vec::new(), inputs: Vec::new(), outputs: Vec::new(), body: &[], pos: node.span.start };
+	let mut rest = &args[1..];
+	while let Some((clause, tail)) = rest.split_first() {
+		match clause.kind {
+			NodeKind::Application(ref f, ref items) if symbol(f) == Some("deps") => {
+				for item in items {
+					task.deps.push(symbol(item).ok_or_else(bad)?);
+				}
+			}
+			NodeKind::Application(ref f, ref items) if symbol(f) == Some("inputs") => task.inputs.extend(items),
+			NodeKind::Application(ref f, ref items) if symbol(f) == Some("outputs") => task.outputs.extend(items),
+			_ => break
+		}
+		rest = tail;
+	}
+	task.body = rest;
+	Ok(Some(task))
+}
+
+// The paths the expressions `nodes` evaluate to.
+fn paths(nodes: &[&Node], env: &Rc<Env>) -> Result<Vec<String>, CrustError> {
+	let mut paths = Vec::new();
+	for node in nodes {
+		let v = eval(node, env)?;
+		let items = match v {
+			Value::Str(_) => vec![v],
+			_ => list_items(&v).unwrap_or_else(|| vec![v.clone()])
+		};
+		for item in items {
+			match item {
+				Value::Str(ref s) => paths.push(s.to_string()),
+				ref v => return Err(super::wrong_type("a path or a list of paths", v).or_at(node.span.start))
+			}
+		}
+	}
+	Ok(paths)
+}
+
+// The time `path` was last modified, or for a directory the latest time
+// anything in it was.
+fn modified(path: &Path) -> io::Result<SystemTime> {
+	let meta = fs::metadata(path)?;
+	let mut time = meta.modified()?;
+	if meta.is_dir() {
+		for entry in fs::read_dir(path)? {
+			time = time.max(modified(&entry?.path())?);
+		}
+	}
+	Ok(time)
+}
+
+struct Runner<'n, 'r> {
+	tasks: HashMap<&'n str, Task<'n>>,
+	env: Rc<Env>,
+	// Whether each task that has been visited ran, `None` while its
+	// dependencies are being run.
+	done: HashMap<&'n str, Option<bool>>,
+	// The tasks being visited, outermost first, for reporting cycles.
+	path: Vec<&'n str>,
+	report: &'r mut dyn FnMut(&str, bool)
+}
+
+impl<'n, 'r> Runner<'n, 'r> {
+	fn up_to_date(&self, task: &Task) -> Result<bool, CrustError> {
+		if task.outputs.is_empty() {
+			return Ok(false);
+		}
+		let mut newest_input = None;
+		for input in paths(&task.inputs, &self.env)? {
+			let time = modified(Path::new(&input))
+				.map_err(|e| task_error(format!("input '{}' of task '{}': {}", input, task.name, e), task.pos))?;
+			newest_input = newest_input.max(Some(time));
+		}
+		for output in paths(&task.outputs, &self.env)? {
+			match (modified(Path::new(&output)), newest_input) {
+				(Err(_), _) => return Ok(false),
+				(Ok(time), Some(input)) if time < input => return Ok(false),
+				_ => ()
+			}
+		}
+		Ok(true)
+	}
+
+	// Runs `name` if it is not up to date, after its dependencies, and
+	// returns whether it ran. `pos` is where it was asked for.
+	fn run(&mut self, name: &'n str, pos: Option<Pos>) -> Result<bool, CrustError> {
+		match self.done.get(name) {
+			Some(&Some(ran)) => return Ok(ran),
+			Some(&None) => {
+				let start = self.path.iter().position(|&n| n == name).expect("tasks being visited are on the path");
+				let cycle = self.path[start..].join(" -> ");
+				let pos = self.tasks[name].pos;
+				return Err(task_error(format!("tasks depend on each other: {} -> {}", cycle, name), pos));
+			}
+			None => ()
+		}
+		let (deps, task_pos) = match self.tasks.get(name) {
+			Some(task) => (task.deps.clone(), task.pos),
+			None => {
+				let msg = format!("no task named '{}'", name);
+				return Err(match pos {
+					Some(pos) => task_error(msg, pos),
+					None => ErrorKind::Task(msg).into()
+				});
+			}
+		};
+		self.done.insert(name, None);
+		self.path.push(name);
+		let mut deps_ran = false;
+		for dep in deps {
+			deps_ran |= self.run(dep, Some(task_pos))?;
+		}
+		self.path.pop();
+		let task = &self.tasks[name];
+		let ran = deps_ran || !self.up_to_date(task)?;
+		(self.report)(name, ran);
+		if ran {
+			eval_body(task.body, &Env::new(Some(self.env.clone())))?;
+		}
+		self.done.insert(name, Some(ran));
+		Ok(ran)
+	}
+}
+
+/// The names of the tasks defined in the task file `source`, in order.
+pub fn list(source: &str) -> Result<Vec<String>, CrustError> {
+	let tokens = lex(source)?;
+	let roots = parse(&tokens)?;
+	let mut names = Vec::new();
+	for root in &roots {
+		if let Some(task) = deftask(root)? {
+			names.push(task.name.to_string());
+		}
+	}
+	Ok(names)
+}
+
+/// Runs the tasks `targets` of the task file `source`, or its first task
+/// if there are none, with their dependencies. The file is evaluated in
+/// `interp`, with its definitions, limits and cache setting. `report` is
+/// called with the name of each task when its turn comes and whether it
+/// is about to run, rather than being up to date.
+pub fn run(interp: &mut Interpreter, source: &str, targets: &[&str], report: &mut dyn FnMut(&str, bool))
+           -> Result<(), CrustError> {
+	let tokens = lex(source)?;
+	let roots = parse(&tokens)?;
+	interp.evaluating(|| run_tasks(&roots, interp.env.clone(), targets, report))
+}
+
+fn run_tasks(roots: &[Node], env: Rc<Env>, targets: &[&str], report: &mut dyn FnMut(&str, bool)) -> Result<(), CrustError> {
+	let mut tasks = HashMap::new();
+	let mut first = None;
+	for root in roots {
+		match deftask(root)? {
+			Some(task) => {
+				if tasks.contains_key(task.name) {
+					return Err(task_error(format!("task '{}' is defined twice", task.name), task.pos));
+				}
+				first = first.or(Some(task.name));
+				tasks.insert(task.name, task);
+			}
+			None => {
+				eval(root, &env)?;
+			}
+		}
+	}
+	let targets = match (targets, first) {
+		([], Some(first)) => vec![first],
+		([], None) => return Err(ErrorKind::Task("no tasks are defined".to_string()).into()),
+		(targets, _) => targets.to_vec()
+	};
+	let mut runner = Runner { tasks, env, done: HashMap::new(), path: Vec::new(), report };
+	for target in targets {
+		runner.run(target, None)?;
+	}
+	Ok(())
+}
+
+#[test]
+fn test_tasks() {
+	use std::time::Duration;
+	let dir = super::TempDir::new();
+	let (input, output) = (dir.join("in"), dir.join("out"));
+	fs::write(&input, "x").unwrap();
+	let source = format!("(define out {:?})\n\
+	                      (deftask all (deps build check))\n\
+	                      (deftask build (deps prepare) (inputs {:?}) (outputs (list out)) (msgpack-encode 1 out))\n\
+	                      (deftask prepare (outputs {:?}))\n\
+	                      (deftask check (deps build))\n\
+	                      (deftask loop (deps cycle))\n\
+	                      (deftask cycle (deps loop))",
+	                     output.display().to_string(), input.display().to_string(), input.display().to_string());
+	let make = |targets: &[&str]| {
+		let mut log = Vec::new();
+		run(&mut Interpreter::new(), &source, targets, &mut |name, ran| log.push(format!("{}{}", name, if ran { "" } else { " skipped" })))
+			.map(|_| log.join(", ")).map_err(|e| e.to_string())
+	};
+	assert_eq!(Ok("prepare skipped, build, check, all".to_string()), make(&[]));
+	assert!(output.exists());
+	assert_eq!(Ok("prepare skipped, build skipped, check, all".to_string()), make(&[]));
+	assert_eq!(Ok("prepare skipped, build skipped".to_string()), make(&["build"]));
+	let later = SystemTime::now() + Duration::from_secs(10);
+	fs::File::options().write(true).open(&input).unwrap().set_modified(later).unwrap();
+	assert_eq!(Ok("prepare skipped, build".to_string()), make(&["build"]));
+	assert_eq!(Err("6:1: tasks depend on each other: loop -> cycle -> loop".to_string()), make(&["loop"]));
+	assert_eq!(Err("no task named 'nope'".to_string()), make(&["nope"]));
+	assert_eq!(vec!["a", "b"], list("(deftask a) (define x 1) (deftask b (deps a) x)").unwrap());
+	let twice = run(&mut Interpreter::new(), "(deftask a) (deftask a)", &[], &mut |_, _| ()).unwrap_err();
+	assert_eq!("1:13: task 'a' is defined twice", twice.to_string());
+	// Tasks get the interpreter's builtins and limits.
+	let mut pure = Interpreter::pure();
+	pure.fuel_limit(Some(100));
+	let err = run(&mut pure, "(deftask a (msgpack-encode 1 \"out\"))", &[], &mut |_, _| ()).unwrap_err();
+	assert_eq!("1:13: unbound symbol 'msgpack-encode'", err.to_string());
+	let err = run(&mut pure, "(define (f) (f)) (deftask a (f))", &[], &mut |_, _| ()).unwrap_err();
+	assert_eq!(ErrorKind::FuelExhausted, err.kind);
+	let err = run(&mut Interpreter::new(), "(deftask a (exit 3))", &[], &mut |_, _| ()).unwrap_err();
+	assert_eq!(ErrorKind::Exit(3), err.kind);
+}
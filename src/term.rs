@@ -0,0 +1,180 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Builtins for the command-line side of scripts: prompts and progress bars.
+// They talk to the user on stderr, so that they also work in scripts whose
+// output is piped elsewhere.
+
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::Command;
+use std::rc::Rc;
+
+use super::{builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, ErrorKind, Value};
+
+pub(super) fn io_error(e: io::Error) -> CrustError {
+	ErrorKind::Io(e.to_string()).into()
+}
+
+// Runs stty on the terminal, returning what it prints.
+pub(super) fn stty(tty: &File, args: &[&str]) -> io::Result<String> {
+	let output = Command::new("stty").args(args).stdin(tty.try_clone()?).output()?;
+	if !output.status.success() {
+		return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+	}
+	Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// The optional message argument of a prompt.
+fn message<'v>(args: &'v [Value], default: &'v str) -> Result<&'v str, CrustError> {
+	match args.first() {
+		None => Ok(default),
+		Some(Value::Str(s)) => Ok(s),
+		Some(v) => Err(wrong_type("a string", v))
+	}
+}
+
+// Shows `msg` and reads a line from `input`, without its line ending, or
+// None at the end of the input.
+fn ask<R: BufRead, W: Write>(msg: &str, input: &mut R, output: &mut W) -> io::Result<Option<String>> {
+	write!(output, "{}", msg)?;
+	output.flush()?;
+	let mut line = String::new();
+	if input.read_line(&mut line)? == 0 {
+		return Ok(None);
+	}
+	let len = line.trim_end_matches(['\n', '\r']).len();
+	line.truncate(len);
+	Ok(Some(line))
+}
+
+// Asks `msg` until the answer is yes or no. The end of the input counts as
+// no.
+fn confirm<R: BufRead, W: Write>(msg: &str, input: &mut R, output: &mut W) -> io::Result<bool> {
+	loop {
+		match ask(&format!("{} [y/n] ", msg), input, output)? {
+			None => return Ok(false),
+			Some(answer) => match answer.trim().to_lowercase().as_str() {
+				"y" | "yes" => return Ok(true),
+				"n" | "no" => return Ok(false),
+				_ => ()
+			}
+		}
+	}
+}
+
+// (prompt [message]) reads a line from stdin, returning #f at the end of
+// the input.
+fn builtin_prompt(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("prompt", args, 0, Some(1))?;
+	let msg = message(args, "")?;
+	match ask(msg, &mut io::stdin().lock(), &mut io::stderr()).map_err(io_error)? {
+		Some(line) => Ok(Value::Str(Rc::from(line))),
+		None => Ok(Value::Boolean(false))
+	}
+}
+
+fn builtin_confirm(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("confirm?", args, 1, Some(1))?;
+	let msg = message(args, "")?;
+	Ok(Value::Boolean(confirm(msg, &mut io::stdin().lock(), &mut io::stderr()).map_err(io_error)?))
+}
+
+// (password-prompt [message]) reads a line from the terminal without
+// echoing it, even if stdin is redirected.
+fn builtin_password_prompt(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("password-prompt", args, 0, Some(1))?;
+	let msg = message(args, "Password: ")?;
+	let tty = File::open("/dev/tty").map_err(io_error)?;
+	let saved = stty(&tty, &["-g"]).map_err(io_error)?;
+	let res = stty(&tty, &["-echo"]).and_then(|_| ask(msg, &mut BufReader::new(&tty), &mut io::stderr()));
+	let restored = stty(&tty, &[&saved]);
+	// The newline typed by the user was not echoed either.
+	eprintln!();
+	let line = res.map_err(io_error)?;
+	restored.map_err(io_error)?;
+	Ok(line.map_or(Value::Boolean(false), |line| Value::Str(Rc::from(line))))
+}
+
+const BAR_WIDTH: usize = 30;
+
+fn bar(done: i64, total: i64) -> String {
+	let done = done.clamp(0, total);
+	let filled = (done as f64 / total as f64 * BAR_WIDTH as f64) as usize;
+	format!("\r[{}{}] {}/{}", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled), done, total)
+}
+
+// (progress-bar total) returns a procedure that, called with the amount of
+// work done so far, redraws a progress bar on stderr. The bar is finished
+// with a newline when the amount reaches `total`.
+fn builtin_progress_bar(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("progress-bar", args, 1, Some(1))?;
+	let total = match args[0] {
+		Value::Integer(n) if n > 0 => n,
+		ref v => return Err(wrong_type("a positive integer", v))
+	};
+	let finished = Cell::new(false);
+	Ok(builtin("progress", move |args| {
+		check_arity("progress", args, 1, Some(1))?;
+		let done = match args[0] {
+			Value::Integer(n) => n,
+			ref v => return Err(wrong_type("an integer", v))
+		};
+		if !finished.get() {
+			let mut err = io::stderr();
+			let end = if done >= total { "\n" } else { "" };
+			write!(err, "{}{}", bar(done, total), end).and_then(|_| err.flush()).map_err(io_error)?;
+			finished.set(done >= total);
+		}
+		Ok(Value::Unspecified)
+	}))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("prompt", builtin_prompt),
+		("confirm?", builtin_confirm),
+		("password-prompt", builtin_password_prompt),
+		("progress-bar", builtin_progress_bar),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_prompts() {
+	let mut input = io::Cursor::new("Ada\r\nmaybe\nYES\n\n");
+	let mut output = Vec::new();
+	assert_eq!(Some("Ada".to_string()), ask("Name? ", &mut input, &mut output).unwrap());
+	assert!(confirm("Sure?", &mut input, &mut output).unwrap());
+	assert_eq!(Some("".to_string()), ask("", &mut input, &mut output).unwrap());
+	assert_eq!(None, ask("More? ", &mut input, &mut output).unwrap());
+	assert!(!confirm("Sure?", &mut input, &mut output).unwrap());
+	assert_eq!("Name? Sure? [y/n] Sure? [y/n] More? Sure? [y/n] ", String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn test_progress_bar() {
+	assert_eq!("\r[..............................] 0/4", bar(0, 4));
+	assert_eq!("\r[#######.......................] 1/4", bar(1, 4));
+	assert_eq!("\r[##############################] 4/4", bar(7, 4));
+	let mut interp = super::Interpreter::new();
+	assert_eq!("#<builtin progress>", interp.eval_str("(progress-bar 10)").unwrap().to_string());
+	assert_eq!("1:1: expected a positive integer, got 0", interp.eval_str("(progress-bar 0)").unwrap_err().to_string());
+}
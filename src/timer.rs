@@ -0,0 +1,159 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Timers: `after` and `every` schedule thunks, and `run-scheduler` calls
+// them as they come due until none are left. Everything runs on the one
+// thread, so a thunk that takes long delays the timers after it.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::{apply, signal, builtin, check_arity, wrong_type, CrustError, Env, Value};
+
+struct Timer {
+	id: i64,
+	due: Instant,
+	// How often to run again, for timers made by `every`.
+	period: Option<Duration>,
+	thunk: Value
+}
+
+#[derive(Default)]
+struct Scheduler {
+	next_id: i64,
+	timers: Vec<Timer>
+}
+
+impl Scheduler {
+	fn add(&mut self, delay: Duration, period: Option<Duration>, thunk: Value) -> Value {
+		self.next_id += 1;
+		self.timers.push(Timer { id: self.next_id, due: Instant::now() + delay, period, thunk });
+		Value::Integer(self.next_id)
+	}
+
+	// The timer that is due first, with ties going to the oldest.
+	fn next(&self) -> Option<usize> {
+		(0..self.timers.len()).min_by_key(|&i| (self.timers[i].due, self.timers[i].id))
+	}
+}
+
+fn millis(v: &Value, min: i64) -> Result<Duration, CrustError> {
+	match *v {
+		Value::Integer(ms) if ms >= min => Ok(Duration::from_millis(ms as u64)),
+		ref v => Err(wrong_type(if min > 0 { "a positive integer" } else { "a non-negative integer" }, v))
+	}
+}
+
+fn thunk(v: &Value) -> Result<Value, CrustError> {
+	match *v {
+		Value::Builtin(_) | Value::Procedure(_) => Ok(v.clone()),
+		ref v => Err(wrong_type("a procedure", v))
+	}
+}
+
+// Calls the timers as they come due until there are none left. A periodic
+// timer is rescheduled before its thunk runs, so that the thunk can cancel
+// it. An error in a thunk stops the loop, leaving the timers in place.
+fn run(scheduler: &RefCell<Scheduler>) -> Result<(), CrustError> {
+	loop {
+		let (due, thunk) = {
+			let mut s = scheduler.borrow_mut();
+			let i = match s.next() {
+				Some(i) => i,
+				None => return Ok(())
+			};
+			let due = s.timers[i].due;
+			let thunk = s.timers[i].thunk.clone();
+			match s.timers[i].period {
+				Some(period) => s.timers[i].due += period,
+				None => {
+					s.timers.remove(i);
+				}
+			}
+			(due, thunk)
+		};
+		signal::sleep(due.saturating_duration_since(Instant::now()))?;
+		apply(thunk, Vec::new(), None)?;
+	}
+}
+
+// Defines `(after ms thunk)` and `(every ms thunk)`, which return a handle
+// for `(cancel handle)`, and `(run-scheduler)`. Timers still pending when
+// the interpreter goes away keep it alive, as their thunks refer to it.
+pub(super) fn define(env: &Env) {
+	let scheduler = Rc::new(RefCell::new(Scheduler::default()));
+
+	let s = scheduler.clone();
+	env.define(Rc::from("after"), builtin("after", move |args| {
+		check_arity("after", args, 2, Some(2))?;
+		let delay = millis(&args[0], 0)?;
+		Ok(s.borrow_mut().add(delay, None, thunk(&args[1])?))
+	}));
+
+	let s = scheduler.clone();
+	env.define(Rc::from("every"), builtin("every", move |args| {
+		check_arity("every", args, 2, Some(2))?;
+		let period = millis(&args[0], 1)?;
+		Ok(s.borrow_mut().add(period, Some(period), thunk(&args[1])?))
+	}));
+
+	// Returns whether the timer was still pending.
+	let s = scheduler.clone();
+	env.define(Rc::from("cancel"), builtin("cancel", move |args| {
+		check_arity("cancel", args, 1, Some(1))?;
+		let id = match args[0] {
+			Value::Integer(id) => id,
+			ref v => return Err(wrong_type("a timer", v))
+		};
+		let mut s = s.borrow_mut();
+		let before = s.timers.len();
+		s.timers.retain(|t| t.id != id);
+		Ok(Value::Boolean(s.timers.len() != before))
+	}));
+
+	env.define(Rc::from("run-scheduler"), builtin("run-scheduler", move |args| {
+		check_arity("run-scheduler", args, 0, Some(0))?;
+		run(&scheduler)?;
+		Ok(Value::Unspecified)
+	}));
+}
+
+#[test]
+fn test_scheduler() {
+	let log = Rc::new(RefCell::new(Vec::new()));
+	let mut interp = super::Interpreter::new();
+	let l = log.clone();
+	interp.register("log", move |args| {
+		l.borrow_mut().push(args[0].to_string());
+		Ok(Value::Unspecified)
+	});
+	let start = Instant::now();
+	let program = "
+		(define tick (every 10 (lambda () (log 'tick))))
+		(after 35 (lambda () (log 'stop) (cancel tick)))
+		(after 0 (lambda () (log 'first)))
+		(define never (after 5 (lambda () (log 'never))))
+		(cancel never)
+		(run-scheduler)";
+	interp.eval_str(program).unwrap();
+	assert!(start.elapsed() >= Duration::from_millis(35));
+	assert_eq!(*log.borrow(), ["first", "tick", "tick", "tick", "stop"]);
+	assert_eq!(interp.eval_str("(cancel tick)").unwrap().to_string(), "#f");
+	assert!(interp.eval_str("(every 0 (lambda () 1))").is_err());
+	assert!(interp.eval_str("(after 1 2)").is_err());
+}
@@ -0,0 +1,151 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Terminal builtins for interactive programs, behind the `tui` feature.
+// Output uses ANSI escape codes, and reading single keypresses puts the
+// terminal in raw mode with stty, which keeps crust free of dependencies
+// but limits this to Unix-like systems.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use super::term::{io_error, stty};
+use super::{builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, Value};
+
+fn write(s: &str) -> Result<Value, CrustError> {
+	let mut out = io::stdout();
+	out.write_all(s.as_bytes()).and_then(|_| out.flush()).map_err(io_error)?;
+	Ok(Value::Unspecified)
+}
+
+fn builtin_clear_screen(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("clear-screen", args, 0, Some(0))?;
+	write("\x1b[2J\x1b[H")
+}
+
+// (move-cursor row column), both counting from 1 at the top left.
+fn builtin_move_cursor(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("move-cursor", args, 2, Some(2))?;
+	let mut at = [0; 2];
+	for (n, v) in at.iter_mut().zip(args) {
+		*n = match *v {
+			Value::Integer(i) if i >= 1 => i,
+			ref v => return Err(wrong_type("a positive integer", v))
+		};
+	}
+	write(&format!("\x1b[{};{}H", at[0], at[1]))
+}
+
+fn colour_code(name: &str) -> Option<u8> {
+	let colours = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+	colours.iter().position(|&c| c == name).map(|i| 30 + i as u8)
+}
+
+// The text of `s` in `colour`, or as it is if there is no colour.
+fn coloured(s: &str, colour: Option<&Value>) -> Result<String, CrustError> {
+	match colour {
+		None => Ok(s.to_string()),
+		Some(Value::Symbol(name)) if colour_code(name).is_some() => {
+			Ok(format!("\x1b[{}m{}\x1b[0m", colour_code(name).unwrap(), s))
+		}
+		Some(v) => Err(wrong_type("a colour (black, red, green, yellow, blue, magenta, cyan or white)", v))
+	}
+}
+
+// (put-text string [colour]) writes the characters of `string`, unlike the
+// REPL, which prints strings as literals.
+fn builtin_put_text(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("put-text", args, 1, Some(2))?;
+	match args[0] {
+		Value::Str(ref s) => write(&coloured(s, args.get(1))?),
+		ref v => Err(wrong_type("a string", v))
+	}
+}
+
+// The value for the bytes of a keypress: the arrow keys are the symbols
+// up, down, right and left, anything else is a string.
+fn key(bytes: &[u8]) -> Value {
+	match bytes {
+		b"\x1b[A" => Value::Symbol(Rc::from("up")),
+		b"\x1b[B" => Value::Symbol(Rc::from("down")),
+		b"\x1b[C" => Value::Symbol(Rc::from("right")),
+		b"\x1b[D" => Value::Symbol(Rc::from("left")),
+		_ => Value::Str(Rc::from(String::from_utf8_lossy(bytes)))
+	}
+}
+
+fn read_key(tty: &mut File) -> io::Result<Vec<u8>> {
+	let mut bytes = vec![0];
+	tty.read_exact(&mut bytes)?;
+	let len = match bytes[0] {
+		0xf0..=0xff => 4,
+		0xe0..=0xef => 3,
+		0xc0..=0xdf => 2,
+		_ => 1
+	};
+	if bytes[0] == 0x1b {
+		// Escape on its own or the start of a sequence: wait a tenth of a
+		// second for the rest.
+		stty(tty, &["min", "0", "time", "1"])?;
+		let mut rest = [0; 2];
+		let n = tty.read(&mut rest)?;
+		bytes.extend_from_slice(&rest[..n]);
+	} else if len > 1 {
+		let mut rest = vec![0; len - 1];
+		tty.read_exact(&mut rest)?;
+		bytes.extend(rest);
+	}
+	Ok(bytes)
+}
+
+// (read-key) waits for a key to be pressed, without waiting for enter or
+// echoing it.
+fn builtin_read_key(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("read-key", args, 0, Some(0))?;
+	let mut tty = File::open("/dev/tty").map_err(io_error)?;
+	let saved = stty(&tty, &["-g"]).map_err(io_error)?;
+	let res = stty(&tty, &["raw", "-echo"]).and_then(|_| read_key(&mut tty));
+	let restored = stty(&tty, &[&saved]);
+	let bytes = res.map_err(io_error)?;
+	restored.map_err(io_error)?;
+	Ok(key(&bytes))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("clear-screen", builtin_clear_screen),
+		("move-cursor", builtin_move_cursor),
+		("put-text", builtin_put_text),
+		("read-key", builtin_read_key),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_tui() {
+	assert_eq!("\x1b[31mhi\x1b[0m", coloured("hi", Some(&Value::Symbol(Rc::from("red")))).unwrap());
+	assert_eq!("hi", coloured("hi", None).unwrap());
+	assert!(coloured("hi", Some(&Value::Symbol(Rc::from("mauve")))).is_err());
+	assert_eq!("up", key(b"\x1b[A").to_string());
+	assert_eq!("\"λ\"", key("λ".as_bytes()).to_string());
+	assert_eq!("\"\\r\"", key(b"\r").to_string());
+	let mut interp = super::Interpreter::new();
+	assert_eq!("1:1: expected a positive integer, got 0", interp.eval_str("(move-cursor 0 1)").unwrap_err().to_string());
+}
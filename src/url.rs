@@ -0,0 +1,203 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// URL builtins: splitting a URL into its parts after RFC 3986, and percent
+// encoding. crust has no hash tables, so the parts of a URL come back as an
+// association list. Its entries are `(key value)` lists rather than pairs,
+// since those can also be written as quoted data.
+
+use std::rc::Rc;
+
+use super::{builtin, check_arity, list_items, wrong_type, BuiltinFn, CrustError, Env, Value};
+
+fn string_arg<'v>(name: &str, args: &'v [Value]) -> Result<&'v str, CrustError> {
+	check_arity(name, args, 1, Some(1))?;
+	match args[0] {
+		Value::Str(ref s) => Ok(s),
+		ref v => Err(wrong_type("a string", v))
+	}
+}
+
+// Splits `s` at the first of `delimiters`, which stays with the rest.
+fn split_at_any<'s>(s: &'s str, delimiters: &[char]) -> (&'s str, &'s str) {
+	s.split_at(s.find(delimiters).unwrap_or(s.len()))
+}
+
+// The parts of `url` that are present, in order: scheme, user, host, port,
+// path, query and fragment.
+pub(super) fn parse(url: &str) -> Option<Vec<(&'static str, Value)>> {
+	let str = |s: &str| Value::Str(Rc::from(s));
+	let (scheme, rest) = url.split_once(':')?;
+	let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic()) &&
+		scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+	if !valid_scheme {
+		return None;
+	}
+	let mut parts = vec![("scheme", str(&scheme.to_lowercase()))];
+	let (rest, fragment) = match rest.split_once('#') {
+		Some((rest, fragment)) => (rest, Some(fragment)),
+		None => (rest, None)
+	};
+	let (rest, query) = match rest.split_once('?') {
+		Some((rest, query)) => (rest, Some(query)),
+		None => (rest, None)
+	};
+	let path = match rest.strip_prefix("//") {
+		Some(rest) => {
+			let (authority, path) = split_at_any(rest, &['/']);
+			let host = match authority.rsplit_once('@') {
+				Some((user, host)) => {
+					parts.push(("user", str(user)));
+					host
+				}
+				None => authority
+			};
+			// The colon of an IPv6 address in brackets is not a port.
+			let (host, port) = match host.rsplit_once(':') {
+				Some((host, port)) if !port.contains(']') => (host, Some(port)),
+				_ => (host, None)
+			};
+			parts.push(("host", str(&host.to_lowercase())));
+			match port {
+				Some("") | None => (),
+				Some(port) => parts.push(("port", Value::Integer(port.parse::<u16>().ok()?.into())))
+			}
+			path
+		}
+		None => rest
+	};
+	parts.push(("path", str(path)));
+	if let Some(query) = query {
+		parts.push(("query", str(query)));
+	}
+	if let Some(fragment) = fragment {
+		parts.push(("fragment", str(fragment)));
+	}
+	Some(parts)
+}
+
+// (url-parse url) returns an association list like ((scheme "https")
+// (host "example.com") (path "/")), leaving out the parts `url` does not
+// have.
+fn builtin_url_parse(args: &[Value]) -> Result<Value, CrustError> {
+	let url = string_arg("url-parse", args)?;
+	let parts = parse(url).ok_or_else(|| wrong_type("a URL", &args[0]))?;
+	Ok(Value::list(parts.into_iter().map(|(k, v)| Value::list(vec![Value::Symbol(Rc::from(k)), v])).collect()))
+}
+
+fn encode(s: &str) -> String {
+	let mut res = String::new();
+	for b in s.bytes() {
+		if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+			res.push(b as char);
+		} else {
+			res.push_str(&format!("%{:02X}", b));
+		}
+	}
+	res
+}
+
+fn decode(s: &str) -> Option<String> {
+	let mut bytes = Vec::new();
+	let mut rest = s.as_bytes();
+	while let Some((&b, tail)) = rest.split_first() {
+		if b == b'%' {
+			let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+			bytes.push(u8::from_str_radix(hex, 16).ok()?);
+			rest = &tail[2..];
+		} else {
+			bytes.push(b);
+			rest = tail;
+		}
+	}
+	String::from_utf8(bytes).ok()
+}
+
+// (url-encode s) percent-encodes everything but letters, digits and -_.~,
+// so that `s` can be used as any part of a URL.
+fn builtin_url_encode(args: &[Value]) -> Result<Value, CrustError> {
+	Ok(Value::Str(Rc::from(encode(string_arg("url-encode", args)?))))
+}
+
+fn builtin_url_decode(args: &[Value]) -> Result<Value, CrustError> {
+	let s = string_arg("url-decode", args)?;
+	let decoded = decode(s).ok_or_else(|| wrong_type("a percent-encoded UTF-8 string", &args[0]))?;
+	Ok(Value::Str(Rc::from(decoded)))
+}
+
+// The text of a key or value in a query string.
+fn query_text(v: &Value) -> Result<String, CrustError> {
+	match *v {
+		Value::Str(ref s) | Value::Symbol(ref s) => Ok(encode(s)),
+		Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Ok(encode(&v.to_string())),
+		ref v => Err(wrong_type("a string, symbol or number", v))
+	}
+}
+
+// (query-string '((key value) ...)) builds `key=value&...`, encoding keys
+// and values.
+fn builtin_query_string(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("query-string", args, 1, Some(1))?;
+	let items = list_items(&args[0]).ok_or_else(|| wrong_type("an association list", &args[0]))?;
+	let mut pairs = Vec::new();
+	for item in &items {
+		match list_items(item).as_deref() {
+			Some([key, value]) => pairs.push(format!("{}={}", query_text(key)?, query_text(value)?)),
+			_ => return Err(wrong_type("a (key value) list", item))
+		}
+	}
+	Ok(Value::Str(Rc::from(pairs.join("&"))))
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("url-parse", builtin_url_parse),
+		("url-encode", builtin_url_encode),
+		("url-decode", builtin_url_decode),
+		("query-string", builtin_query_string),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_url_parse() {
+	let parse = |url| super::eval_str(&format!("(url-parse \"{}\")", url)).unwrap();
+	assert_eq!("((scheme \"https\") (user \"ada\") (host \"example.com\") (port 8080) (path \"/a/b\") \
+	            (query \"x=1&y\") (fragment \"top\"))",
+	           parse("HTTPS://ada@Example.COM:8080/a/b?x=1&y#top"));
+	assert_eq!("((scheme \"http\") (host \"[::1]\") (path \"\"))", parse("http://[::1]"));
+	assert_eq!("((scheme \"http\") (host \"[::1]\") (port 80) (path \"/\"))", parse("http://[::1]:80/"));
+	assert_eq!("((scheme \"mailto\") (path \"ada@example.com\"))", parse("mailto:ada@example.com"));
+	assert_eq!("((scheme \"file\") (host \"\") (path \"/tmp/x\"))", parse("file:///tmp/x"));
+	let error = |src| super::eval_str(src).unwrap_err().to_string();
+	assert_eq!("1:1: expected a URL, got \"example.com\"", error("(url-parse \"example.com\")"));
+	assert_eq!("1:1: expected a URL, got \"http://h:99999\"", error("(url-parse \"http://h:99999\")"));
+}
+
+#[test]
+fn test_url_encoding() {
+	assert_eq!("a%20b%26c%3D%C3%A5-_.~", encode("a b&c=å-_.~"));
+	assert_eq!(Some("a b&c=å+".to_string()), decode("a%20b%26c%3d%C3%A5+"));
+	assert_eq!(None, decode("%2"));
+	assert_eq!(None, decode("%zz"));
+	assert_eq!(None, decode("%ff"));
+	assert_eq!("\"q=a%20b&page=2&lang=sv\"", super::eval_str("(query-string '((q \"a b\") (page 2) (lang sv)))").unwrap());
+	assert_eq!("\"\"", super::eval_str("(query-string '())").unwrap());
+	assert_eq!("1:1: expected a (key value) list, got (q)", super::eval_str("(query-string '((q)))").unwrap_err().to_string());
+}
@@ -0,0 +1,161 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Watching files for changes. crust has no dependencies to reach the
+// operating system's notification APIs with, so this polls: the watched
+// tree is listed at an interval and compared with the previous listing.
+// That is portable and cheap enough for the trees a script watches, at the
+// cost of a little latency and of missing changes undone within the
+// interval.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+use super::{apply, signal, builtin, check_arity, wrong_type, BuiltinFn, CrustError, Env, Value};
+
+const INTERVAL: Duration = Duration::from_millis(200);
+
+// What a file looked like when listed: its modification time and size.
+type Snapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+// Lists `path` and, if it is a directory, everything in it. Files that
+// disappear while being listed are left out. A symbolic link inside the
+// tree is listed as the link, without following it, so that a link to a
+// directory above cannot make the listing go round forever; the watched
+// path itself is followed.
+fn snapshot(path: &Path, files: &mut Snapshot) {
+	list(path, fs::metadata(path), files);
+}
+
+fn list(path: &Path, meta: std::io::Result<fs::Metadata>, files: &mut Snapshot) {
+	let meta = match meta {
+		Ok(meta) => meta,
+		Err(_) => return
+	};
+	files.insert(path.to_path_buf(), (meta.modified().ok(), meta.len()));
+	if meta.is_dir() {
+		if let Ok(dir) = fs::read_dir(path) {
+			for entry in dir.flatten() {
+				let path = entry.path();
+				list(&path, fs::symlink_metadata(&path), files);
+			}
+		}
+	}
+}
+
+// The changes from `old` to `new`, in path order.
+fn changes(old: &Snapshot, new: &Snapshot) -> Vec<(&'static str, PathBuf)> {
+	let mut events = Vec::new();
+	let mut paths: Vec<&PathBuf> = old.keys().chain(new.keys()).collect();
+	paths.sort();
+	paths.dedup();
+	for path in paths {
+		match (old.get(path), new.get(path)) {
+			(None, Some(_)) => events.push(("create", path.clone())),
+			(Some(_), None) => events.push(("delete", path.clone())),
+			(Some(before), Some(after)) if before != after => events.push(("modify", path.clone())),
+			_ => ()
+		}
+	}
+	events
+}
+
+// (watch-path path handler) watches a file, or a directory and everything
+// in it, calling (handler kind path) for each change, where kind is one of
+// the symbols create, modify and delete. Watching stops once the handler
+// returns #f.
+fn builtin_watch_path(args: &[Value]) -> Result<Value, CrustError> {
+	check_arity("watch-path", args, 2, Some(2))?;
+	let path = match args[0] {
+		Value::Str(ref s) => Path::new(&**s),
+		ref v => return Err(wrong_type("a file name", v))
+	};
+	let handler = args[1].clone();
+	let mut files = Snapshot::new();
+	snapshot(path, &mut files);
+	loop {
+		signal::sleep(INTERVAL)?;
+		let mut now = Snapshot::new();
+		snapshot(path, &mut now);
+		for (kind, changed) in changes(&files, &now) {
+			let event = vec![Value::Symbol(Rc::from(kind)), Value::Str(Rc::from(changed.to_string_lossy().as_ref()))];
+			if let Value::Boolean(false) = apply(handler.clone(), event, None)? {
+				return Ok(Value::Unspecified);
+			}
+		}
+		files = now;
+	}
+}
+
+pub(super) fn define(env: &Env) {
+	let builtins: &[(&str, BuiltinFn)] = &[
+		("watch-path", builtin_watch_path),
+	];
+	for &(name, f) in builtins {
+		env.define(Rc::from(name), builtin(name, f));
+	}
+}
+
+#[test]
+fn test_changes() {
+	let t = SystemTime::UNIX_EPOCH;
+	let old: Snapshot = vec![(PathBuf::from("a"), (Some(t), 1)), (PathBuf::from("b"), (Some(t), 1))].into_iter().collect();
+	let new: Snapshot = vec![(PathBuf::from("b"), (Some(t), 2)), (PathBuf::from("c"), (Some(t), 1))].into_iter().collect();
+	let events = changes(&old, &new);
+	assert_eq!(events, vec![("delete", PathBuf::from("a")), ("modify", PathBuf::from("b")), ("create", PathBuf::from("c"))]);
+	assert!(changes(&new, &new).is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_snapshot_symlinks() {
+	use std::os::unix::fs::symlink;
+	let dir = super::TempDir::new();
+	fs::create_dir_all(dir.join("sub")).unwrap();
+	fs::write(dir.join("sub/file"), "x").unwrap();
+	symlink(&dir, dir.join("sub/up")).unwrap();
+	symlink(dir.join("sub"), dir.join("link")).unwrap();
+	let mut files = Snapshot::new();
+	snapshot(&dir, &mut files);
+	let names: Vec<&Path> = files.keys().map(|p| p.strip_prefix(&dir).unwrap()).collect();
+	assert_eq!(names, ["", "link", "sub", "sub/file", "sub/up"].iter().map(Path::new).collect::<Vec<_>>());
+	// The watched path is followed when it is a link.
+	let mut files = Snapshot::new();
+	snapshot(&dir.join("link"), &mut files);
+	assert_eq!(files.len(), 3);
+}
+
+#[test]
+fn test_watch_path() {
+	let dir = super::TempDir::new();
+	let file = dir.join("new.txt");
+	let writer = {
+		let file = file.clone();
+		std::thread::spawn(move || {
+			std::thread::sleep(INTERVAL * 2);
+			fs::write(file, "x").unwrap();
+		})
+	};
+	// The directory changes too, so this stops at the right event only.
+	let program = format!("(watch-path {:?} (lambda (kind path) (not (and (eq? kind 'create) (equal? path {:?})))))",
+		dir.to_str().unwrap(), file.to_str().unwrap());
+	super::Interpreter::new().eval_str(&program).unwrap();
+	writer.join().unwrap();
+}
@@ -0,0 +1,478 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// A WebSocket client after RFC 6455, behind the `websocket` feature. Only
+// ws:// URLs work, as wss:// needs TLS. A connection is a socket that crust
+// values cannot hold, so `ws-connect` returns a handle for it, as `after`
+// does for timers.
+
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::base64::base64_encode;
+use super::hash::sha1;
+use super::{builtin, check_arity, url, wrong_type, CrustError, Env, ErrorKind, Value};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// The largest message `ws-receive` takes, counting all of its fragments. A
+// larger one closes the connection, as it would otherwise all be held in
+// memory.
+const MAX_MESSAGE: usize = 16 << 20;
+
+// Close status codes.
+const UNSUPPORTED_DATA: u16 = 1003;
+const TOO_BIG: u16 = 1009;
+
+// Random enough for handshake keys and masks, which only need to be
+// unpredictable to proxies.
+fn random() -> u64 {
+	RandomState::new().build_hasher().finish()
+}
+
+enum Received {
+	Message(Vec<u8>),
+	Timeout,
+	Closed
+}
+
+struct Connection {
+	stream: TcpStream,
+	// Bytes received but not yet parsed into frames.
+	buf: Vec<u8>,
+	// The fragments of the message being received.
+	partial: Vec<u8>,
+	// Whether a message has started arriving in `partial`.
+	receiving: bool,
+	// Whether we have sent a close frame, and whether the server has.
+	close_sent: bool,
+	closed: bool
+}
+
+struct Frame {
+	fin: bool,
+	opcode: u8,
+	payload: Vec<u8>,
+	// The length of the whole frame in bytes.
+	used: usize
+}
+
+// The payload length in the header of the frame at the start of `buf`, or
+// None if the header has not been received in full.
+fn payload_len(buf: &[u8]) -> Option<(u64, usize)> {
+	match buf.get(1)? & 0x7f {
+		126 => Some((u16::from_be_bytes([*buf.get(2)?, *buf.get(3)?]) as u64, 4)),
+		127 => {
+			let mut n = [0; 8];
+			n.copy_from_slice(buf.get(2..10)?);
+			Some((u64::from_be_bytes(n), 10))
+		}
+		n => Some((n as u64, 2))
+	}
+}
+
+// The frame at the start of `buf`, or None if it has not been received in
+// full. A data frame with a payload of more than `limit` bytes is refused
+// with its length as soon as its header is in, so that it is never
+// buffered.
+fn parse_frame(buf: &[u8], limit: usize) -> Result<Option<Frame>, u64> {
+	let (len, mut pos) = match payload_len(buf) {
+		Some(header) => header,
+		None => return Ok(None)
+	};
+	let (b0, b1) = (buf[0], buf[1]);
+	let len = match usize::try_from(len) {
+		Ok(n) if n <= limit || b0 & 0x08 != 0 => n,
+		_ => return Err(len)
+	};
+	let mask = if b1 & 0x80 != 0 {
+		match buf.get(pos..pos + 4) {
+			Some(mask) => {
+				pos += 4;
+				[mask[0], mask[1], mask[2], mask[3]]
+			}
+			None => return Ok(None)
+		}
+	} else {
+		[0; 4]
+	};
+	let payload = match pos.checked_add(len).and_then(|end| buf.get(pos..end)) {
+		Some(payload) => payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect(),
+		None => return Ok(None)
+	};
+	Ok(Some(Frame { fin: b0 & 0x80 != 0, opcode: b0 & 0x0f, payload, used: pos + len }))
+}
+
+// A final frame from the client, which must be masked.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+	let mut out = vec![0x80 | opcode];
+	match payload.len() {
+		n if n < 126 => out.push(0x80 | n as u8),
+		n if n <= 0xffff => {
+			out.push(0x80 | 126);
+			out.extend((n as u16).to_be_bytes());
+		}
+		n => {
+			out.push(0x80 | 127);
+			out.extend((n as u64).to_be_bytes());
+		}
+	}
+	let mask = (random() as u32).to_be_bytes();
+	out.extend(mask);
+	out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+	out
+}
+
+fn protocol_error(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl Connection {
+	fn open(host: &str, port: u16, resource: &str) -> io::Result<Connection> {
+		let mut stream = TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port))?;
+		let key = base64_encode(&[random().to_be_bytes(), random().to_be_bytes()].concat());
+		write!(stream, "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+		                Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n", resource, host, port, key)?;
+		// Read the response a byte at a time, so that frames the server
+		// sends right after it stay in the socket.
+		let mut reader = BufReader::with_capacity(1, stream.try_clone()?);
+		let mut status = String::new();
+		reader.read_line(&mut status)?;
+		if status.split_whitespace().nth(1) != Some("101") {
+			return Err(protocol_error(&format!("the server refused the upgrade: {}", status.trim_end())));
+		}
+		let expected = base64_encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+		let mut accepted = false;
+		loop {
+			let mut line = String::new();
+			if reader.read_line(&mut line)? == 0 {
+				return Err(protocol_error("the connection closed during the handshake"));
+			}
+			let line = line.trim_end();
+			if line.is_empty() {
+				break;
+			}
+			if let Some((name, value)) = line.split_once(':') {
+				accepted |= name.eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected;
+			}
+		}
+		if !accepted {
+			return Err(protocol_error("the server did not accept the handshake"));
+		}
+		Ok(Connection { stream, buf: Vec::new(), partial: Vec::new(), receiving: false, close_sent: false, closed: false })
+	}
+
+	fn send(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+		self.stream.write_all(&frame(opcode, payload))
+	}
+
+	// Closes the connection with `status` because of a message that cannot
+	// be received, and returns the error for it.
+	fn fail(&mut self, status: u16, msg: &str) -> io::Error {
+		if !self.close_sent {
+			self.close_sent = true;
+			let _ = self.send(8, &status.to_be_bytes());
+		}
+		self.closed = true;
+		self.buf.clear();
+		self.partial.clear();
+		protocol_error(msg)
+	}
+
+	// The next message, answering pings and closes on the way. Partial
+	// frames stay buffered when the timeout passes. Only text messages are
+	// taken; a binary message closes the connection, as crust strings
+	// cannot hold arbitrary bytes.
+	fn receive(&mut self, timeout: Option<Duration>) -> io::Result<Received> {
+		let deadline = timeout.map(|t| Instant::now() + t);
+		loop {
+			loop {
+				let frame = match parse_frame(&self.buf, MAX_MESSAGE - self.partial.len()) {
+					Ok(Some(frame)) => frame,
+					Ok(None) => break,
+					Err(_) => {
+						let msg = format!("message larger than {} bytes", MAX_MESSAGE);
+						return Err(self.fail(TOO_BIG, &msg));
+					}
+				};
+				self.buf.drain(..frame.used);
+				match frame.opcode {
+					2 if !self.receiving => return Err(self.fail(UNSUPPORTED_DATA, "binary messages are not supported")),
+					0..=2 => {
+						self.receiving = true;
+						self.partial.extend(frame.payload);
+						if frame.fin {
+							self.receiving = false;
+							return Ok(Received::Message(mem::take(&mut self.partial)));
+						}
+					}
+					8 => {
+						self.closed = true;
+						if !self.close_sent {
+							self.close_sent = true;
+							let _ = self.send(8, &frame.payload[..frame.payload.len().min(2)]);
+						}
+						return Ok(Received::Closed);
+					}
+					9 => self.send(10, &frame.payload)?,
+					_ => ()
+				}
+			}
+			if self.closed {
+				return Ok(Received::Closed);
+			}
+			let wait = match deadline {
+				Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+					Some(wait) if !wait.is_zero() => Some(wait),
+					_ => return Ok(Received::Timeout)
+				},
+				None => None
+			};
+			self.stream.set_read_timeout(wait)?;
+			let mut chunk = [0; 4096];
+			match self.stream.read(&mut chunk) {
+				Ok(0) => {
+					self.closed = true;
+					return Ok(Received::Closed);
+				}
+				Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+				Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+					return Ok(Received::Timeout);
+				}
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+				Err(e) => return Err(e)
+			}
+		}
+	}
+}
+
+fn ws_error(e: io::Error) -> CrustError {
+	ErrorKind::Io(format!("websocket: {}", e)).into()
+}
+
+#[derive(Default)]
+struct Connections {
+	next_id: i64,
+	open: HashMap<i64, Connection>
+}
+
+impl Connections {
+	fn get(&mut self, v: &Value) -> Result<&mut Connection, CrustError> {
+		match *v {
+			Value::Integer(id) if self.open.contains_key(&id) => Ok(self.open.get_mut(&id).expect("just checked")),
+			ref v => Err(wrong_type("an open websocket", v))
+		}
+	}
+}
+
+// Defines `(ws-connect url)`, which returns a handle for the connection,
+// `(ws-send ws string)`, `(ws-receive ws [timeout-ms])` and `(ws-close ws)`.
+pub(super) fn define(env: &Env) {
+	let connections = Rc::new(RefCell::new(Connections::default()));
+
+	let c = connections.clone();
+	env.define(Rc::from("ws-connect"), builtin("ws-connect", move |args| {
+		check_arity("ws-connect", args, 1, Some(1))?;
+		let parts = match args[0] {
+			Value::Str(ref s) => url::parse(s),
+			_ => None
+		};
+		let parts = parts.ok_or_else(|| wrong_type("a ws:// URL", &args[0]))?;
+		let part = |name| parts.iter().find(|p| p.0 == name).map(|p| &p.1);
+		let (host, path) = match (part("scheme"), part("host"), part("path")) {
+			(Some(Value::Str(scheme)), Some(Value::Str(host)), Some(Value::Str(path))) if &**scheme == "ws" => (host, path),
+			_ => return Err(wrong_type("a ws:// URL", &args[0]))
+		};
+		let port = match part("port") {
+			Some(&Value::Integer(port)) => port as u16,
+			_ => 80
+		};
+		let mut resource = if path.is_empty() { "/".to_string() } else { path.to_string() };
+		if let Some(Value::Str(query)) = part("query") {
+			resource = format!("{}?{}", resource, query);
+		}
+		let connection = Connection::open(host, port, &resource).map_err(ws_error)?;
+		let mut c = c.borrow_mut();
+		c.next_id += 1;
+		let id = c.next_id;
+		c.open.insert(id, connection);
+		Ok(Value::Integer(id))
+	}));
+
+	let c = connections.clone();
+	env.define(Rc::from("ws-send"), builtin("ws-send", move |args| {
+		check_arity("ws-send", args, 2, Some(2))?;
+		let text = match args[1] {
+			Value::Str(ref s) => s.clone(),
+			ref v => return Err(wrong_type("a string", v))
+		};
+		c.borrow_mut().get(&args[0])?.send(1, text.as_bytes()).map_err(ws_error)?;
+		Ok(Value::Unspecified)
+	}));
+
+	// Returns the next message as a string, #f if none arrives before the
+	// timeout, and the symbol closed once the connection has closed.
+	let c = connections.clone();
+	env.define(Rc::from("ws-receive"), builtin("ws-receive", move |args| {
+		check_arity("ws-receive", args, 1, Some(2))?;
+		let timeout = match args.get(1) {
+			None => None,
+			Some(&Value::Integer(ms)) if ms >= 0 => Some(Duration::from_millis(ms as u64)),
+			Some(v) => return Err(wrong_type("a non-negative integer", v))
+		};
+		match c.borrow_mut().get(&args[0])?.receive(timeout).map_err(ws_error)? {
+			Received::Message(bytes) => Ok(Value::Str(Rc::from(String::from_utf8_lossy(&bytes).as_ref()))),
+			Received::Timeout => Ok(Value::Boolean(false)),
+			Received::Closed => Ok(Value::Symbol(Rc::from("closed")))
+		}
+	}));
+
+	env.define(Rc::from("ws-close"), builtin("ws-close", move |args| {
+		check_arity("ws-close", args, 1, Some(1))?;
+		let mut c = connections.borrow_mut();
+		let connection = c.get(&args[0])?;
+		if !connection.close_sent {
+			connection.close_sent = true;
+			connection.send(8, &1000u16.to_be_bytes()).map_err(ws_error)?;
+		}
+		// Wait a little for the server's close, as the protocol asks.
+		while let Ok(Received::Message(_)) = connection.receive(Some(Duration::from_secs(1))) {}
+		if let Value::Integer(id) = args[0] {
+			c.open.remove(&id);
+		}
+		Ok(Value::Unspecified)
+	}));
+}
+
+// Accepts a connection on `listener` and completes the handshake, returning
+// the stream, a reader for it and the request line.
+#[cfg(test)]
+fn accept(listener: &std::net::TcpListener) -> (TcpStream, BufReader<TcpStream>, String) {
+	let (mut stream, _) = listener.accept().unwrap();
+	let mut reader = BufReader::new(stream.try_clone().unwrap());
+	let mut request = Vec::new();
+	loop {
+		let mut line = String::new();
+		reader.read_line(&mut line).unwrap();
+		if line == "\r\n" {
+			break;
+		}
+		request.push(line.trim_end().to_string());
+	}
+	let key = request.iter().find_map(|l| l.strip_prefix("Sec-WebSocket-Key: ")).unwrap();
+	let accept = base64_encode(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+	write!(stream, "HTTP/1.1 101 Switching Protocols\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept).unwrap();
+	(stream, reader, request.swap_remove(0))
+}
+
+// The opcode and payload of the next frame from the client.
+#[cfg(test)]
+fn next_frame(reader: &mut BufReader<TcpStream>) -> (u8, Vec<u8>) {
+	let mut buf = Vec::new();
+	loop {
+		if let Some(frame) = parse_frame(&buf, MAX_MESSAGE).unwrap() {
+			return (frame.opcode, frame.payload);
+		}
+		let mut byte = [0];
+		reader.read_exact(&mut byte).unwrap();
+		buf.push(byte[0]);
+	}
+}
+
+#[test]
+fn test_websocket() {
+	use std::net::TcpListener;
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let server = std::thread::spawn(move || {
+		let (mut stream, mut reader, request) = accept(&listener);
+		// A message in two fragments with a ping between them.
+		stream.write_all(b"\x01\x03hel\x89\x01p\x80\x02lo").unwrap();
+		let mut received = Vec::new();
+		for _ in 0..2 {
+			received.push(next_frame(&mut reader));
+		}
+		// Echo the message.
+		let echo = received[1].1.clone();
+		stream.write_all(&[&[0x81, echo.len() as u8], &echo[..]].concat()).unwrap();
+		received.push(next_frame(&mut reader));
+		stream.write_all(b"\x88\x02\x03\xe8").unwrap();
+		received.push(next_frame(&mut reader));
+		(request, received)
+	});
+	let mut interp = super::Interpreter::new();
+	let program = format!("(define ws (ws-connect \"ws://127.0.0.1:{}/chat?room=1\")) \
+	                       (list (ws-receive ws) (ws-send ws \"hi\") (ws-receive ws) (ws-receive ws 50) \
+	                             (ws-send ws \"done\") (ws-receive ws) (ws-close ws))", port);
+	assert_eq!(interp.eval_str(&program).unwrap().to_string(), "(\"hello\" #<unspecified> \"hi\" #f #<unspecified> closed #<unspecified>)");
+	let (request, received) = server.join().unwrap();
+	assert_eq!(request, "GET /chat?room=1 HTTP/1.1");
+	let expected: [(u8, &[u8]); 4] = [(10, b"p"), (1, b"hi"), (1, b"done"), (8, b"\x03\xe8")];
+	assert_eq!(received, expected.iter().map(|&(op, s)| (op, s.to_vec())).collect::<Vec<_>>());
+	assert!(interp.eval_str("(ws-send ws \"x\")").is_err());
+	assert!(interp.eval_str("(ws-connect \"http://example.com/\")").is_err());
+}
+
+#[test]
+fn test_websocket_refused_messages() {
+	use std::net::TcpListener;
+	assert_eq!(parse_frame(b"\x81\x7f\x00\x00\x01\x00\x00\x00\x00\x00", MAX_MESSAGE).err(), Some(1 << 40));
+	assert_eq!(parse_frame(b"\x81\x7e\x00\x06", 5).err(), Some(6));
+	assert!(parse_frame(b"\x89\x7e\x00\x06", 5).unwrap().is_none());
+	assert_eq!(parse_frame(b"\x81\x02hi", 2).unwrap().unwrap().payload, b"hi");
+
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let server = std::thread::spawn(move || {
+		let mut closes = Vec::new();
+		// A first fragment announcing more than MAX_MESSAGE bytes, then
+		// fragments that only add up to more than it.
+		let half = (MAX_MESSAGE / 2 + 1) as u64;
+		for header in [[&[0x81, 127][..], &(MAX_MESSAGE as u64 + 1).to_be_bytes()].concat(),
+		               [&[0x01, 127][..], &half.to_be_bytes()].concat()] {
+			let (mut stream, mut reader, _) = accept(&listener);
+			stream.write_all(&header).unwrap();
+			if header[0] == 0x01 {
+				stream.write_all(&vec![b'a'; half as usize]).unwrap();
+				stream.write_all(&[&[0x80, 127][..], &half.to_be_bytes()].concat()).unwrap();
+			}
+			closes.push(next_frame(&mut reader));
+		}
+		let (mut stream, mut reader, _) = accept(&listener);
+		stream.write_all(b"\x82\x02\x00\xff").unwrap();
+		closes.push(next_frame(&mut reader));
+		closes
+	});
+	let mut interp = super::Interpreter::new();
+	let url = format!("\"ws://127.0.0.1:{}/\"", port);
+	let too_big = format!("websocket: message larger than {} bytes", MAX_MESSAGE);
+	for expected in [&too_big[..], &too_big[..], "websocket: binary messages are not supported"] {
+		interp.eval_str(&format!("(define ws (ws-connect {}))", url)).unwrap();
+		assert_eq!(interp.eval_str("(ws-receive ws)").unwrap_err().to_string(), format!("1:1: {}", expected));
+		assert_eq!(interp.eval_str("(ws-receive ws)").unwrap().to_string(), "closed");
+	}
+	let expected: [(u8, &[u8]); 3] = [(8, b"\x03\xf1"), (8, b"\x03\xf1"), (8, b"\x03\xeb")];
+	assert_eq!(server.join().unwrap(), expected.iter().map(|&(op, s)| (op, s.to_vec())).collect::<Vec<_>>());
+}
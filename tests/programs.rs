@@ -0,0 +1,79 @@
+// Copyright 2016 Erik Duveblad
+//
+// This file is part of crust.
+//
+// crust is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// crust is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with crust.  If not, see <http://www.gnu.org/licenses/>.
+
+// Golden tests: every `tests/programs/<name>.crust` is run through the crust
+// binary and its output (stdout followed by stderr) is compared with
+// `tests/programs/<name>.out`.
+//
+// Run with CRUST_BLESS=1 to (re)write the `.out` files from the current
+// output instead of comparing against them.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn programs_dir() -> PathBuf {
+	Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("programs")
+}
+
+fn programs() -> Vec<PathBuf> {
+	let mut v: Vec<PathBuf> = fs::read_dir(programs_dir())
+		.unwrap()
+		.map(|e| e.unwrap().path())
+		.filter(|p| p.extension().is_some_and(|e| e == "crust"))
+		.collect();
+	v.sort();
+	v
+}
+
+fn run(program: &Path) -> String {
+	// Run from the programs directory so that file names in error messages
+	// do not depend on where the repository is checked out.
+	let output = Command::new(env!("CARGO_BIN_EXE_crust"))
+		.arg(program.file_name().unwrap())
+		.current_dir(programs_dir())
+		.output()
+		.unwrap();
+	let mut res = String::from_utf8(output.stdout).unwrap();
+	res.push_str(&String::from_utf8(output.stderr).unwrap());
+	if !output.status.success() {
+		res.push_str(&format!("{}\n", output.status));
+	}
+	res
+}
+
+#[test]
+fn test_programs() {
+	let bless = env::var_os("CRUST_BLESS").is_some();
+	let mut failures = Vec::new();
+	for program in programs() {
+		let expected_path = program.with_extension("out");
+		let actual = run(&program);
+		if bless {
+			fs::write(&expected_path, &actual).unwrap();
+			continue;
+		}
+		let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+		if actual != expected {
+			failures.push(format!(
+				"{}\n--- expected\n{}--- actual\n{}",
+				program.display(), expected, actual));
+		}
+	}
+	assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}